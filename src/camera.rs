@@ -1,5 +1,7 @@
-use super::math_3d::Rotor3;
+use super::geometry::{Aabb, BoundingSphere, Ray};
+use super::math_3d::{Rotor3, Vector3};
 use super::transform::Transform3;
+use super::util::Vf2d;
 
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Camera {
@@ -11,9 +13,42 @@ pub struct Camera {
     pub inv_camera_rot: Rotor3,
     pub aspect: f32,
     pub fov: f32,
+    pub projection: Projection,
     pub mat: RawMat,
 }
 
+///How a Camera maps view space onto the screen. Everything but Custom uses the camera's
+/// clip_near and clip_far.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum Projection {
+    //Uses the camera's fov and aspect
+    #[default]
+    Perspective,
+    //Parallel view rays, height is how many world units fit vertically on screen and
+    // the width follows from the camera's aspect. With a rotated camera this gives
+    // isometric and other 2.5D views
+    Orthographic { height: f32 },
+    //A perspective whose centre isn't in the middle of the screen, the bounds are of
+    // the visible area at clip_near. Good for split screens and looking through portals
+    OffCenter {
+        left: f32,
+        right: f32,
+        bottom: f32,
+        top: f32,
+    },
+    //Orthographic with depth sheared across the screen, angle in radians sets which
+    // way and depth_scale how far. A scale of 0.5 at 45 degrees is a cabinet projection,
+    // 1.0 a cavalier one
+    Oblique {
+        height: f32,
+        angle: f32,
+        depth_scale: f32,
+    },
+    //Used as is, so it has to already map depth to 0..1 like wgpu
+    Custom(cgmath::Matrix4<f32>),
+}
+
+
 
 #[repr(C)]
 // This is so we can store this in a buffer
@@ -22,6 +57,11 @@ pub struct RawMat {
     pub view_proj: [[f32; 4]; 4],
     pub view_inv_proj: [[f32; 4]; 4],
     pub position: [f32; 3],
+    //1 when the projection is parallel, so lighting looks along view_dir instead of
+    // from position
+    pub parallel: f32,
+    //The direction the view rays point in, only w is unused
+    pub view_dir: [f32; 4],
 }
 
 impl Default for RawMat {
@@ -30,6 +70,8 @@ impl Default for RawMat {
             view_proj: [[0.0; 4]; 4],
             view_inv_proj: [[0.0; 4]; 4],
             position: [0.0; 3],
+            parallel: 0.0,
+            view_dir: [0.0; 4],
         }
     }
 }
@@ -44,24 +86,164 @@ impl Camera {
             ..Default::default()
         }
     }
+    ///The projection on its own, in wgpu's clip space
+    pub fn projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        let (near, far) = (self.clip_near, self.clip_far);
+        let proj = match self.projection {
+            Projection::Perspective => {
+                cgmath::perspective(cgmath::Deg(self.fov), self.aspect, near, far)
+            }
+            Projection::Orthographic { height } => {
+                let (h, w) = (height / 2.0, height / 2.0 * self.aspect);
+                cgmath::ortho(-w, w, -h, h, near, far)
+            }
+            Projection::OffCenter {
+                left,
+                right,
+                bottom,
+                top,
+            } => cgmath::frustum(left, right, bottom, top, near, far),
+            Projection::Oblique {
+                height,
+                angle,
+                depth_scale,
+            } => {
+                let (h, w) = (height / 2.0, height / 2.0 * self.aspect);
+                //View space looks down -z, so the further away the more it's pushed along
+                #[rustfmt::skip]
+                let shear = cgmath::Matrix4::new(
+                    1.0, 0.0, 0.0, 0.0,
+                    0.0, 1.0, 0.0, 0.0,
+                    -depth_scale * angle.cos(), -depth_scale * angle.sin(), 1.0, 0.0,
+                    0.0, 0.0, 0.0, 1.0,
+                );
+                cgmath::ortho(-w, w, -h, h, near, far) * shear
+            }
+            Projection::Custom(m) => return m,
+        };
+        OPENGL_TO_WGPU_MATRIX * proj
+    }
     pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
-        let proj = cgmath::perspective(
-            cgmath::Deg(self.fov),
-            self.aspect,
-            self.clip_near,
-            self.clip_far,
-        );
-        OPENGL_TO_WGPU_MATRIX * proj * self.transform.to_transform_matrix()
+        self.projection_matrix() * self.transform.to_transform_matrix()
+    }
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(self.build_view_projection_matrix())
     }
     pub fn build_reverse_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        invert_or_zero(self.projection_matrix())
+    }
+    ///What the shaders need for this camera, from its transform and projection
+    pub fn build_raw_mat(&self) -> RawMat {
+        use cgmath::InnerSpace;
+        let m = self.build_view_projection_matrix();
+        let row = |i: usize| cgmath::Vector3::new(m.x[i], m.y[i], m.z[i]);
+        //W doesn't depend on the position, so nothing is divided by depth
+        let parallel = row(3).magnitude2() == 0.0;
+        //Points along it keep their screen position, so it's at right angles to the x and
+        // y rows, facing the way depth grows
+        let dir = row(0).cross(row(1));
+        let dir = if dir.dot(row(2)) < 0.0 { -dir } else { dir };
+        let dir = if dir.magnitude2() > 0.0 {
+            dir.normalize()
+        } else {
+            dir
+        };
+        let pos = self.transform.pos;
+        RawMat {
+            view_proj: m.into(),
+            view_inv_proj: invert_or_zero(m).into(),
+            position: [pos.x, pos.y, pos.z],
+            parallel: if parallel { 1.0 } else { 0.0 },
+            view_dir: [dir.x, dir.y, dir.z, 0.0],
+        }
+    }
+    ///The world space ray under a point on the screen, for picking what the mouse is
+    /// over. Uses mat, so it matches what was drawn even when mat was set by hand. None
+    /// when mat can't be inverted, like before it's first set.
+    pub fn screen_to_ray(&self, mouse_pos: Vf2d, screen_size: Vf2d) -> Option<Ray> {
         use cgmath::SquareMatrix;
-        let proj = cgmath::perspective(
-            cgmath::Deg(self.fov),
-            self.aspect,
-            self.clip_near,
-            self.clip_far,
-        );
-        OPENGL_TO_WGPU_MATRIX * proj.invert().unwrap()
+        let inverse = cgmath::Matrix4::from(self.mat.view_proj).invert()?;
+        let x = mouse_pos.x / screen_size.x * 2.0 - 1.0;
+        //Screen y grows downwards, clip space y upwards
+        let y = 1.0 - mouse_pos.y / screen_size.y * 2.0;
+        let unproject = |depth: f32| {
+            let p = inverse * cgmath::Vector4::new(x, y, depth, 1.0);
+            Vector3::new(p.x / p.w, p.y / p.w, p.z / p.w)
+        };
+        //From the near plane to the far one, which also works for parallel projections
+        let (near, far) = (unproject(0.0), unproject(1.0));
+        let dir = far - near;
+        if !dir.is_valid() || dir.length_sqrd() == 0.0 {
+            return None;
+        }
+        Some(Ray {
+            origin: near,
+            dir: dir.normal(),
+        })
+    }
+    ///Rebuilds mat, call after moving the camera or changing its projection
+    pub fn update_mat(&mut self) {
+        self.mat = self.build_raw_mat();
+    }
+}
+
+fn invert_or_zero(m: cgmath::Matrix4<f32>) -> cgmath::Matrix4<f32> {
+    use cgmath::{SquareMatrix, Zero};
+    m.invert().unwrap_or_else(cgmath::Matrix4::zero)
+}
+
+///The six planes bounding what a view projection matrix can see. Each plane is
+/// (a, b, c, d) with a normalized (a, b, c) pointing inwards, so a point p is inside
+/// when a*p.x + b*p.y + c*p.z + d >= 0 for every plane.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Frustum {
+    pub planes: [[f32; 4]; 6],
+}
+
+impl Frustum {
+    ///Extracts the planes from the rows of a view projection matrix, with depth going
+    /// from 0 to 1 like wgpu. A zero matrix gives planes that cull nothing.
+    pub fn from_matrix(m: cgmath::Matrix4<f32>) -> Self {
+        let row = |i: usize| [m.x[i], m.y[i], m.z[i], m.w[i]];
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        let add = |a: [f32; 4], b: [f32; 4]| [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]];
+        let sub = |a: [f32; 4], b: [f32; 4]| [a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]];
+        let normalize = |p: [f32; 4]| {
+            let len = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+            if len > 0.0 {
+                [p[0] / len, p[1] / len, p[2] / len, p[3] / len]
+            } else {
+                p
+            }
+        };
+        Self {
+            planes: [
+                normalize(add(r3, r0)),
+                normalize(sub(r3, r0)),
+                normalize(add(r3, r1)),
+                normalize(sub(r3, r1)),
+                normalize(r2),
+                normalize(sub(r3, r2)),
+            ],
+        }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        let c = sphere.center;
+        self.planes
+            .iter()
+            .all(|p| p[0] * c.x + p[1] * c.y + p[2] * c.z + p[3] >= -sphere.radius)
+    }
+
+    ///Conservative, boxes near a corner of the frustum can pass without being seen
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|p| {
+            //The corner furthest along the plane's normal
+            let x = if p[0] >= 0.0 { aabb.max.x } else { aabb.min.x };
+            let y = if p[1] >= 0.0 { aabb.max.y } else { aabb.min.y };
+            let z = if p[2] >= 0.0 { aabb.max.z } else { aabb.min.z };
+            p[0] * x + p[1] * y + p[2] * z + p[3] >= 0.0
+        })
     }
 }
 
@@ -72,3 +254,51 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     0.0, 0.0, 0.5, 0.0,
     0.0, 0.0, 0.5, 1.0,
 );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Looking down -z from the origin, seeing from 0.1 to 100 away
+    fn frustum() -> Frustum {
+        let proj = cgmath::perspective(cgmath::Deg(90.0), 1.0, 0.1, 100.0);
+        Frustum::from_matrix(OPENGL_TO_WGPU_MATRIX * proj)
+    }
+
+    fn sphere(x: f32, y: f32, z: f32, radius: f32) -> BoundingSphere {
+        BoundingSphere {
+            center: Vector3::new(x, y, z),
+            radius,
+        }
+    }
+
+    #[test]
+    fn frustum_keeps_what_is_in_front() {
+        let f = frustum();
+        assert!(f.intersects_sphere(&sphere(0.0, 0.0, -10.0, 1.0)));
+        //Centred outside the side plane, but reaching over it
+        assert!(f.intersects_sphere(&sphere(11.0, 0.0, -10.0, 2.0)));
+        assert!(f.intersects_aabb(&Aabb {
+            min: Vector3::new(-1.0, -1.0, -11.0),
+            max: Vector3::new(1.0, 1.0, -9.0),
+        }));
+    }
+
+    #[test]
+    fn frustum_culls_what_is_outside() {
+        let f = frustum();
+        assert!(!f.intersects_sphere(&sphere(0.0, 0.0, 10.0, 1.0)));
+        assert!(!f.intersects_sphere(&sphere(20.0, 0.0, -10.0, 1.0)));
+        assert!(!f.intersects_sphere(&sphere(0.0, 0.0, -200.0, 1.0)));
+        assert!(!f.intersects_aabb(&Aabb {
+            min: Vector3::new(-1.0, -1.0, 9.0),
+            max: Vector3::new(1.0, 1.0, 11.0),
+        }));
+    }
+
+    #[test]
+    fn zero_matrix_culls_nothing() {
+        use cgmath::Zero;
+        let f = Frustum::from_matrix(cgmath::Matrix4::zero());
+        assert!(f.intersects_sphere(&sphere(0.0, 0.0, 1000.0, 0.0)));
+    }
+}
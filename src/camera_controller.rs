@@ -0,0 +1,364 @@
+use super::{
+    camera::Camera,
+    engine::OLCEngine,
+    math_3d::Vector3,
+    olc::OlcData,
+    platform::Key,
+    transform::Transform3,
+    util::{Mouse, Vf2d},
+};
+
+//Pitch stops just short of straight up or down, where yaw stops meaning anything
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
+///How moving the mouse turns the camera
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MouseLook {
+    Off,
+    //Only while the button is held
+    Drag(Mouse),
+    //Whenever the mouse moves, best with the cursor locked
+    Always,
+}
+
+///Turning by holding the mouse near the edges of the screen, so the cursor never has to
+/// leave the window. The closer to the edge the faster it turns.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EdgeTurning {
+    //In game pixels from each edge
+    pub margin: f32,
+    //Radians per second at the very edge
+    pub speed: f32,
+}
+
+impl Default for EdgeTurning {
+    fn default() -> Self {
+        Self {
+            margin: 16.0,
+            speed: 1.5,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MoveKeys {
+    pub forward: Key,
+    pub back: Key,
+    pub left: Key,
+    pub right: Key,
+    pub up: Key,
+    pub down: Key,
+    //Held to move fast_multiplier times faster
+    pub fast: Key,
+}
+
+impl Default for MoveKeys {
+    fn default() -> Self {
+        Self {
+            forward: Key::W,
+            back: Key::S,
+            left: Key::A,
+            right: Key::D,
+            up: Key::E,
+            down: Key::Q,
+            fast: Key::LShift,
+        }
+    }
+}
+
+impl MoveKeys {
+    //Right, up and forward, each -1, 0 or 1
+    fn axes<D: OlcData>(&self, engine: &OLCEngine<D>) -> (f32, f32, f32) {
+        let axis = |pos: Key, neg: Key| {
+            engine.get_key(pos).held as i32 as f32 - engine.get_key(neg).held as i32 as f32
+        };
+        (
+            axis(self.right, self.left),
+            axis(self.up, self.down),
+            axis(self.forward, self.back),
+        )
+    }
+}
+
+///Right, up and forward for a yaw around world up and a pitch above the horizon.
+/// A yaw and pitch of 0 faces +z like an unrotated Transform3.
+pub fn yaw_pitch_axes(yaw: f32, pitch: f32) -> (Vector3, Vector3, Vector3) {
+    let forward = Vector3::new(
+        yaw.sin() * pitch.cos(),
+        pitch.sin(),
+        yaw.cos() * pitch.cos(),
+    );
+    let right = Vector3::new(yaw.cos(), 0.0, -yaw.sin());
+    (right, forward.cross(right), forward)
+}
+
+///A transform at pos facing along yaw and pitch, with no roll
+pub fn yaw_pitch_transform(pos: Vector3, yaw: f32, pitch: f32) -> Transform3 {
+    let (right, up, forward) = yaw_pitch_axes(yaw, pitch);
+    #[rustfmt::skip]
+    let m = cgmath::Matrix4::new(
+        right.x, right.y, right.z, 0.0,
+        up.x, up.y, up.z, 0.0,
+        forward.x, forward.y, forward.z, 0.0,
+        pos.x, pos.y, pos.z, 1.0,
+    );
+    Transform3::from_model_matrix(m)
+}
+
+//Yaw and pitch that face along a direction
+fn yaw_pitch_of(dir: Vector3) -> (f32, f32) {
+    let flat = (dir.x * dir.x + dir.z * dir.z).sqrt();
+    (
+        dir.x.atan2(dir.z),
+        dir.y.atan2(flat).clamp(-MAX_PITCH, MAX_PITCH),
+    )
+}
+
+//Mouse movement since last frame that should turn the camera, in game pixels
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct MouseTracker {
+    last: Option<Vf2d>,
+}
+
+impl MouseTracker {
+    fn delta<D: OlcData>(&mut self, engine: &OLCEngine<D>, look: MouseLook) -> (f32, f32) {
+        let pos: Vf2d = (engine.mouse_x(), engine.mouse_y()).into();
+        let last = self.last.replace(pos);
+        let active = match look {
+            MouseLook::Off => false,
+            //The press frame only records where the drag starts
+            MouseLook::Drag(button) => {
+                let b = engine.get_mouse(button);
+                b.held && !b.pressed
+            }
+            MouseLook::Always => true,
+        };
+        match last {
+            Some(last) if active => (pos.x - last.x, pos.y - last.y),
+            _ => (0.0, 0.0),
+        }
+    }
+}
+
+//Yaw and pitch speeds from how far into the margins the mouse is
+fn edge_turn<D: OlcData>(engine: &OLCEngine<D>, edge: &EdgeTurning) -> (f32, f32) {
+    if edge.margin <= 0.0 || !engine.is_focused() {
+        return (0.0, 0.0);
+    }
+    let (w, h) = (engine.screen_width() as f32, engine.screen_height() as f32);
+    let (x, y) = (engine.mouse_x(), engine.mouse_y());
+    let amount = |d: f32| (1.0 - d / edge.margin).clamp(0.0, 1.0);
+    let yaw = amount(w - 1.0 - x) - amount(x);
+    //Screen y grows downwards
+    let pitch = amount(y) - amount(h - 1.0 - y);
+    (yaw * edge.speed, pitch * edge.speed)
+}
+
+///Flies freely in whatever direction it faces, like a level editor camera
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FlyController {
+    pub position: Vector3,
+    //Radians, around world up and above the horizon
+    pub yaw: f32,
+    pub pitch: f32,
+    //World units per second
+    pub speed: f32,
+    pub fast_multiplier: f32,
+    //Radians per game pixel the mouse moves
+    pub sensitivity: f32,
+    pub look: MouseLook,
+    pub keys: MoveKeys,
+    mouse: MouseTracker,
+}
+
+impl FlyController {
+    pub fn new(position: Vector3) -> Self {
+        Self {
+            position,
+            yaw: 0.0,
+            pitch: 0.0,
+            speed: 5.0,
+            fast_multiplier: 4.0,
+            sensitivity: 0.005,
+            look: MouseLook::Drag(Mouse::Right),
+            keys: MoveKeys::default(),
+            mouse: MouseTracker::default(),
+        }
+    }
+
+    ///Starts from wherever the camera already is and faces
+    pub fn from_camera(camera: &Camera) -> Self {
+        let (yaw, pitch) = yaw_pitch_of(camera.transform.forward());
+        Self {
+            yaw,
+            pitch,
+            ..Self::new(camera.transform.pos)
+        }
+    }
+
+    pub fn update<D: OlcData>(&mut self, engine: &OLCEngine<D>, elapsed_time: f32) {
+        let (dx, dy) = self.mouse.delta(engine, self.look);
+        self.yaw += dx * self.sensitivity;
+        self.pitch = (self.pitch - dy * self.sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+
+        let (x, y, z) = self.keys.axes(engine);
+        let (right, up, forward) = yaw_pitch_axes(self.yaw, self.pitch);
+        let mut speed = self.speed * elapsed_time;
+        if engine.get_key(self.keys.fast).held {
+            speed *= self.fast_multiplier;
+        }
+        //Up and down are the camera's own, so it rises the way the screen's top points
+        self.position += (right * x + up * y + forward * z) * speed;
+    }
+
+    ///Moves the camera to the controller and rebuilds its mat
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.transform = yaw_pitch_transform(self.position, self.yaw, self.pitch);
+        camera.update_mat();
+    }
+}
+
+///Walks along the ground, turning with the mouse or the edges of the screen. Up and down
+/// keys move straight up and down, which a game with gravity can leave unbound by
+/// setting them to keys it doesn't use.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FpsController {
+    //Of the eyes, not the feet
+    pub position: Vector3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub speed: f32,
+    pub fast_multiplier: f32,
+    pub sensitivity: f32,
+    pub look: MouseLook,
+    //Turns as well as, or instead of, mouse look
+    pub edge_turning: Option<EdgeTurning>,
+    pub keys: MoveKeys,
+    mouse: MouseTracker,
+}
+
+impl FpsController {
+    pub fn new(position: Vector3) -> Self {
+        Self {
+            position,
+            yaw: 0.0,
+            pitch: 0.0,
+            speed: 3.0,
+            fast_multiplier: 2.0,
+            sensitivity: 0.005,
+            look: MouseLook::Always,
+            edge_turning: None,
+            keys: MoveKeys {
+                up: Key::Space,
+                down: Key::LControl,
+                ..MoveKeys::default()
+            },
+            mouse: MouseTracker::default(),
+        }
+    }
+
+    ///Turns only by holding the mouse at the edges of the screen, plan.md's first person
+    /// movement
+    pub fn with_edge_turning(position: Vector3, edge: EdgeTurning) -> Self {
+        Self {
+            look: MouseLook::Off,
+            edge_turning: Some(edge),
+            ..Self::new(position)
+        }
+    }
+
+    pub fn from_camera(camera: &Camera) -> Self {
+        let (yaw, pitch) = yaw_pitch_of(camera.transform.forward());
+        Self {
+            yaw,
+            pitch,
+            ..Self::new(camera.transform.pos)
+        }
+    }
+
+    pub fn update<D: OlcData>(&mut self, engine: &OLCEngine<D>, elapsed_time: f32) {
+        let (dx, dy) = self.mouse.delta(engine, self.look);
+        let (edge_yaw, edge_pitch) = match &self.edge_turning {
+            Some(edge) => edge_turn(engine, edge),
+            None => (0.0, 0.0),
+        };
+        self.yaw += dx * self.sensitivity + edge_yaw * elapsed_time;
+        self.pitch = (self.pitch - dy * self.sensitivity + edge_pitch * elapsed_time)
+            .clamp(-MAX_PITCH, MAX_PITCH);
+
+        let (x, y, z) = self.keys.axes(engine);
+        //Looking up or down doesn't change which way is forward on the ground
+        let (right, _, forward) = yaw_pitch_axes(self.yaw, 0.0);
+        let mut speed = self.speed * elapsed_time;
+        if engine.get_key(self.keys.fast).held {
+            speed *= self.fast_multiplier;
+        }
+        let mut walk = right * x + forward * z;
+        //Diagonals aren't faster
+        if walk.length_sqrd() > 1.0 {
+            walk = walk.normal();
+        }
+        self.position += (walk + Vector3::up() * y) * speed;
+    }
+
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.transform = yaw_pitch_transform(self.position, self.yaw, self.pitch);
+        camera.update_mat();
+    }
+}
+
+///Circles a target, dragging to turn around it and the wheel to zoom in and out
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OrbitController {
+    pub target: Vector3,
+    pub distance: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+    //Of the camera, so a positive pitch looks down on the target from above
+    pub yaw: f32,
+    pub pitch: f32,
+    pub sensitivity: f32,
+    //Each notch of the wheel scales the distance by this, or its inverse zooming out
+    pub zoom_step: f32,
+    pub look: MouseLook,
+    mouse: MouseTracker,
+}
+
+impl OrbitController {
+    pub fn new(target: Vector3, distance: f32) -> Self {
+        Self {
+            target,
+            distance,
+            min_distance: 0.5,
+            max_distance: 500.0,
+            yaw: 0.0,
+            pitch: 0.5,
+            sensitivity: 0.005,
+            zoom_step: 0.9,
+            look: MouseLook::Drag(Mouse::Left),
+            mouse: MouseTracker::default(),
+        }
+    }
+
+    pub fn update<D: OlcData>(&mut self, engine: &OLCEngine<D>) {
+        let (dx, dy) = self.mouse.delta(engine, self.look);
+        self.yaw += dx * self.sensitivity;
+        self.pitch = (self.pitch + dy * self.sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+        let wheel = engine.mouse_wheel();
+        if wheel != 0 {
+            self.distance *= self.zoom_step.powi(wheel);
+        }
+        self.distance = self.distance.clamp(self.min_distance, self.max_distance);
+    }
+
+    ///Where the camera sits, distance back from the target along the way it faces
+    pub fn eye(&self) -> Vector3 {
+        let (_, _, forward) = yaw_pitch_axes(self.yaw, -self.pitch);
+        self.target - forward * self.distance
+    }
+
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.transform = yaw_pitch_transform(self.eye(), self.yaw, -self.pitch);
+        camera.update_mat();
+    }
+}
@@ -0,0 +1,280 @@
+use super::{engine::OLCEngine, olc::OlcData, pixel::Pixel, platform::Key};
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+
+//Oldest lines are dropped past this
+const SCROLLBACK_LINES: usize = 500;
+const HISTORY_LINES: usize = 100;
+
+lazy_static::lazy_static! {
+    //Log records can come from any thread, they wait here until the console next updates
+    static ref LOG_QUEUE: Mutex<Vec<(log::Level, String)>> = Mutex::new(vec![]);
+}
+
+pub type ConsoleFunc<D> = Box<dyn Fn(&mut OLCEngine<D>, &ConsoleArgs) -> Result<(), String>>;
+
+pub struct ConsoleCommand<D: OlcData + 'static> {
+    pub usage: String,
+    pub func: ConsoleFunc<D>,
+}
+
+///The words after the command name. Double quotes group words with spaces into one argument.
+pub struct ConsoleArgs {
+    pub args: Vec<String>,
+}
+
+impl ConsoleArgs {
+    pub fn parse(line: &str) -> (String, Self) {
+        let mut words = vec![];
+        let mut word = String::new();
+        let mut quoted = false;
+        let mut started = false;
+        for c in line.chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    started = true;
+                }
+                c if c.is_whitespace() && !quoted => {
+                    if started {
+                        words.push(std::mem::take(&mut word));
+                        started = false;
+                    }
+                }
+                c => {
+                    word.push(c);
+                    started = true;
+                }
+            }
+        }
+        if started {
+            words.push(word);
+        }
+        let name = if words.is_empty() {
+            String::new()
+        } else {
+            words.remove(0)
+        };
+        (name, Self { args: words })
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    ///The argument at i parsed as T, the error is ready to print in the console
+    pub fn get<T: FromStr>(&self, i: usize) -> Result<T, String> {
+        let arg = self
+            .args
+            .get(i)
+            .ok_or_else(|| format!("missing argument {}", i + 1))?;
+        arg.parse().map_err(|_| {
+            format!(
+                "argument {} ({}) should be a {}",
+                i + 1,
+                arg,
+                std::any::type_name::<T>()
+            )
+        })
+    }
+
+    ///Like get, but a missing argument gives the default. A bad one is still an error.
+    pub fn get_or<T: FromStr>(&self, i: usize, default: T) -> Result<T, String> {
+        if i < self.args.len() {
+            self.get(i)
+        } else {
+            Ok(default)
+        }
+    }
+}
+
+///State of the developer console, see OLCEngine::console_show
+pub struct Console<D: OlcData + 'static> {
+    pub shown: bool,
+    pub close_key: Key,
+    //Games get an elapsed time of 0 while the console is open
+    pub suspend_time: bool,
+    pub layer_id: Option<u32>,
+    pub lines: Vec<(String, Pixel)>,
+    pub history: Vec<String>,
+    pub input: String,
+    //How many lines up from the newest the view is scrolled
+    pub scroll: usize,
+    pub(crate) history_index: Option<usize>,
+    pub(crate) commands: HashMap<String, ConsoleCommand<D>>,
+}
+
+impl<D: OlcData + 'static> Console<D> {
+    pub fn new() -> Self {
+        Self {
+            shown: false,
+            close_key: Key::Escape,
+            suspend_time: false,
+            layer_id: None,
+            lines: vec![],
+            history: vec![],
+            input: String::new(),
+            scroll: 0,
+            history_index: None,
+            commands: HashMap::new(),
+        }
+    }
+
+    pub fn out(&mut self, text: &str, col: Pixel) {
+        for line in text.lines() {
+            self.lines.push((line.to_string(), col));
+        }
+        if self.lines.len() > SCROLLBACK_LINES {
+            let extra = self.lines.len() - SCROLLBACK_LINES;
+            self.lines.drain(..extra);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.scroll = 0;
+    }
+
+    pub(crate) fn push_history(&mut self, line: &str) {
+        if self.history.last().map(|last| last != line).unwrap_or(true) {
+            self.history.push(line.to_string());
+        }
+        if self.history.len() > HISTORY_LINES {
+            self.history.remove(0);
+        }
+        self.history_index = None;
+    }
+
+    //Up goes back through the history, down comes forward again to an empty line
+    pub(crate) fn browse_history(&mut self, back: bool) {
+        if self.history.is_empty() {
+            return;
+        }
+        self.history_index = match (self.history_index, back) {
+            (None, true) => Some(self.history.len() - 1),
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.history.len() => Some(i + 1),
+            _ => None,
+        };
+        self.input = self
+            .history_index
+            .map(|i| self.history[i].clone())
+            .unwrap_or_default();
+    }
+
+    pub(crate) fn drain_log(&mut self) {
+        let records: Vec<(log::Level, String)> = LOG_QUEUE
+            .lock()
+            .expect("Console log queue is poisoned")
+            .drain(..)
+            .collect();
+        for (level, text) in records {
+            let col = match level {
+                log::Level::Error => Pixel::RED,
+                log::Level::Warn => Pixel::YELLOW,
+                log::Level::Info => Pixel::WHITE,
+                log::Level::Debug => Pixel::GREY,
+                log::Level::Trace => Pixel::DARK_GREY,
+            };
+            self.out(&format!("[{}] {}", level, text), col);
+        }
+    }
+}
+
+impl<D: OlcData + 'static> Default for Console<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//What still reaches the browser console on the web, as console_log was set up before
+#[cfg(target_arch = "wasm32")]
+const BROWSER_LOG_LEVEL: log::Level = log::Level::Warn;
+
+///Sends log records to the console, and on to env_logger on native builds or the
+/// browser console on the web
+pub struct ConsoleLogger {
+    level: log::LevelFilter,
+    #[cfg(not(target_arch = "wasm32"))]
+    env_logger: env_logger::Logger,
+}
+
+impl ConsoleLogger {
+    ///Installs the logger for the whole program. construct does this with the Info level,
+    /// call it before construct to pick another. Like any logger it can only be done once.
+    pub fn init(level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+        let logger = Self {
+            level,
+            #[cfg(not(target_arch = "wasm32"))]
+            env_logger: env_logger::Builder::from_default_env().build(),
+        };
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(log::LevelFilter::Trace);
+        Ok(())
+    }
+}
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.env_logger.enabled(metadata) {
+            return true;
+        }
+        #[cfg(target_arch = "wasm32")]
+        if metadata.level() <= BROWSER_LOG_LEVEL {
+            return true;
+        }
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() <= self.level {
+            if let Ok(mut queue) = LOG_QUEUE.lock() {
+                queue.push((record.level(), record.args().to_string()));
+            }
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        self.env_logger.log(record);
+        #[cfg(target_arch = "wasm32")]
+        if record.level() <= BROWSER_LOG_LEVEL {
+            console_log::log(record);
+        }
+    }
+
+    fn flush(&self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.env_logger.flush();
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::ConsoleArgs;
+
+    #[test]
+    fn parse_splits_words() {
+        let (name, args) = ConsoleArgs::parse("  teleport 1.5   -2 ");
+        assert_eq!(name, "teleport");
+        assert_eq!(args.args, vec!["1.5", "-2"]);
+        assert_eq!(args.get::<f32>(0), Ok(1.5));
+        assert_eq!(args.get::<i32>(1), Ok(-2));
+        assert!(args.get::<i32>(2).is_err());
+    }
+
+    #[test]
+    fn parse_groups_quoted_words() {
+        let (name, args) = ConsoleArgs::parse(r#"say "hello there" "" x"#);
+        assert_eq!(name, "say");
+        //Empty quotes still make an argument
+        assert_eq!(args.args, vec!["hello there", "", "x"]);
+    }
+
+    #[test]
+    fn parse_empty_line() {
+        let (name, args) = ConsoleArgs::parse("   ");
+        assert_eq!(name, "");
+        assert!(args.is_empty());
+    }
+}
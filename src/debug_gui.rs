@@ -1 +1,565 @@
+use super::{
+    engine::OLCEngine,
+    olc::OlcData,
+    pixel::Pixel,
+    platform::Key,
+    util::{Mouse, Vf2d},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
 
+//Glyphs of the built in font are 8x8, every row of a window is this tall
+const ROW_HEIGHT: f32 = 12.0;
+const PADDING: f32 = 4.0;
+const INDENT: f32 = 8.0;
+const TITLE_HEIGHT: f32 = 12.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct GuiStyle {
+    pub window: Pixel,
+    pub title: Pixel,
+    pub title_active: Pixel,
+    pub border: Pixel,
+    pub text: Pixel,
+    pub widget: Pixel,
+    pub widget_hot: Pixel,
+    pub widget_active: Pixel,
+    pub accent: Pixel,
+}
+
+impl Default for GuiStyle {
+    fn default() -> Self {
+        Self {
+            window: Pixel::rgba(20, 20, 28, 220),
+            title: Pixel::rgba(50, 50, 70, 240),
+            title_active: Pixel::rgba(70, 70, 120, 240),
+            border: Pixel::rgba(110, 110, 140, 255),
+            text: Pixel::WHITE,
+            widget: Pixel::rgba(55, 55, 70, 255),
+            widget_hot: Pixel::rgba(80, 80, 105, 255),
+            widget_active: Pixel::rgba(100, 100, 150, 255),
+            accent: Pixel::rgba(120, 190, 255, 255),
+        }
+    }
+}
+
+//What the GUI draws, queued per window so windows can be drawn back to front
+enum GuiDraw {
+    Rect(Vf2d, Vf2d, Pixel),
+    Outline(Vf2d, Vf2d, Pixel),
+    Line(Vf2d, Vf2d, Pixel),
+    Text(Vf2d, String, Pixel),
+}
+
+//Window state that has to outlive the frame, everything else is rebuilt every frame
+struct GuiWindow {
+    pos: Vf2d,
+    size: Vf2d,
+    collapsed: bool,
+    //Set by begin_window, windows the game stopped declaring leave order at end_frame
+    declared: bool,
+    draws: Vec<GuiDraw>,
+}
+
+//Mouse and keyboard state, read once at the start of the frame
+#[derive(Default)]
+struct GuiInput {
+    mouse: Vf2d,
+    pressed: bool,
+    held: bool,
+    released: bool,
+    text: String,
+    backspace: bool,
+    enter: bool,
+    escape: bool,
+}
+
+///An immediate mode GUI for tweaking values while the game runs. Widgets are declared
+/// every frame between begin_frame and end_frame, and report what the user did to them
+/// straight away. It draws with decals on the engine's GUI layer, see gui_layer.
+///
+/// Widgets are identified by their window and label, so labels in a window must be unique.
+pub struct DebugGui {
+    pub layer_id: u32,
+    pub style: GuiStyle,
+    windows: HashMap<String, GuiWindow>,
+    //Back to front
+    order: Vec<String>,
+    open_nodes: HashMap<u64, bool>,
+    input: GuiInput,
+    //The window under the mouse, only its widgets react
+    hovered_window: Option<String>,
+    current: Option<String>,
+    cursor: Vf2d,
+    indent: f32,
+    hot: Option<u64>,
+    //Held by the widget the mouse went down on until it is released
+    active: Option<u64>,
+    //The text field taking keyboard input
+    focused: Option<u64>,
+    drag_offset: Vf2d,
+}
+
+impl DebugGui {
+    pub fn new<D: OlcData>(engine: &mut OLCEngine<D>) -> Self {
+        Self {
+            layer_id: engine.gui_layer(),
+            style: GuiStyle::default(),
+            windows: HashMap::new(),
+            order: vec![],
+            open_nodes: HashMap::new(),
+            input: GuiInput::default(),
+            hovered_window: None,
+            current: None,
+            cursor: Vf2d::new(0.0, 0.0),
+            indent: 0.0,
+            hot: None,
+            active: None,
+            focused: None,
+            drag_offset: Vf2d::new(0.0, 0.0),
+        }
+    }
+
+    pub fn begin_frame<D: OlcData>(&mut self, engine: &OLCEngine<D>) {
+        let left = engine.get_mouse(Mouse::Left);
+        self.input = GuiInput {
+            mouse: engine.get_mouse_pos(),
+            pressed: left.pressed,
+            held: left.held,
+            released: left.released,
+            text: engine.get_text_input(),
+            backspace: engine.get_key(Key::Back).pressed,
+            enter: engine.get_key(Key::Return).pressed,
+            escape: engine.get_key(Key::Escape).pressed,
+        };
+        let mouse = self.input.mouse;
+        //Sizes are from last frame, which is what the user is looking at
+        self.hovered_window = self
+            .order
+            .iter()
+            .rev()
+            .find(|title| {
+                let window = &self.windows[*title];
+                contains(window.pos, window.size, mouse)
+            })
+            .cloned();
+        if self.input.pressed {
+            if let Some(title) = &self.hovered_window {
+                self.order.retain(|t| t != title);
+                self.order.push(title.clone());
+            }
+            //Clicking anywhere else drops keyboard focus
+            self.focused = None;
+        }
+        self.hot = None;
+    }
+
+    ///Draws everything declared this frame onto the GUI layer
+    pub fn end_frame<D: OlcData>(&mut self, engine: &mut OLCEngine<D>) {
+        if !self.input.held {
+            self.active = None;
+        }
+        let previous_target = engine.draw_target;
+        engine.draw_target = self.layer_id;
+        for title in &self.order {
+            if let Some(window) = self.windows.get_mut(title) {
+                for draw in window.draws.drain(..) {
+                    match draw {
+                        GuiDraw::Rect(pos, size, col) => engine.fill_rect_decal(pos, size, col),
+                        GuiDraw::Outline(pos, size, col) => engine.draw_rect_decal(pos, size, col),
+                        GuiDraw::Line(p1, p2, col) => engine.draw_line_decal(p1, p2, col),
+                        GuiDraw::Text(pos, text, col) => {
+                            engine.draw_string_decal_with_color(pos, &text, col)
+                        }
+                    }
+                }
+            }
+        }
+        engine.draw_target = previous_target;
+        //Hidden windows shouldn't be hit tested with where they were last drawn
+        let windows = &mut self.windows;
+        self.order.retain(|title| match windows.get_mut(title) {
+            Some(window) => std::mem::replace(&mut window.declared, false),
+            None => false,
+        });
+    }
+
+    ///True while the mouse is over a window or dragging a widget, so the game can
+    /// ignore clicks meant for the GUI
+    pub fn wants_mouse(&self) -> bool {
+        self.hovered_window.is_some() || self.active.is_some()
+    }
+
+    ///True while a text field is taking keyboard input
+    pub fn wants_keyboard(&self) -> bool {
+        self.focused.is_some()
+    }
+
+    ///Starts a window, pos and width only apply the first time it is seen. Returns false
+    /// when the window is collapsed, end_window has to be called either way.
+    pub fn begin_window(&mut self, title: &str, pos: Vf2d, width: f32) -> bool {
+        if !self.windows.contains_key(title) {
+            self.windows.insert(
+                title.to_string(),
+                GuiWindow {
+                    pos,
+                    size: Vf2d::new(width, TITLE_HEIGHT),
+                    collapsed: false,
+                    declared: false,
+                    draws: vec![],
+                },
+            );
+        }
+        //Back on top when it's declared again after being left out
+        if !self.order.iter().any(|t| t == title) {
+            self.order.push(title.to_string());
+        }
+        self.windows.get_mut(title).unwrap().declared = true;
+        self.current = Some(title.to_string());
+        self.indent = 0.0;
+
+        //The title bar moves the window, the arrow at its left collapses it
+        let id = self.id("##title");
+        let (win_pos, win_width) = {
+            let window = &self.windows[title];
+            (window.pos, window.size.x)
+        };
+        let (_, clicked) = self.interact(id, win_pos, Vf2d::new(win_width, TITLE_HEIGHT));
+        if self.active == Some(id) {
+            if self.input.pressed {
+                self.drag_offset = self.input.mouse - win_pos;
+            }
+            let window = self.windows.get_mut(title).unwrap();
+            window.pos = self.input.mouse - self.drag_offset;
+        }
+        if clicked && self.input.mouse.x < win_pos.x + ROW_HEIGHT {
+            let window = self.windows.get_mut(title).unwrap();
+            window.collapsed = !window.collapsed;
+        }
+
+        let window = &self.windows[title];
+        let (pos, size, collapsed) = (window.pos, window.size, window.collapsed);
+        let focused = self.order.last().map(|t| t == title).unwrap_or(false);
+        let style = self.style;
+        if !collapsed {
+            self.push(GuiDraw::Rect(pos, size, style.window));
+            self.push(GuiDraw::Outline(pos, size, style.border));
+        }
+        let title_col = if focused {
+            style.title_active
+        } else {
+            style.title
+        };
+        self.push(GuiDraw::Rect(
+            pos,
+            Vf2d::new(size.x, TITLE_HEIGHT),
+            title_col,
+        ));
+        let arrow = if collapsed { ">" } else { "v" };
+        self.push(GuiDraw::Text(pos + 2.0, arrow.to_string(), style.text));
+        self.push(GuiDraw::Text(
+            pos + Vf2d::new(ROW_HEIGHT, 2.0),
+            title.to_string(),
+            style.text,
+        ));
+        self.cursor = pos + Vf2d::new(PADDING, TITLE_HEIGHT + PADDING);
+        !collapsed
+    }
+
+    pub fn end_window(&mut self) {
+        if let Some(title) = self.current.take() {
+            let cursor_y = self.cursor.y;
+            if let Some(window) = self.windows.get_mut(&title) {
+                window.size.y = if window.collapsed {
+                    TITLE_HEIGHT
+                } else {
+                    cursor_y - window.pos.y + PADDING
+                };
+            }
+        }
+    }
+
+    pub fn label(&mut self, text: &str) {
+        let pos = self.row();
+        let col = self.style.text;
+        self.push(GuiDraw::Text(
+            pos + Vf2d::new(0.0, 2.0),
+            text.to_string(),
+            col,
+        ));
+    }
+
+    pub fn button(&mut self, label: &str) -> bool {
+        let id = self.id(label);
+        let pos = self.row();
+        let size = Vf2d::new(
+            label.chars().count() as f32 * 8.0 + PADDING * 2.0,
+            ROW_HEIGHT - 1.0,
+        );
+        let (_, clicked) = self.interact(id, pos, size);
+        let col = self.widget_col(id);
+        self.push(GuiDraw::Rect(pos, size, col));
+        let text_col = self.style.text;
+        self.push(GuiDraw::Text(
+            pos + Vf2d::new(PADDING, 2.0),
+            label.to_string(),
+            text_col,
+        ));
+        clicked
+    }
+
+    pub fn checkbox(&mut self, label: &str, value: &mut bool) -> bool {
+        let id = self.id(label);
+        let pos = self.row();
+        let box_size = Vf2d::new(ROW_HEIGHT - 2.0, ROW_HEIGHT - 2.0);
+        let hit_size = Vf2d::new(self.inner_width(), ROW_HEIGHT);
+        let (_, clicked) = self.interact(id, pos, hit_size);
+        if clicked {
+            *value = !*value;
+        }
+        let col = self.widget_col(id);
+        self.push(GuiDraw::Rect(pos, box_size, col));
+        if *value {
+            let accent = self.style.accent;
+            self.push(GuiDraw::Rect(pos + 2.0, box_size - 4.0, accent));
+        }
+        let text_col = self.style.text;
+        self.push(GuiDraw::Text(
+            pos + Vf2d::new(ROW_HEIGHT + 2.0, 2.0),
+            label.to_string(),
+            text_col,
+        ));
+        clicked
+    }
+
+    ///Drag anywhere on the bar to set the value. Returns true when it changed.
+    pub fn slider(&mut self, label: &str, value: &mut f32, min: f32, max: f32) -> bool {
+        let id = self.id(label);
+        let pos = self.row();
+        let size = Vf2d::new(self.inner_width(), ROW_HEIGHT - 1.0);
+        self.interact(id, pos, size);
+        let old = *value;
+        if self.active == Some(id) && self.input.held && size.x > 0.0 {
+            let t = ((self.input.mouse.x - pos.x) / size.x).clamp(0.0, 1.0);
+            *value = min + t * (max - min);
+        }
+        let t = if max > min {
+            ((*value - min) / (max - min)).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let col = self.widget_col(id);
+        let accent = self.style.accent;
+        let text_col = self.style.text;
+        self.push(GuiDraw::Rect(pos, size, col));
+        self.push(GuiDraw::Rect(
+            pos + Vf2d::new((size.x - 3.0) * t, 0.0),
+            Vf2d::new(3.0, size.y),
+            accent,
+        ));
+        self.push(GuiDraw::Text(
+            pos + Vf2d::new(2.0, 2.0),
+            format!("{}: {:.2}", label, value),
+            text_col,
+        ));
+        *value != old
+    }
+
+    pub fn slider_i32(&mut self, label: &str, value: &mut i32, min: i32, max: i32) -> bool {
+        let mut v = *value as f32;
+        self.slider(label, &mut v, min as f32, max as f32);
+        let old = *value;
+        *value = v.round() as i32;
+        *value != old
+    }
+
+    ///Click to type into it, enter or escape finish. Returns true when the text changed.
+    pub fn text_field(&mut self, label: &str, text: &mut String) -> bool {
+        let id = self.id(label);
+        let pos = self.row();
+        let label_width = (label.chars().count() as f32 + 1.0) * 8.0;
+        let field_pos = pos + Vf2d::new(label_width, 0.0);
+        let size = Vf2d::new(
+            (self.inner_width() - label_width).max(8.0),
+            ROW_HEIGHT - 1.0,
+        );
+        let (_, clicked) = self.interact(id, field_pos, size);
+        if clicked {
+            self.focused = Some(id);
+        }
+        let mut changed = false;
+        if self.focused == Some(id) {
+            if !self.input.text.is_empty() {
+                text.push_str(&self.input.text);
+                changed = true;
+            }
+            if self.input.backspace {
+                changed |= text.pop().is_some();
+            }
+            if self.input.enter || self.input.escape {
+                self.focused = None;
+            }
+        }
+        let style = self.style;
+        self.push(GuiDraw::Text(
+            pos + Vf2d::new(0.0, 2.0),
+            label.to_string(),
+            style.text,
+        ));
+        let col = if self.focused == Some(id) {
+            style.widget_active
+        } else {
+            self.widget_col(id)
+        };
+        self.push(GuiDraw::Rect(field_pos, size, col));
+        //Only the end of long text is shown, that's where the typing happens
+        let fits = ((size.x - 4.0) / 8.0).max(0.0) as usize;
+        let shown: String = {
+            let count = text.chars().count();
+            let cursor = if self.focused == Some(id) { "_" } else { "" };
+            let skip = (count + cursor.len()).saturating_sub(fits);
+            text.chars().skip(skip).collect::<String>() + cursor
+        };
+        self.push(GuiDraw::Text(
+            field_pos + Vf2d::new(2.0, 2.0),
+            shown,
+            style.text,
+        ));
+        changed
+    }
+
+    ///A collapsible node, call tree_pop after its children when it returns true
+    pub fn tree_node(&mut self, label: &str) -> bool {
+        let id = self.id(label);
+        let pos = self.row();
+        let size = Vf2d::new(self.inner_width(), ROW_HEIGHT);
+        let (hovered, clicked) = self.interact(id, pos, size);
+        let open = self.open_nodes.entry(id).or_insert(false);
+        if clicked {
+            *open = !*open;
+        }
+        let open = *open;
+        if hovered {
+            let col = self.style.widget_hot;
+            self.push(GuiDraw::Rect(pos, size, col));
+        }
+        let arrow = if open { "v " } else { "> " };
+        let text_col = self.style.text;
+        self.push(GuiDraw::Text(
+            pos + Vf2d::new(0.0, 2.0),
+            format!("{}{}", arrow, label),
+            text_col,
+        ));
+        if open {
+            self.indent += INDENT;
+        }
+        open
+    }
+
+    pub fn tree_pop(&mut self) {
+        self.indent = (self.indent - INDENT).max(0.0);
+    }
+
+    ///A line graph of the values, scaled so min is at the bottom and max at the top
+    pub fn plot(&mut self, label: &str, values: &[f32], min: f32, max: f32) {
+        let style = self.style;
+        let last = values.last().copied().unwrap_or(0.0);
+        let text_pos = self.row();
+        self.push(GuiDraw::Text(
+            text_pos + Vf2d::new(0.0, 2.0),
+            format!("{}: {:.2}", label, last),
+            style.text,
+        ));
+        let pos = self.cursor + Vf2d::new(self.indent, 0.0);
+        let size = Vf2d::new(self.inner_width(), ROW_HEIGHT * 3.0);
+        self.cursor.y += size.y + 1.0;
+        self.push(GuiDraw::Rect(pos, size, style.widget));
+        let range = if max > min { max - min } else { 1.0 };
+        let point = |i: usize, v: f32| {
+            let x = pos.x + size.x * i as f32 / (values.len().max(2) - 1) as f32;
+            let y = pos.y + size.y * (1.0 - ((v - min) / range).clamp(0.0, 1.0));
+            Vf2d::new(x, y)
+        };
+        for (i, pair) in values.windows(2).enumerate() {
+            self.push(GuiDraw::Line(
+                point(i, pair[0]),
+                point(i + 1, pair[1]),
+                style.accent,
+            ));
+        }
+    }
+
+    pub fn separator(&mut self) {
+        let pos = self.cursor + Vf2d::new(self.indent, ROW_HEIGHT / 2.0);
+        let end = pos + Vf2d::new(self.inner_width(), 0.0);
+        self.cursor.y += ROW_HEIGHT;
+        let col = self.style.border;
+        self.push(GuiDraw::Line(pos, end, col));
+    }
+
+    fn id(&self, label: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.current.hash(&mut hasher);
+        label.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    //Hands out the next row of the current window
+    fn row(&mut self) -> Vf2d {
+        let pos = self.cursor + Vf2d::new(self.indent, 0.0);
+        self.cursor.y += ROW_HEIGHT;
+        pos
+    }
+
+    fn inner_width(&self) -> f32 {
+        let width = self
+            .current
+            .as_ref()
+            .and_then(|title| self.windows.get(title))
+            .map(|window| window.size.x)
+            .unwrap_or(0.0);
+        (width - PADDING * 2.0 - self.indent).max(0.0)
+    }
+
+    fn push(&mut self, draw: GuiDraw) {
+        let window = match &self.current {
+            Some(title) => self.windows.get_mut(title),
+            None => None,
+        };
+        if let Some(window) = window {
+            window.draws.push(draw);
+        }
+    }
+
+    //Returns whether the mouse is over the widget, and whether it was clicked this frame
+    fn interact(&mut self, id: u64, pos: Vf2d, size: Vf2d) -> (bool, bool) {
+        let in_window = self.hovered_window.is_some() && self.hovered_window == self.current;
+        let hovered = in_window
+            && contains(pos, size, self.input.mouse)
+            && (self.active.is_none() || self.active == Some(id));
+        if hovered {
+            self.hot = Some(id);
+            if self.input.pressed {
+                self.active = Some(id);
+            }
+        }
+        let clicked = hovered && self.input.released && self.active == Some(id);
+        (hovered, clicked)
+    }
+
+    fn widget_col(&self, id: u64) -> Pixel {
+        if self.active == Some(id) {
+            self.style.widget_active
+        } else if self.hot == Some(id) {
+            self.style.widget_hot
+        } else {
+            self.style.widget
+        }
+    }
+}
+
+fn contains(pos: Vf2d, size: Vf2d, p: Vf2d) -> bool {
+    p.x >= pos.x && p.y >= pos.y && p.x < pos.x + size.x && p.y < pos.y + size.y
+}
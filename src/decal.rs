@@ -6,7 +6,6 @@ use super::{
 };
 use std::sync::Arc;
 
-#[derive(Clone)]
 pub struct SmallD {
     pub id: i32,
     pub sprite: Sprite,
@@ -18,11 +17,11 @@ pub struct Decal {
     pub d_inst: Arc<SmallD>,
 }
 
-impl Drop for Decal {
+//The texture only goes away once the last Decal and DecalInstance holding it are gone
+impl Drop for SmallD {
     fn drop(&mut self) {
-        let id = self.get().id;
-        if id != -1 {
-            Renderer::delete_texture(&mut (id as u32));
+        if self.id != -1 {
+            Renderer::delete_texture(self.id as u32);
         }
     }
 }
@@ -47,7 +46,7 @@ impl Decal {
                     sprite,
                     uv_scale: Vf2d::from((1.0, 1.0)),
                 };
-                //Decal::update(&mut small);
+                Decal::update(&mut small, renderer);
                 Self {
                     d_inst: Arc::new(small),
                 }
@@ -56,34 +55,223 @@ impl Decal {
         }
     }
 
-    /*fn update(small: &mut SmallD) {
-        if small.id == -1 { return; };
-        small.uv_scale = Vf2d::from(
-            (1.0 / (small.sprite.width as f32),
-             (1.0 / (small.sprite.height as f32))
-            ));
-        Renderer::apply_texture(small.id as u32);
-        Renderer::update_texture(small.id as u32, &small.sprite);
-    }*/
+    //For textures the renderer has already created, like render targets.
+    // The decal takes ownership, so the texture is freed with the last clone
+    pub fn from_texture(id: i32, width: u32, height: u32) -> Self {
+        Self {
+            d_inst: Arc::new(SmallD {
+                id,
+                sprite: Sprite::new(width, height),
+                uv_scale: Vf2d::from((1.0 / width as f32, 1.0 / height as f32)),
+            }),
+        }
+    }
+
+    //A blank texture with nothing kept on the CPU, for decals only ever written with
+    // update_texture_region. The sprite just carries the size, it has no pixels to read
+    pub fn blank(width: u32, height: u32, renderer: &mut Renderer) -> Self {
+        let id = renderer.create_texture(width, height);
+        let clear = vec![Pixel::BLANK; (width * height) as usize];
+        renderer.update_texture_region(id as u32, 0, 0, width, height, &clear);
+        Self {
+            d_inst: Arc::new(SmallD {
+                id,
+                sprite: Sprite {
+                    width,
+                    height,
+                    ..Default::default()
+                },
+                uv_scale: Vf2d::from((1.0 / width as f32, 1.0 / height as f32)),
+            }),
+        }
+    }
+
+    fn update(small: &mut SmallD, renderer: &Renderer) {
+        if small.id == -1 {
+            return;
+        }
+        small.uv_scale = Vf2d::from((
+            1.0 / (small.sprite.width as f32),
+            1.0 / (small.sprite.height as f32),
+        ));
+        renderer.update_texture(small.id as u32, &small.sprite);
+    }
 
     pub fn get(&self) -> Arc<SmallD> {
         Arc::clone(&self.d_inst)
     }
 }
 
+///Packs several sprites into one texture. Decals drawn from the same atlas share a
+/// texture, so a mix of them still goes out in a single batch.
+#[derive(Clone)]
+pub struct DecalAtlas {
+    pub decal: Decal,
+    //Source position and size of each sprite, in the order they were given
+    pub regions: Vec<(Vf2d, Vf2d)>,
+}
+
+impl DecalAtlas {
+    pub fn create(sprites: &[Sprite], renderer: &mut Renderer) -> Self {
+        //Shelf packing, tallest sprites first so each row wastes as little as possible
+        let area: u32 = sprites.iter().map(|s| s.width * s.height).sum();
+        let width = sprites
+            .iter()
+            .map(|s| s.width)
+            .max()
+            .unwrap_or(0)
+            .max((area as f32).sqrt().ceil() as u32);
+        let mut order: Vec<usize> = (0..sprites.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(sprites[*i].height));
+
+        let mut placed = vec![Vi2d::new(0, 0); sprites.len()];
+        let (mut x, mut y, mut row_height) = (0, 0, 0);
+        for i in order {
+            let spr = &sprites[i];
+            if x + spr.width > width {
+                x = 0;
+                y += row_height;
+                row_height = 0;
+            }
+            placed[i] = Vi2d::new(x as i32, y as i32);
+            x += spr.width;
+            row_height = row_height.max(spr.height);
+        }
+
+        let mut atlas = Sprite::new(width, y + row_height);
+        for (spr, pos) in sprites.iter().zip(placed.iter()) {
+            for sy in 0..spr.height {
+                for sx in 0..spr.width {
+                    atlas.set_pixel(pos.x as u32 + sx, pos.y as u32 + sy, spr.get_pixel(sx, sy));
+                }
+            }
+        }
+        let regions = sprites
+            .iter()
+            .zip(placed.iter())
+            .map(|(spr, pos)| {
+                (
+                    Vf2d::new(pos.x as f32, pos.y as f32),
+                    Vf2d::new(spr.width as f32, spr.height as f32),
+                )
+            })
+            .collect();
+        Self {
+            decal: Decal::create(Some(atlas), renderer),
+            regions,
+        }
+    }
+}
+
+//Same set of modes as PGE's SetDecalMode, picked per instance so batches can mix them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DecalMode {
+    Normal,
+    Additive,
+    Multiplicative,
+    Stencil,
+    Illuminate,
+    Wireframe,
+}
+
+impl Default for DecalMode {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+impl DecalMode {
+    pub const ALL: [DecalMode; 6] = [
+        DecalMode::Normal,
+        DecalMode::Additive,
+        DecalMode::Multiplicative,
+        DecalMode::Stencil,
+        DecalMode::Illuminate,
+        DecalMode::Wireframe,
+    ];
+
+    pub fn blend_state(&self) -> wgpu::BlendState {
+        let (src_factor, dst_factor) = match self {
+            DecalMode::Normal | DecalMode::Wireframe => {
+                (wgpu::BlendFactor::SrcAlpha, wgpu::BlendFactor::OneMinusSrcAlpha)
+            }
+            DecalMode::Additive => (wgpu::BlendFactor::SrcAlpha, wgpu::BlendFactor::One),
+            DecalMode::Multiplicative => {
+                (wgpu::BlendFactor::Dst, wgpu::BlendFactor::OneMinusSrcAlpha)
+            }
+            //Only the decal's alpha is kept, it cuts the shape out of what is below
+            DecalMode::Stencil => (wgpu::BlendFactor::Zero, wgpu::BlendFactor::SrcAlpha),
+            DecalMode::Illuminate => {
+                (wgpu::BlendFactor::OneMinusSrcAlpha, wgpu::BlendFactor::SrcAlpha)
+            }
+        };
+        wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                operation: wgpu::BlendOperation::Add,
+                src_factor,
+                dst_factor,
+            },
+            alpha: wgpu::BlendComponent {
+                operation: wgpu::BlendOperation::Add,
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+            },
+        }
+    }
+
+    pub fn polygon_mode(&self) -> wgpu::PolygonMode {
+        match self {
+            //Lines need NON_FILL_POLYGON_MODE, which the web backend doesn't get
+            #[cfg(not(target_arch = "wasm32"))]
+            DecalMode::Wireframe => wgpu::PolygonMode::Line,
+            _ => wgpu::PolygonMode::Fill,
+        }
+    }
+}
+
+//How the points of a DecalInstance are turned into triangles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecalStructure {
+    //Every point after the first two adds a triangle with the first point, quads are fans
+    Fan,
+    //Every three points are their own triangle
+    List,
+}
+
+impl Default for DecalStructure {
+    fn default() -> Self {
+        Self::Fan
+    }
+}
+
 #[derive(Clone)]
 pub struct DecalInstance {
     pub decal: Option<Arc<SmallD>>,
+    pub mode: DecalMode,
     pub pos: [Vf2d; 4],
     pub uv: [Vf2d; 4],
     pub w: [f32; 4],
     pub tint: [Pixel; 4],
+    //Set for anything that isn't a quad, which then ignores the arrays above.
+    // Quads are most of what's drawn, so only polygons pay for the allocations.
+    pub polygon: Option<DecalPolygon>,
+}
+
+///The points of a polygon decal, with one uv, w and tint per point
+#[derive(Clone)]
+pub struct DecalPolygon {
+    pub structure: DecalStructure,
+    pub pos: Vec<Vf2d>,
+    pub uv: Vec<Vf2d>,
+    pub w: Vec<f32>,
+    pub tint: Vec<Pixel>,
 }
 
 impl Default for DecalInstance {
     fn default() -> Self {
         Self {
             decal: None,
+            mode: DecalMode::Normal,
             pos: [Vf2d::from((0.0, 0.0)); 4],
             uv: [
                 Vf2d::from((0.0, 0.0)),
@@ -93,6 +281,7 @@ impl Default for DecalInstance {
             ],
             w: [1.0; 4],
             tint: [Pixel::rgb(255, 255, 255); 4],
+            polygon: None,
         }
     }
 }
@@ -101,23 +290,108 @@ impl DecalInstance {
     fn get(&self) -> &Arc<SmallD> {
         self.decal.as_ref().unwrap()
     }
+
+    ///A polygon with one uv, w and tint per point
+    pub fn polygon(
+        decal: Option<Arc<SmallD>>,
+        structure: DecalStructure,
+        pos: Vec<Vf2d>,
+        uv: Vec<Vf2d>,
+        tint: Vec<Pixel>,
+    ) -> Self {
+        Self {
+            decal,
+            polygon: Some(DecalPolygon {
+                structure,
+                w: vec![1.0; pos.len()],
+                pos,
+                uv,
+                tint,
+            }),
+            ..Default::default()
+        }
+    }
+
+    ///Splits the points into triangles ready to go in a vertex buffer
+    pub fn get_vertices(&self) -> Vec<DecalVertex> {
+        let mut vertices = vec![];
+        self.append_vertices(&mut vertices);
+        vertices
+    }
+
+    pub fn append_vertices(&self, vertices: &mut Vec<DecalVertex>) {
+        let (structure, pos, uv, w, tint) = match &self.polygon {
+            Some(p) => (p.structure, &p.pos[..], &p.uv[..], &p.w[..], &p.tint[..]),
+            None => (
+                DecalStructure::Fan,
+                &self.pos[..],
+                &self.uv[..],
+                &self.w[..],
+                &self.tint[..],
+            ),
+        };
+        //Points missing a uv, w or tint fall back to the defaults instead of panicking
+        let vert = |i: usize| {
+            let uv = uv.get(i).copied().unwrap_or_default();
+            let w = w.get(i).copied().unwrap_or(1.0);
+            let tint = tint.get(i).copied().unwrap_or(Pixel::WHITE);
+            #[cfg(not(target_arch = "wasm32"))]
+            let v = uv.y;
+            //Textures are sampled upside down on the web, see the layer quad
+            #[cfg(target_arch = "wasm32")]
+            let v = w - uv.y;
+            DecalVertex {
+                position: [pos[i].x, pos[i].y],
+                tex_coords: [uv.x, v, w],
+                tint,
+            }
+        };
+        match structure {
+            DecalStructure::Fan => {
+                for i in 1..pos.len().saturating_sub(1) {
+                    vertices.extend_from_slice(&[vert(0), vert(i), vert(i + 1)]);
+                }
+            }
+            DecalStructure::List => {
+                for i in (0..pos.len() / 3).map(|t| t * 3) {
+                    vertices.extend_from_slice(&[vert(i), vert(i + 1), vert(i + 2)]);
+                }
+            }
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct DecalTriangleInstance {
-    pub decal: Decal,
-    pub points: [Vf2d; 3],
-    pub texture: [Vf2d; 3],
-    pub colours: [Pixel; 3],
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DecalVertex {
+    pub position: [f32; 2],
+    pub tex_coords: [f32; 3],
+    pub tint: Pixel,
 }
 
-impl DecalTriangleInstance {
-    pub fn new() -> Self {
-        Self {
-            decal: Decal::empty(),
-            points: [Vf2d::from((0.0, 0.0)); 3],
-            texture: [Vf2d::from((0.0, 0.0)); 3],
-            colours: [Pixel::rgb(255, 255, 255); 3],
+impl DecalVertex {
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<DecalVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Unorm8x4,
+                },
+            ],
         }
     }
 }
@@ -1,12 +1,15 @@
 use super::{
-    olc::OlcData,
-    camera::Camera,
-    decal::{Decal, DecalInstance, SmallD},
+    olc::{Olc, OlcData},
+    camera::{Camera, Projection},
+    console::{Console, ConsoleArgs, ConsoleCommand, ConsoleFunc},
+    decal::{Decal, DecalAtlas, DecalInstance, DecalMode, DecalStructure, SmallD},
+    font::{Font, HorizontalAlign, VerticalAlign},
     geometry::{Triangle, UV, Mesh, Vertex},
-    layer::{LayerDesc, LayerInfo, LayerType, Image, EMPTY_IMAGE, PipelineBundle},
+    layer::{LayerDesc, LayerInfo, LayerType, LayerBlend, Image, Mask, EMPTY_IMAGE, CONSOLE_LAYER_ORDER, GUI_LAYER_ORDER, PipelineBundle, Target},
     pixel::{Pixel, PixelMode},
+    rich_text::{modulate, RichText},
     platform::{PLATFORM_DATA, Platform, Key},
-    renderer::Renderer,
+    renderer::{CullStats, DecalStats, PickHit, Renderer},
     sprite::{Sprite},
     util::{HWButton, Mouse, Vf2d, Vi2d, BMPLoader, ImageLoader, PNGLoader},
 };
@@ -32,6 +35,12 @@ pub struct OLCEngine<D: OlcData + 'static> {
     pub layers: Vec<LayerDesc<D>>,
     pub mouse_position: Vi2d,
     pub font_decal: Decal,
+    pub default_font: Font,
+    pub prop_font: Font,
+    pub decal_mode: DecalMode,
+    pub console: Console<D>,
+    //The Image layer GUI decals are drawn on, see gui_layer
+    pub gui_layer: Option<u32>,
     pub depth_buffer: Vec<f64>,
     pub window: winit::window::Window,
 }
@@ -67,6 +76,9 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         self.draw_target = 0;
         self.mouse_position = Vi2d::new(0, 0);
         self.font_decal = Decal::empty();
+        self.decal_mode = DecalMode::Normal;
+        self.console = Console::new();
+        self.gui_layer = None;
     }
 
     pub fn is_focused(&self) -> bool {
@@ -74,6 +86,14 @@ impl<D: OlcData + 'static> OLCEngine<D> {
     }
 
     pub fn get_key(&self, k: Key) -> HWButton {
+        //The console has the keyboard to itself while it is open
+        if self.console.shown {
+            return HWButton::new();
+        }
+        self.get_raw_key(k)
+    }
+
+    fn get_raw_key(&self, k: Key) -> HWButton {
         unsafe {
             if let Some(button) = PLATFORM_DATA.key_map.as_mut().unwrap().get(&k) {
                 *button
@@ -117,10 +137,22 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         Renderer::draw_triangles(triangles, texture);
     }
 
-    pub fn set_perspective(&self, fov: f32, aspect: f32, near_clip: f32, far_clip: f32) {
-        let f_H = ((fov / 360.0 * std::f32::consts::PI) * near_clip) as f64;
-        let f_W = f_H * aspect as f64;
-        //(GL.glFrustum)(-f_W, f_W, -f_H, f_H, near_clip as f64, far_clip as f64)
+    pub fn set_perspective(&mut self, fov: f32, aspect: f32, near_clip: f32, far_clip: f32) {
+        self.camera.fov = fov;
+        self.camera.aspect = aspect;
+        self.camera.clip_near = near_clip;
+        self.camera.clip_far = far_clip;
+        self.camera.projection = Projection::Perspective;
+        self.camera.update_mat();
+    }
+
+    ///Height is how many world units fit vertically on screen
+    pub fn set_orthographic(&mut self, height: f32, aspect: f32, near_clip: f32, far_clip: f32) {
+        self.camera.aspect = aspect;
+        self.camera.clip_near = near_clip;
+        self.camera.clip_far = far_clip;
+        self.camera.projection = Projection::Orthographic { height };
+        self.camera.update_mat();
     }
 
     pub fn set_mouse(&self, i: usize, k: HWButton) {
@@ -190,7 +222,20 @@ impl<D: OlcData + 'static> OLCEngine<D> {
     }
 
     pub fn mouse_wheel(&self) -> i32 {
-        0
+        unsafe { PLATFORM_DATA.mouse_wheel_delta }
+    }
+
+    ///Printable characters typed since the last frame, in order
+    pub fn get_text_input(&self) -> String {
+        //Typing into the console doesn't reach the game either
+        if self.console.shown {
+            return String::new();
+        }
+        self.get_raw_text_input()
+    }
+
+    fn get_raw_text_input(&self) -> String {
+        unsafe { PLATFORM_DATA.text_input.clone() }
     }
 
     //pub fn get_window_mouse() -> Vi2d { Vi2d }
@@ -236,6 +281,23 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         0
     }
 
+    pub fn get_decal_stats(&self) -> DecalStats {
+        self.renderer.decal_stats
+    }
+
+    pub fn get_cull_stats(&self) -> CullStats {
+        self.renderer.cull_stats
+    }
+
+    ///What is under the mouse, for the game objects in the mask drawn with the engine's
+    /// camera
+    pub fn pick(&self, mask: Mask) -> Option<PickHit> {
+        let screen: Vf2d = (self.screen_width() as f32, self.screen_height() as f32).into();
+        let mouse: Vf2d = (self.mouse_x(), self.mouse_y()).into();
+        let ray = self.camera.screen_to_ray(mouse, screen)?;
+        self.renderer.raycast(&ray, mask)
+    }
+
     pub fn get_elapsed_time(&self) -> f32 {
         0.0
     }
@@ -301,11 +363,64 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         }
     }
 
-    pub fn set_layer_offset(&self, layer: u8, x: f32, y: f32) {}
+    pub fn set_layer_offset(&mut self, layer_id: u32, x: f32, y: f32) {
+        if let Some(image_info) = self.get_image_layer_mut(layer_id) {
+            image_info.offset = Vf2d::new(x, y);
+        }
+    }
+
+    pub fn set_layer_scale(&mut self, layer_id: u32, x: f32, y: f32) {
+        if let Some(image_info) = self.get_image_layer_mut(layer_id) {
+            image_info.scale = Vf2d::new(x, y);
+        }
+    }
+
+    pub fn set_layer_tint(&mut self, layer_id: u32, tint: Pixel) {
+        if let Some(image_info) = self.get_image_layer_mut(layer_id) {
+            image_info.tint = tint;
+        }
+    }
+
+    pub fn set_layer_blend(&mut self, layer_id: u32, blend: LayerBlend) {
+        if let Ok(layer) = self.get_layer_mut(layer_id) {
+            match &mut layer.layer_info {
+                LayerInfo::Image(image_info) => image_info.blend = blend,
+                LayerInfo::Target(target) => target.blend = blend,
+                LayerInfo::Render(_) => {}
+            }
+        }
+    }
+
+    //Lower z_index layers are drawn first, so higher ones end up on top
+    pub fn set_layer_order(&mut self, layer_id: u32, z_index: i32) {
+        if let Ok(layer) = self.get_layer_mut(layer_id) {
+            layer.z_index = z_index;
+        }
+    }
+
+    pub fn move_layer(&mut self, layer_id: u32, delta: i32) {
+        if let Ok(layer) = self.get_layer_mut(layer_id) {
+            layer.z_index = layer.z_index.saturating_add(delta);
+        }
+    }
 
-    pub fn set_layer_scale(&self, layer: u8, x: f32, y: f32) {}
+    ///A plain Image layer for GUI decals, made the first time it's asked for so everything
+    /// drawing GUI shares one layer on top of the game's, at GUI_LAYER_ORDER. It has
+    /// nothing to do with Mask::GUI, which picks game objects for draw_mask.
+    pub fn gui_layer(&mut self) -> u32 {
+        if let Some(id) = self.gui_layer {
+            return id;
+        }
+        let id = self.add_layer_with_info(LayerInfo::Image(Image::default()));
+        self.set_layer_order(id, GUI_LAYER_ORDER);
+        self.set_layer_visible(id, true);
+        self.gui_layer = Some(id);
+        id
+    }
 
-    pub fn set_layer_tint(&self, layer: u8, tint: Pixel) {}
+    pub fn get_layer_order(&self, layer_id: u32) -> Option<i32> {
+        self.get_layer_ref(layer_id).map(|layer| layer.z_index)
+    }
 
     //We'll come back to this
     //pub fn set_layer_custom_render_function
@@ -332,6 +447,18 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         None
     }
 
+    pub fn get_image_layer_mut(&mut self, layer_id: u32) -> Option<&mut Image> {
+        let layer_iter = self.layers.iter_mut();
+        for layer in layer_iter {
+            if layer.id == layer_id {
+                if let LayerInfo::Image(image_info) = &mut layer.layer_info {
+                    return Some(image_info);
+                }
+            }
+        }
+        None
+    }
+
     pub fn get_layer(&self, layer_id: u32) -> Result<&LayerDesc<D>, ()> {
         let layer_iter = self.layers.iter();
         for layer in layer_iter {
@@ -375,12 +502,13 @@ impl<D: OlcData + 'static> OLCEngine<D> {
     }
 
     pub fn add_layer(&mut self, layer_type: LayerType) -> u32 {
+        if let LayerType::Target = layer_type {
+            return self.add_render_target(self.pixels_w, self.pixels_h);
+        }
         let lay_id = self.renderer.create_texture(self.pixels_w, self.pixels_h);
         let mut layer = LayerDesc::empty(layer_type);
         layer.id = lay_id as u32;
         self.layers.push(layer);
-        self.renderer.active_decals.insert(lay_id as usize, lay_id as u32);
-        self.renderer.update_layer_texture_groups();
         lay_id as u32
     }
 
@@ -392,22 +520,86 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         let mut layer = LayerDesc::new(layer_info);
         layer.id = lay_id as u32;
         self.layers.push(layer);
-        self.renderer.active_decals.insert(lay_id as usize, lay_id as u32);
-        self.renderer.update_layer_texture_groups();
         lay_id as u32
     }
 
+    ///Adds a hidden layer backed by an offscreen texture. Set it as the draw target to draw
+    /// decals into it, using its own pixel coordinates. It can be shown like any other layer,
+    /// or drawn elsewhere with the decal from get_render_target_decal.
+    pub fn add_render_target(&mut self, width: u32, height: u32) -> u32 {
+        let lay_id = self.renderer.create_target_texture(width, height);
+        let mut layer = LayerDesc::new(LayerInfo::Target(Target {
+            width,
+            height,
+            decal: Decal::from_texture(lay_id, width, height),
+            blend: LayerBlend::Alpha,
+        }));
+        layer.id = lay_id as u32;
+        self.layers.push(layer);
+        lay_id as u32
+    }
+
+    pub fn get_render_target_decal(&self, layer_id: u32) -> Option<Arc<SmallD>> {
+        self.get_layer_ref(layer_id)?
+            .as_target_layer()
+            .map(|target| target.decal.get())
+    }
+
+    //Targets keep what was drawn into them until they are cleared
+    pub fn clear_render_target(&mut self, layer_id: u32, p: Pixel) {
+        if self.get_render_target_decal(layer_id).is_some() {
+            self.renderer.clear_texture(layer_id, p);
+        }
+    }
+
+    ///Reads a render target back into a Sprite, blocking until the GPU is done.
+    /// On the web use Renderer::read_texture and await it instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn read_render_target(&self, layer_id: u32) -> Option<Sprite> {
+        self.get_render_target_decal(layer_id)?;
+        futures::executor::block_on(self.renderer.read_texture(layer_id))
+    }
+
+    //The first layer is the primary draw target, so it can't be removed
+    pub fn remove_layer(&mut self, layer_id: u32) -> Option<LayerDesc<D>> {
+        let index = self.layers.iter().position(|layer| layer.id == layer_id)?;
+        if index == 0 {
+            return None;
+        }
+        let layer = self.layers.remove(index);
+        //A target's texture belongs to its decal, and goes once the last clone is dropped
+        if layer.as_target_layer().is_none() {
+            self.renderer.free_texture(layer.id);
+        }
+        if self.draw_target == layer_id {
+            self.reset_draw_target();
+        }
+        if self.gui_layer == Some(layer_id) {
+            self.gui_layer = None;
+        }
+        Some(layer)
+    }
+
     pub fn load_sprite(&self, path: &str) -> Sprite {
         Sprite::load_from_file::<BMPLoader>(path).unwrap()
     }
 
-    fn push_decal_instance(&mut self, di: DecalInstance) {
+    fn push_decal_instance(&mut self, mut di: DecalInstance) {
+        di.mode = self.decal_mode;
         self.get_draw_target()
             .expect("Can't get draw target")
             .vec_decal_instance
             .push(di);
     }
 
+    pub fn set_decal_mode(&mut self, mode: DecalMode) {
+        self.decal_mode = mode;
+    }
+
+    pub fn get_decal_mode(&self) -> DecalMode {
+        self.decal_mode
+    }
+
     pub fn set_pixel_mode(&mut self, m: PixelMode) {}
 
     pub fn get_pixel_mode(&self) -> PixelMode {
@@ -876,7 +1068,9 @@ impl<D: OlcData + 'static> OLCEngine<D> {
                 }
             }
         }
-        //self.font_decal = Decal::create(Some(font_sprite), &mut self.renderer);
+        self.font_decal = Decal::create(Some(font_sprite), &mut self.renderer);
+        self.default_font = Font::from_font_sheet(self.font_decal.clone());
+        self.prop_font = self.default_font.proportional();
     }
 
     pub fn draw_decal(&mut self, pos: Vf2d, decal: Arc<SmallD>) {
@@ -902,14 +1096,16 @@ impl<D: OlcData + 'static> OLCEngine<D> {
             ((pos.y * self.inv_screen_size.y) * 2.0 - 1.0) * -1.0,
         ));
         let screen_space_dim = Vf2d::from((
-            screen_space_pos.x + (2.0 * (decal.sprite.width as f32) * self.inv_screen_size.x),
-            screen_space_pos.y - (2.0 * (decal.sprite.height as f32) * self.inv_screen_size.y),
+            screen_space_pos.x
+                + (2.0 * (decal.sprite.width as f32) * self.inv_screen_size.x) * scale.x,
+            screen_space_pos.y
+                - (2.0 * (decal.sprite.height as f32) * self.inv_screen_size.y) * scale.y,
         ));
         let mut di = DecalInstance {
             decal: Some(decal),
+            tint: [tint; 4],
             ..Default::default()
         };
-        di.tint[0] = tint;
         di.pos[0] = Vf2d::from((screen_space_pos.x, screen_space_pos.y));
         di.pos[1] = Vf2d::from((screen_space_pos.x, screen_space_dim.y));
         di.pos[2] = Vf2d::from((screen_space_dim.x, screen_space_dim.y));
@@ -934,8 +1130,10 @@ impl<D: OlcData + 'static> OLCEngine<D> {
             screen_space_pos.x + (2.0 * (source_size.x as f32) * self.inv_screen_size.x) * scale.x,
             screen_space_pos.y - (2.0 * (source_size.y as f32) * self.inv_screen_size.y) * scale.y,
         ));
-        let mut di = DecalInstance::default();
-        di.tint[0] = tint;
+        let mut di = DecalInstance {
+            tint: [tint; 4],
+            ..Default::default()
+        };
 
         di.pos[0] = Vf2d::from((screen_space_pos.x, screen_space_pos.y));
         di.pos[1] = Vf2d::from((screen_space_pos.x, screen_space_dim.y));
@@ -960,6 +1158,19 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         self.push_decal_instance(di);
     }
 
+    pub fn draw_atlas_decal(
+        &mut self,
+        pos: Vf2d,
+        atlas: &DecalAtlas,
+        index: usize,
+        scale: Vf2d,
+        tint: Pixel,
+    ) {
+        if let Some((source_pos, source_size)) = atlas.regions.get(index) {
+            self.draw_partial_decal(pos, atlas.decal.get(), *source_pos, *source_size, scale, tint);
+        }
+    }
+
     pub fn draw_rotated_decal(
         &mut self,
         pos: Vf2d,
@@ -969,32 +1180,27 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         scale: Vf2d,
         tint: Pixel,
     ) {
-        let mut di = DecalInstance::default();
-        di.tint[0] = tint;
-        di.pos[0] = Vf2d::new(0.0 - center.x * scale.x, 0.0 - center.y * scale.y);
-        di.pos[1] = Vf2d::new(
-            0.0 - center.x * scale.x,
-            decal.sprite.height as f32 - center.y * scale.y,
-        );
-        di.pos[2] = Vf2d::new(
-            decal.sprite.width as f32 - center.x * scale.x,
-            decal.sprite.height as f32 - center.y * scale.y,
-        );
-        di.pos[3] = Vf2d::new(
-            decal.sprite.width as f32 - center.x * scale.x,
-            0.0 - center.y * scale.y,
-        );
+        let mut di = DecalInstance {
+            tint: [tint; 4],
+            ..Default::default()
+        };
+        let (w, h) = (decal.sprite.width as f32, decal.sprite.height as f32);
+        di.pos[0] = (Vf2d::new(0.0, 0.0) - center) * scale;
+        di.pos[1] = (Vf2d::new(0.0, h) - center) * scale;
+        di.pos[2] = (Vf2d::new(w, h) - center) * scale;
+        di.pos[3] = (Vf2d::new(w, 0.0) - center) * scale;
         let (c, s) = (angle.cos(), angle.sin());
         for i in 0..4 {
-            di.pos[i] = Vf2d::new(
-                di.pos[1].x * c - di.pos[i].y * s,
-                di.pos[i].x * s + di.pos[i].y * c,
-            );
+            di.pos[i] = pos
+                + Vf2d::new(
+                    di.pos[i].x * c - di.pos[i].y * s,
+                    di.pos[i].x * s + di.pos[i].y * c,
+                );
             di.pos[i] = Vf2d::new(
                 di.pos[i].x * self.inv_screen_size.x * 2.0 - 1.0,
                 di.pos[i].y * self.inv_screen_size.y * 2.0 - 1.0,
             );
-            di.pos[i].y += -1.0;
+            di.pos[i].y *= -1.0;
         }
         di.decal = Some(decal);
         self.push_decal_instance(di);
@@ -1007,9 +1213,9 @@ impl<D: OlcData + 'static> OLCEngine<D> {
     pub fn draw_warped_decal_with_tint(&mut self, decal: Arc<SmallD>, pos: &[Vf2d], tint: Pixel) {
         let mut di = DecalInstance {
             decal: Some(decal),
+            tint: [tint; 4],
             ..Default::default()
         };
-        di.tint[0] = tint;
         let mut center = Vf2d::new(0.0, 0.0);
         let mut rd: f32 = (pos[2].x - pos[0].x) * (pos[3].y - pos[1].y)
             - (pos[3].x - pos[1].x) * (pos[2].y - pos[0].y);
@@ -1021,7 +1227,7 @@ impl<D: OlcData + 'static> OLCEngine<D> {
             let sn: f32 = ((pos[2].x - pos[0].x) * (pos[0].y - pos[1].y)
                 - (pos[2].y - pos[0].y) * (pos[0].x - pos[1].x))
                 * rd;
-            if !((0.0..=1.0).contains(&rn) || (0.0..=1.0).contains(&sn)) {
+            if (0.0..=1.0).contains(&rn) && (0.0..=1.0).contains(&sn) {
                 let i = pos[2] - pos[0];
                 center = pos[0] + Vf2d::new(rn * i.x, rn * i.y);
             }
@@ -1055,8 +1261,10 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         source_size: Vf2d,
         tint: Pixel,
     ) {
-        let mut di = DecalInstance::default();
-        di.tint[0] = tint;
+        let mut di = DecalInstance {
+            tint: [tint; 4],
+            ..Default::default()
+        };
         let mut center = Vf2d::new(0.0, 0.0);
         let mut rd: f32 = (pos[2].x - pos[0].x) * (pos[3].y - pos[1].y)
             - (pos[3].x - pos[1].x) * (pos[2].y - pos[0].y);
@@ -1082,9 +1290,9 @@ impl<D: OlcData + 'static> OLCEngine<D> {
             let sn: f32 = ((pos[2].x - pos[0].x) * (pos[0].y - pos[1].y)
                 - (pos[2].y - pos[0].y) * (pos[0].x - pos[1].x))
                 * rd;
-            if !((0.0..=1.0).contains(&rn) || (0.0..=1.0).contains(&sn)) {
+            if (0.0..=1.0).contains(&rn) && (0.0..=1.0).contains(&sn) {
                 let i = pos[2] - pos[0];
-                center = Vf2d::new(pos[0].x + rn, pos[0].y + rn) * i;
+                center = pos[0] + Vf2d::new(rn * i.x, rn * i.y);
             }
             let mut d: [f32; 4] = [0.0; 4];
             for i in 0..4 {
@@ -1117,7 +1325,7 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         col: Vec<Pixel>,
     ) {
         let mut di = DecalInstance::default();
-        if decal.id > 0 {
+        if decal.id >= 0 {
             di.decal = Some(decal);
         } else {
             di.decal = None;
@@ -1126,7 +1334,7 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         for i in 0..4 {
             di.pos[i] = Vf2d::from((
                 (pos[i].x * self.inv_screen_size.x) * 2.0 - 1.0,
-                (pos[i].y * self.inv_screen_size.y) * 2.0 - 1.0,
+                ((pos[i].y * self.inv_screen_size.y) * 2.0 - 1.0) * -1.0,
             ));
             di.uv[i] = uv[i];
             di.tint[i] = col[i];
@@ -1166,6 +1374,97 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         self.draw_explicit_decal(Decal::empty().get(), points, uvs, cols);
     }
 
+    //Pixel coordinates to the -1..1 space decal instances are stored in
+    fn to_decal_space(&self, pos: Vf2d) -> Vf2d {
+        Vf2d::new(
+            (pos.x * self.inv_screen_size.x) * 2.0 - 1.0,
+            ((pos.y * self.inv_screen_size.y) * 2.0 - 1.0) * -1.0,
+        )
+    }
+
+    ///Draws a convex polygon as a triangle fan, pos, uv and col are given per point.
+    /// With no decal the polygon is filled with the colours alone.
+    pub fn draw_polygon_decal(
+        &mut self,
+        decal: Option<Arc<SmallD>>,
+        pos: &[Vf2d],
+        uv: &[Vf2d],
+        col: &[Pixel],
+    ) {
+        if pos.len() < 3 {
+            return;
+        }
+        let di = DecalInstance::polygon(
+            decal,
+            DecalStructure::Fan,
+            pos.iter().map(|p| self.to_decal_space(*p)).collect(),
+            uv.to_vec(),
+            col.to_vec(),
+        );
+        self.push_decal_instance(di);
+    }
+
+    ///Like draw_polygon_decal, but every three indices make a triangle, so any shape works
+    pub fn draw_indexed_polygon_decal(
+        &mut self,
+        decal: Option<Arc<SmallD>>,
+        pos: &[Vf2d],
+        uv: &[Vf2d],
+        col: &[Pixel],
+        indices: &[u32],
+    ) {
+        let indices: Vec<usize> = indices
+            .iter()
+            .map(|i| *i as usize)
+            .filter(|i| *i < pos.len())
+            .collect();
+        if indices.len() < 3 {
+            return;
+        }
+        let di = DecalInstance::polygon(
+            decal,
+            DecalStructure::List,
+            indices.iter().map(|i| self.to_decal_space(pos[*i])).collect(),
+            indices
+                .iter()
+                .map(|i| uv.get(*i).copied().unwrap_or_default())
+                .collect(),
+            indices
+                .iter()
+                .map(|i| col.get(*i).copied().unwrap_or(Pixel::WHITE))
+                .collect(),
+        );
+        self.push_decal_instance(di);
+    }
+
+    ///A one pixel wide line, drawn as a thin quad so it batches with other untextured decals
+    pub fn draw_line_decal(&mut self, pos1: Vf2d, pos2: Vf2d, col: Pixel) {
+        //Run through the middle of the pixels and cover the end pixels completely
+        let (p1, p2) = (pos1 + 0.5, pos2 + 0.5);
+        let dir = if (p2 - p1).mag2() == 0.0 {
+            Vf2d::new(1.0, 0.0)
+        } else {
+            (p2 - p1).norm()
+        };
+        let (along, across) = (dir * 0.5, dir.perp() * 0.5);
+        let points = [
+            p1 - along - across,
+            p1 - along + across,
+            p2 + along + across,
+            p2 + along - across,
+        ];
+        self.draw_polygon_decal(None, &points, &[], &[col; 4]);
+    }
+
+    pub fn draw_rect_decal(&mut self, pos: Vf2d, size: Vf2d, col: Pixel) {
+        let (tl, br) = (pos, pos + size);
+        let (tr, bl) = (Vf2d::new(br.x, tl.y), Vf2d::new(tl.x, br.y));
+        self.draw_line_decal(tl, tr, col);
+        self.draw_line_decal(tr, br, col);
+        self.draw_line_decal(br, bl, col);
+        self.draw_line_decal(bl, tl, col);
+    }
+
     pub fn draw_partial_rotated_decal(
         &mut self,
         pos: Vf2d,
@@ -1177,23 +1476,26 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         scale: Vf2d,
         tint: Pixel,
     ) {
-        let mut di = DecalInstance::default();
-        di.tint[0] = tint;
+        let mut di = DecalInstance {
+            tint: [tint; 4],
+            ..Default::default()
+        };
         di.pos[0] = (Vf2d::new(0.0, 0.0) - center) * scale;
         di.pos[1] = (Vf2d::new(0.0, source_size.y) - center) * scale;
         di.pos[2] = (Vf2d::new(source_size.x, source_size.y) - center) * scale;
         di.pos[3] = (Vf2d::new(source_size.x, 0.0) - center) * scale;
         let (c, s) = (angle.cos(), angle.sin());
         for i in 0..4 {
-            di.pos[i] = Vf2d::new(
-                di.pos[1].x * c - di.pos[i].y * s,
-                di.pos[i].x * s + di.pos[i].y * c,
-            );
+            di.pos[i] = pos
+                + Vf2d::new(
+                    di.pos[i].x * c - di.pos[i].y * s,
+                    di.pos[i].x * s + di.pos[i].y * c,
+                );
             di.pos[i] = Vf2d::new(
                 di.pos[i].x * self.inv_screen_size.x * 2.0 - 1.0,
                 di.pos[i].y * self.inv_screen_size.y * 2.0 - 1.0,
             );
-            di.pos[i].y += -1.0;
+            di.pos[i].y *= -1.0;
         }
         let uvtl = Vf2d::new(
             source_pos.x * decal.uv_scale.x,
@@ -1212,6 +1514,94 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         di.decal = Some(decal);
         self.push_decal_instance(di);
     }
+    pub fn draw_string(&mut self, pos: Vi2d, text: &str, col: Pixel) {
+        self.draw_string_with_font(pos, text, col, 1, None);
+    }
+
+    pub fn draw_string_with_scale(&mut self, pos: Vi2d, text: &str, col: Pixel, scale: u32) {
+        self.draw_string_with_font(pos, text, col, scale, None);
+    }
+
+    pub fn draw_string_prop(&mut self, pos: Vi2d, text: &str, col: Pixel) {
+        self.draw_string_prop_with_scale(pos, text, col, 1);
+    }
+
+    pub fn draw_string_prop_with_scale(&mut self, pos: Vi2d, text: &str, col: Pixel, scale: u32) {
+        let placed = self.prop_font.layout(text);
+        let pixels = self.prop_font.pixels(&placed);
+        self.draw_text_pixels(pos, &pixels, col, scale);
+    }
+
+    ///Draws text into the draw target on the CPU, None uses the built in 8x8 font
+    pub fn draw_string_with_font(
+        &mut self,
+        pos: Vi2d,
+        text: &str,
+        col: Pixel,
+        scale: u32,
+        font: Option<&Font>,
+    ) {
+        let font = font.unwrap_or(&self.default_font);
+        let pixels = font.pixels(&font.layout(text));
+        self.draw_text_pixels(pos, &pixels, col, scale);
+    }
+
+    ///Draws text aligned inside the rect at pos, optionally wrapped to its width
+    pub fn draw_string_in_rect(
+        &mut self,
+        pos: Vi2d,
+        size: Vi2d,
+        text: &str,
+        col: Pixel,
+        h_align: HorizontalAlign,
+        v_align: VerticalAlign,
+        wrap: bool,
+        font: Option<&Font>,
+    ) {
+        let font = font.unwrap_or(&self.default_font);
+        let placed = font.layout_in_rect(text, size.to_vf2d(), h_align, v_align, wrap);
+        let pixels = font.pixels(&placed);
+        self.draw_text_pixels(pos, &pixels, col, 1);
+    }
+
+    ///Rich text on the CPU, characters without a colour tag use col
+    pub fn draw_rich_string(
+        &mut self,
+        pos: Vi2d,
+        text: &RichText,
+        col: Pixel,
+        scale: u32,
+        font: Option<&Font>,
+    ) {
+        let scale = scale.max(1) as f32;
+        let glyphs: Vec<_> = {
+            let font = font.unwrap_or(&self.default_font);
+            text.layout(font)
+                .into_iter()
+                .map(|rg| {
+                    let at = Vi2d::new((rg.pos.x * scale) as i32, (rg.pos.y * scale) as i32);
+                    let char_scale = (rg.scale * scale).round().max(1.0) as u32;
+                    let tint = modulate(rg.col.unwrap_or(col), rg.tint);
+                    (at, font.glyph_pixels(&rg.glyph), tint, char_scale)
+                })
+                .collect()
+        };
+        for (at, pixels, tint, char_scale) in glyphs {
+            self.draw_text_pixels(pos + at, &pixels, tint, char_scale);
+        }
+    }
+
+    fn draw_text_pixels(&mut self, pos: Vi2d, pixels: &[Vi2d], col: Pixel, scale: u32) {
+        let scale = scale.max(1) as i32;
+        for p in pixels {
+            for y in 0..scale {
+                for x in 0..scale {
+                    self.draw(pos.x + p.x * scale + x, pos.y + p.y * scale + y, col);
+                }
+            }
+        }
+    }
+
     pub fn draw_string_decal(&mut self, pos: Vf2d, text: &str) {
         self.draw_string_decal_with_color_and_scale(pos, text, Pixel::WHITE, Vf2d::new(1.0, 1.0));
     }
@@ -1231,40 +1621,250 @@ impl<D: OlcData + 'static> OLCEngine<D> {
         col: Pixel,
         scale: Vf2d,
     ) {
-        //self.draw_decal(pos, &FONT_DECAL, scale, Pixel::WHITE);
-        let mut spos = Vf2d::new(0.0, 0.0);
-        for c in text.chars() {
-            if c == '\n' {
-                spos.x = 0.0;
-                spos.y += 8.0 * scale.y;
-            } else {
-                let ox = (c as u8 - 32) % 16;
-                let oy = (c as u8 - 32) / 16;
-                self.draw_partial_decal(
-                    pos + spos,
-                    self.font_decal.get(),
-                    Vf2d::new(ox as f32 * 8.0, oy as f32 * 8.0),
-                    Vf2d::new(8.0, 8.0),
-                    scale,
-                    col,
-                );
-                spos.x += 8.0 * scale.x;
-            }
+        self.draw_string_decal_with_font(pos, text, col, scale, None);
+    }
+
+    ///Draws text with any font, None uses the built in 8x8 font
+    pub fn draw_string_decal_with_font(
+        &mut self,
+        pos: Vf2d,
+        text: &str,
+        col: Pixel,
+        scale: Vf2d,
+        font: Option<&Font>,
+    ) {
+        //Glyphs are collected first so the font isn't borrowed while decals are pushed
+        let glyphs: Vec<(Vf2d, Arc<SmallD>, Vf2d, Vf2d)> = {
+            let font = font.unwrap_or(&self.default_font);
+            font.layout(text)
+                .into_iter()
+                .filter_map(|(glyph_pos, glyph)| {
+                    let page = font.pages.get(glyph.page)?;
+                    Some((glyph_pos * scale, page.get(), glyph.pos, glyph.size))
+                })
+                .collect()
+        };
+        for (glyph_pos, page, source_pos, source_size) in glyphs {
+            self.draw_partial_decal(pos + glyph_pos, page, source_pos, source_size, scale, col);
         }
     }
 
+    ///Rich text through the decal path, characters without a colour tag use col
+    pub fn draw_rich_string_decal(
+        &mut self,
+        pos: Vf2d,
+        text: &RichText,
+        col: Pixel,
+        scale: Vf2d,
+        font: Option<&Font>,
+    ) {
+        let glyphs: Vec<_> = {
+            let font = font.unwrap_or(&self.default_font);
+            text.layout(font)
+                .into_iter()
+                .filter_map(|rg| {
+                    let page = font.pages.get(rg.glyph.page)?;
+                    let tint = modulate(rg.col.unwrap_or(col), rg.tint);
+                    Some((rg.pos * scale, page.get(), rg.glyph, scale * rg.scale, tint))
+                })
+                .collect()
+        };
+        for (glyph_pos, page, glyph, glyph_scale, tint) in glyphs {
+            self.draw_partial_decal(pos + glyph_pos, page, glyph.pos, glyph.size, glyph_scale, tint);
+        }
+    }
+
+    ///For TrueType fonts, rasterises whatever glyphs the text needs before drawing it
+    pub fn draw_string_decal_with_ttf(
+        &mut self,
+        pos: Vf2d,
+        text: &str,
+        col: Pixel,
+        scale: Vf2d,
+        font: &mut Font,
+    ) {
+        font.cache_text(text, &mut self.renderer);
+        self.draw_string_decal_with_font(pos, text, col, scale, Some(font));
+    }
+
+    ///draw_rich_string_decal for TrueType fonts, corruption glyphs are cached along with the text
+    pub fn draw_rich_string_decal_with_ttf(
+        &mut self,
+        pos: Vf2d,
+        text: &RichText,
+        col: Pixel,
+        scale: Vf2d,
+        font: &mut Font,
+    ) {
+        let mut needed = text.text();
+        if text.corruption > 0.0 {
+            needed.extend(text.corruption_glyphs.iter());
+        }
+        font.cache_text(&needed, &mut self.renderer);
+        self.draw_rich_string_decal(pos, text, col, scale, Some(font));
+    }
+
     pub fn get_text_size(&self, s: String) -> Vi2d {
-        let (mut size, mut pos) = (Vi2d::new(0, 1), Vi2d::new(0, 1));
-        for c in s.chars() {
-            if c == '\n' {
-                pos.y += 1;
-                pos.x = 0;
-            } else {
-                pos.x += 1
+        self.get_text_size_with_font(&s, None)
+    }
+
+    pub fn get_text_size_with_font(&self, s: &str, font: Option<&Font>) -> Vi2d {
+        let size = font.unwrap_or(&self.default_font).get_text_size(s);
+        Vi2d::new(size.x.ceil() as i32, size.y.ceil() as i32)
+    }
+
+    pub fn get_text_size_prop(&self, s: &str) -> Vi2d {
+        self.get_text_size_with_font(s, Some(&self.prop_font))
+    }
+
+    ///Opens the console, PGE's ConsoleShow. close_key closes it again, and with
+    /// suspend_time the game gets an elapsed time of 0 while it is open.
+    pub fn console_show(&mut self, close_key: Key, suspend_time: bool) {
+        self.console.close_key = close_key;
+        self.console.suspend_time = suspend_time;
+        self.console.shown = true;
+    }
+
+    pub fn is_console_showing(&self) -> bool {
+        self.console.shown
+    }
+
+    pub fn console_clear(&mut self) {
+        self.console.clear();
+    }
+
+    pub fn console_out(&mut self, text: &str, col: Pixel) {
+        self.console.out(text, col);
+    }
+
+    ///Adds a command the console runs when a line starts with name
+    pub fn register_console_command(&mut self, name: &str, usage: &str, func: ConsoleFunc<D>) {
+        self.console.commands.insert(
+            name.to_string(),
+            ConsoleCommand {
+                usage: usage.to_string(),
+                func,
+            },
+        );
+    }
+
+    ///Runs a line as if it was typed into the console. Lines that aren't built in or
+    /// registered go to olc's on_console_command.
+    pub fn run_console_command(&mut self, line: &str, olc: Option<&dyn Olc<D>>) {
+        self.console.out(&format!("> {}", line), Pixel::CYAN);
+        let (name, args) = ConsoleArgs::parse(line);
+        match name.as_str() {
+            "" => {}
+            "clear" => self.console.clear(),
+            "help" => {
+                let mut names: Vec<String> = self
+                    .console
+                    .commands
+                    .iter()
+                    .map(|(name, command)| format!("{} {}", name, command.usage))
+                    .collect();
+                names.sort();
+                names.insert(0, "clear".to_string());
+                names.insert(0, "help".to_string());
+                self.console.out(&names.join("\n"), Pixel::GREY);
+            }
+            _ => {
+                //The commands are taken out while one runs, so it can have the engine
+                let mut commands = std::mem::take(&mut self.console.commands);
+                let result = commands.get(&name).map(|command| {
+                    (command.func)(self, &args)
+                        .map_err(|e| format!("{}\nusage: {} {}", e, name, command.usage))
+                });
+                for (name, command) in self.console.commands.drain() {
+                    commands.insert(name, command);
+                }
+                self.console.commands = commands;
+                match result {
+                    Some(Ok(())) => {}
+                    Some(Err(message)) => self.console.out(&message, Pixel::RED),
+                    None => {
+                        let handled = olc.map(|olc| olc.on_console_command(self, line));
+                        if handled != Some(true) {
+                            self.console
+                                .out(&format!("Unknown command: {}", name), Pixel::RED);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn update_console(&mut self, olc: &dyn Olc<D>) {
+        self.console.drain_log();
+        if !self.console.shown {
+            return;
+        }
+        if self.get_raw_key(self.console.close_key).pressed {
+            self.console.shown = false;
+            //Used up here, so the game doesn't see it on the frame the console closes
+            self.set_key(self.console.close_key, HWButton::new());
+            return;
+        }
+        self.console.input.push_str(&self.get_raw_text_input());
+        if self.get_raw_key(Key::Back).pressed {
+            self.console.input.pop();
+        }
+        if self.get_raw_key(Key::Up).pressed {
+            self.console.browse_history(true);
+        }
+        if self.get_raw_key(Key::Down).pressed {
+            self.console.browse_history(false);
+        }
+        let page = self.console_rows().max(1);
+        let max_scroll = self.console.lines.len().saturating_sub(page);
+        if self.get_raw_key(Key::PageUp).pressed || self.mouse_wheel() > 0 {
+            self.console.scroll = (self.console.scroll + page / 2).min(max_scroll);
+        }
+        if self.get_raw_key(Key::PageDown).pressed || self.mouse_wheel() < 0 {
+            self.console.scroll = self.console.scroll.saturating_sub(page / 2);
+        }
+        if self.get_raw_key(Key::Return).pressed {
+            let line = std::mem::take(&mut self.console.input);
+            self.console.push_history(&line);
+            self.console.scroll = 0;
+            self.run_console_command(&line, Some(olc));
+        }
+    }
+
+    //Scrollback rows that fit above the input line, the console covers the top half
+    fn console_rows(&self) -> usize {
+        ((self.pixels_h as f32 / 2.0 - 12.0) / 10.0).max(0.0) as usize
+    }
+
+    pub(crate) fn draw_console(&mut self) {
+        let layer_id = match self.console.layer_id {
+            Some(id) => id,
+            None if self.console.shown => {
+                let id = self.add_layer_with_info(LayerInfo::Image(Image::default()));
+                self.set_layer_order(id, CONSOLE_LAYER_ORDER);
+                self.console.layer_id = Some(id);
+                id
             }
-            size.x = std::cmp::max(size.x, pos.x);
-            size.y = std::cmp::max(size.y, pos.y);
+            None => return,
+        };
+        self.set_layer_visible(layer_id, self.console.shown);
+        if !self.console.shown {
+            return;
+        }
+        let previous_target = self.draw_target;
+        self.draw_target = layer_id;
+        let (w, h) = (self.pixels_w as f32, (self.pixels_h / 2) as f32);
+        self.fill_rect_decal(Vf2d::new(0.0, 0.0), Vf2d::new(w, h), Pixel::rgba(0, 0, 0, 200));
+        let rows = self.console_rows();
+        let end = self.console.lines.len() - self.console.scroll.min(self.console.lines.len());
+        let start = end.saturating_sub(rows);
+        let lines: Vec<(String, Pixel)> = self.console.lines[start..end].to_vec();
+        let top = h - 12.0 - lines.len() as f32 * 10.0;
+        for (i, (text, col)) in lines.iter().enumerate() {
+            self.draw_string_decal_with_color(Vf2d::new(2.0, top + i as f32 * 10.0), text, *col);
         }
-        Vi2d::new(size.x * 8, size.y * 8)
+        let input = format!("> {}_", self.console.input);
+        self.draw_string_decal_with_color(Vf2d::new(2.0, h - 10.0), &input, Pixel::WHITE);
+        self.draw_target = previous_target;
     }
 }
@@ -0,0 +1,590 @@
+use super::{
+    decal::Decal,
+    olc::Rcode,
+    pixel::Pixel,
+    renderer::Renderer,
+    sprite::Sprite,
+    util::{BMPLoader, ImageLoader, PNGLoader, Vf2d, Vi2d},
+};
+use ab_glyph::{Font as _, FontVec, GlyphId, PxScale, ScaleFont};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+const TAB_SIZE_IN_SPACES: f32 = 4.0;
+//Size of each TrueType atlas page, glyphs bigger than this get a page of their own
+const TRUETYPE_PAGE_SIZE: u32 = 512;
+
+//Where a character sits on its page and how it moves the pen, all in font pixels
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Glyph {
+    pub pos: Vf2d,
+    pub size: Vf2d,
+    pub offset: Vf2d,
+    pub advance: f32,
+    pub page: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct Font {
+    pub pages: Vec<Decal>,
+    pub glyphs: HashMap<char, Glyph>,
+    pub kerning: HashMap<(char, char), f32>,
+    pub line_height: f32,
+    //Drawn in place of characters the font doesn't have
+    pub fallback: Option<char>,
+    //Set for TrueType fonts, which rasterise glyphs into their pages as they are needed
+    pub truetype: Option<TrueType>,
+}
+
+///The outlines of a .ttf/.otf font at one pixel size, plus the atlas its glyphs are
+/// rasterised into. Clones share the atlas, so text cached through any of them is
+/// packed into the same pages.
+#[derive(Clone)]
+pub struct TrueType {
+    pub font: Arc<FontVec>,
+    pub scale: PxScale,
+    atlas: Arc<Mutex<TrueTypeAtlas>>,
+}
+
+//The pages every clone of a TrueType font draws from. The pixels are only kept here,
+// the decals are just the textures.
+#[derive(Default)]
+struct TrueTypeAtlas {
+    pages: Vec<Sprite>,
+    decals: Vec<Decal>,
+    glyphs: HashMap<char, Glyph>,
+    //Shelf packing cursor in the last page: x, y and the height of the current row
+    cursor: (u32, u32, u32),
+}
+
+impl Font {
+    ///The classic 8x8 PGE font, from the sheet built by construct_font_sheet.
+    /// The sheet is 16 glyphs wide and covers ' ' to '~'.
+    pub fn from_font_sheet(decal: Decal) -> Self {
+        let glyphs = (32u8..128)
+            .map(|c| {
+                let i = (c - 32) as f32;
+                let glyph = Glyph {
+                    pos: Vf2d::new((i % 16.0).floor() * 8.0, (i / 16.0).floor() * 8.0),
+                    size: Vf2d::new(8.0, 8.0),
+                    offset: Vf2d::new(0.0, 0.0),
+                    advance: 8.0,
+                    page: 0,
+                };
+                (c as char, glyph)
+            })
+            .collect();
+        Self {
+            pages: vec![decal],
+            glyphs,
+            kerning: HashMap::new(),
+            line_height: 8.0,
+            fallback: Some('?'),
+            truetype: None,
+        }
+    }
+
+    ///Loads a .ttf or .otf font with glyphs pixel_size pixels tall
+    pub fn load_ttf(path: &str, pixel_size: f32, renderer: &mut Renderer) -> Result<Self, Rcode> {
+        let data = std::fs::read(path).map_err(|_| Rcode::NoFile)?;
+        Self::from_ttf(data, pixel_size, renderer)
+    }
+
+    ///Printable ASCII is rasterised straight away, everything else is added by
+    /// cache_text the first time it's drawn
+    pub fn from_ttf(
+        data: Vec<u8>,
+        pixel_size: f32,
+        renderer: &mut Renderer,
+    ) -> Result<Self, Rcode> {
+        let font = FontVec::try_from_vec(data).map_err(|_| Rcode::Fail)?;
+        let scale = PxScale::from(pixel_size);
+        let line_height = {
+            let scaled = font.as_scaled(scale);
+            (scaled.height() + scaled.line_gap()).ceil()
+        };
+        let mut font = Font {
+            line_height,
+            fallback: Some('?'),
+            truetype: Some(TrueType {
+                font: Arc::new(font),
+                scale,
+                atlas: Arc::default(),
+            }),
+            ..Default::default()
+        };
+        let ascii: String = (32u8..127).map(|c| c as char).collect();
+        font.cache_text(&ascii, renderer);
+        Ok(font)
+    }
+
+    ///Rasterises any characters of the text a TrueType font hasn't drawn yet.
+    /// Does nothing for bitmap fonts, characters missing from the font use the fallback.
+    pub fn cache_text(&mut self, text: &str, renderer: &mut Renderer) {
+        let truetype = match &self.truetype {
+            Some(truetype) => truetype,
+            None => return,
+        };
+        let mut atlas = truetype.atlas.lock().expect("TrueType atlas is poisoned");
+        for c in text.chars() {
+            if c.is_control() || self.glyphs.contains_key(&c) {
+                continue;
+            }
+            //Another clone of the font may have rasterised it already
+            let glyph = match atlas.glyphs.get(&c) {
+                Some(glyph) => Some(*glyph),
+                None => truetype.rasterise(c, &mut atlas, renderer),
+            };
+            if let Some(glyph) = glyph {
+                self.glyphs.insert(c, glyph);
+            }
+        }
+        //Picks up pages added through this clone or any other
+        if self.pages.len() != atlas.decals.len() {
+            self.pages = atlas.decals.clone();
+        }
+    }
+
+    ///Loads a text format BMFont (.fnt). Pages are loaded relative to the .fnt file.
+    pub fn load_bmfont(path: &str, renderer: &mut Renderer) -> Result<Self, Rcode> {
+        let fnt = std::fs::read_to_string(path).map_err(|_| Rcode::NoFile)?;
+        let dir = std::path::Path::new(path)
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new(""));
+        let pages = Self::page_files(&fnt)
+            .iter()
+            .map(|file| {
+                let page_path = dir.join(file);
+                let page_path = page_path.to_str().ok_or(Rcode::NoFile)?;
+                if file.to_lowercase().ends_with(".bmp") {
+                    BMPLoader::load_image_resource(page_path)
+                } else {
+                    PNGLoader::load_image_resource(page_path)
+                }
+            })
+            .collect::<Result<Vec<Sprite>, Rcode>>()?;
+        Self::from_bmfont(&fnt, pages, renderer)
+    }
+
+    ///Builds a font from the text of a .fnt file and its already loaded pages,
+    /// for when the files don't come from the filesystem
+    pub fn from_bmfont(
+        fnt: &str,
+        pages: Vec<Sprite>,
+        renderer: &mut Renderer,
+    ) -> Result<Self, Rcode> {
+        let mut font = Font {
+            fallback: Some('?'),
+            ..Default::default()
+        };
+        for line in fnt.lines() {
+            let (tag, values) = parse_bmfont_line(line);
+            let get = |key: &str| -> f32 {
+                values
+                    .get(key)
+                    .and_then(|v| v.parse::<f32>().ok())
+                    .unwrap_or(0.0)
+            };
+            let get_char = |key: &str| std::char::from_u32(get(key) as u32);
+            match tag {
+                "common" => font.line_height = get("lineHeight"),
+                "char" => {
+                    if let Some(c) = get_char("id") {
+                        let glyph = Glyph {
+                            pos: Vf2d::new(get("x"), get("y")),
+                            size: Vf2d::new(get("width"), get("height")),
+                            offset: Vf2d::new(get("xoffset"), get("yoffset")),
+                            advance: get("xadvance"),
+                            page: get("page") as usize,
+                        };
+                        font.glyphs.insert(c, glyph);
+                    }
+                }
+                "kerning" => {
+                    if let (Some(first), Some(second)) = (get_char("first"), get_char("second")) {
+                        font.kerning.insert((first, second), get("amount"));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if font.glyphs.is_empty() {
+            return Err(Rcode::Fail);
+        }
+        font.pages = pages
+            .into_iter()
+            .map(|page| Decal::create(Some(page), renderer))
+            .collect();
+        Ok(font)
+    }
+
+    fn page_files(fnt: &str) -> Vec<String> {
+        let mut files: Vec<(usize, String)> = fnt
+            .lines()
+            .map(parse_bmfont_line)
+            .filter(|(tag, _)| *tag == "page")
+            .filter_map(|(_, values)| {
+                let id = values.get("id")?.parse::<usize>().ok()?;
+                Some((id, values.get("file")?.to_string()))
+            })
+            .collect();
+        files.sort_by_key(|(id, _)| *id);
+        files.into_iter().map(|(_, file)| file).collect()
+    }
+
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs
+            .get(&c)
+            .or_else(|| self.fallback.and_then(|f| self.glyphs.get(&f)))
+    }
+
+    pub fn kerning(&self, first: char, second: char) -> f32 {
+        if let Some(truetype) = &self.truetype {
+            let scaled = truetype.font.as_scaled(truetype.scale);
+            return scaled.kern(scaled.glyph_id(first), scaled.glyph_id(second));
+        }
+        self.kerning.get(&(first, second)).copied().unwrap_or(0.0)
+    }
+
+    //TrueType fonts keep their pixels in the atlas, their decals have none
+    fn with_page_sprite<R>(&self, page: usize, f: impl FnOnce(&Sprite) -> R) -> Option<R> {
+        match &self.truetype {
+            Some(truetype) => {
+                let atlas = truetype.atlas.lock().expect("TrueType atlas is poisoned");
+                atlas.pages.get(page).map(f)
+            }
+            None => self.pages.get(page).map(|page| f(&page.d_inst.sprite)),
+        }
+    }
+
+    ///Where each glyph is drawn, relative to the top left of the text and before scaling
+    pub fn layout(&self, text: &str) -> Vec<(Vf2d, Glyph)> {
+        let mut placed = vec![];
+        self.walk(text, |pos, glyph| placed.push((pos, *glyph)));
+        placed
+    }
+
+    pub fn get_text_size(&self, text: &str) -> Vf2d {
+        self.walk(text, |_, _| {})
+    }
+
+    //Moves the pen over the text, handing every glyph to `place`, and returns how
+    // much room the text took up
+    fn walk(&self, text: &str, mut place: impl FnMut(Vf2d, &Glyph)) -> Vf2d {
+        let mut size = Vf2d::new(0.0, self.line_height);
+        let mut pen = Vf2d::new(0.0, 0.0);
+        let mut prev = None;
+        for c in text.chars() {
+            match c {
+                '\n' => {
+                    pen.x = 0.0;
+                    pen.y += self.line_height;
+                    prev = None;
+                }
+                '\t' => {
+                    let stop = self.tab_width();
+                    if stop > 0.0 {
+                        pen.x = ((pen.x / stop).floor() + 1.0) * stop;
+                    }
+                    prev = None;
+                }
+                _ => {
+                    if let Some(glyph) = self.glyph(c) {
+                        if let Some(p) = prev {
+                            pen.x += self.kerning(p, c);
+                        }
+                        place(pen + glyph.offset, glyph);
+                        pen.x += glyph.advance;
+                        prev = Some(c);
+                    }
+                }
+            }
+            size.x = size.x.max(pen.x);
+            size.y = size.y.max(pen.y + self.line_height);
+        }
+        size
+    }
+
+    //Tabs jump to the next multiple of this many spaces
+    pub fn tab_width(&self) -> f32 {
+        let space = self
+            .glyphs
+            .get(&' ')
+            .map(|g| g.advance)
+            .unwrap_or(self.line_height / 2.0);
+        space * TAB_SIZE_IN_SPACES
+    }
+
+    ///A copy of the font with every glyph trimmed to the columns it actually uses,
+    /// like PGE's DrawStringProp. Needs the pages' sprites, blank glyphs keep 3/8 of
+    /// their advance so spaces don't disappear.
+    pub fn proportional(&self) -> Font {
+        let mut font = self.clone();
+        for glyph in font.glyphs.values_mut() {
+            let (x, y) = (glyph.pos.x as u32, glyph.pos.y as u32);
+            let (w, h) = (glyph.size.x as u32, glyph.size.y as u32);
+            let inked: Vec<u32> = match self.with_page_sprite(glyph.page, |page| {
+                (0..w)
+                    .filter(|col| (0..h).any(|row| page.get_pixel(x + col, y + row).a() > 0))
+                    .collect()
+            }) {
+                Some(inked) => inked,
+                None => continue,
+            };
+            match (inked.first(), inked.last()) {
+                (Some(first), Some(last)) => {
+                    glyph.pos.x += *first as f32;
+                    glyph.size.x = (last - first + 1) as f32;
+                    glyph.offset.x = 0.0;
+                    glyph.advance = glyph.size.x + 1.0;
+                }
+                _ => glyph.advance = (glyph.advance * 3.0 / 8.0).ceil(),
+            }
+        }
+        font
+    }
+
+    ///Inserts line breaks so no line is wider than max_width. Lines break between
+    /// words, words that don't fit on a line of their own are split between characters.
+    pub fn wrap(&self, text: &str, max_width: f32) -> String {
+        let fits = |line: &str| self.get_text_size(line).x <= max_width;
+        let mut lines: Vec<String> = vec![];
+        for paragraph in text.split('\n') {
+            let mut line = String::new();
+            for word in paragraph.split(' ') {
+                let joined = if line.is_empty() {
+                    word.to_string()
+                } else {
+                    format!("{} {}", line, word)
+                };
+                if fits(&joined) {
+                    line = joined;
+                    continue;
+                }
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                for c in word.chars() {
+                    line.push(c);
+                    if !fits(&line) && line.chars().count() > 1 {
+                        line.pop();
+                        lines.push(std::mem::replace(&mut line, c.to_string()));
+                    }
+                }
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+
+    ///Lays the text out inside a box of the given size, each line aligned on its own.
+    /// Text that doesn't fit still overflows the box, wrap only helps with the width.
+    pub fn layout_in_rect(
+        &self,
+        text: &str,
+        size: Vf2d,
+        h_align: HorizontalAlign,
+        v_align: VerticalAlign,
+        wrap: bool,
+    ) -> Vec<(Vf2d, Glyph)> {
+        let text = if wrap {
+            self.wrap(text, size.x)
+        } else {
+            text.to_string()
+        };
+        let lines: Vec<&str> = text.split('\n').collect();
+        let height = lines.len() as f32 * self.line_height;
+        let top = match v_align {
+            VerticalAlign::Top => 0.0,
+            VerticalAlign::Middle => ((size.y - height) / 2.0).floor(),
+            VerticalAlign::Bottom => size.y - height,
+        };
+        let mut placed = vec![];
+        for (i, line) in lines.iter().enumerate() {
+            let width = self.get_text_size(line).x;
+            let left = match h_align {
+                HorizontalAlign::Left => 0.0,
+                HorizontalAlign::Center => ((size.x - width) / 2.0).floor(),
+                HorizontalAlign::Right => size.x - width,
+            };
+            let origin = Vf2d::new(left, top + i as f32 * self.line_height);
+            placed.extend(
+                self.layout(line)
+                    .into_iter()
+                    .map(|(pos, glyph)| (origin + pos, glyph)),
+            );
+        }
+        placed
+    }
+
+    ///Every inked pixel of the placed glyphs, for drawing text on the CPU
+    pub fn pixels(&self, placed: &[(Vf2d, Glyph)]) -> Vec<Vi2d> {
+        placed
+            .iter()
+            .flat_map(|(pos, glyph)| {
+                self.glyph_pixels(glyph)
+                    .into_iter()
+                    .map(move |p| Vi2d::new(pos.x as i32 + p.x, pos.y as i32 + p.y))
+            })
+            .collect()
+    }
+
+    ///The inked pixels of one glyph, relative to its top left
+    pub fn glyph_pixels(&self, glyph: &Glyph) -> Vec<Vi2d> {
+        let (x, y) = (glyph.pos.x as u32, glyph.pos.y as u32);
+        self.with_page_sprite(glyph.page, |page| {
+            let mut pixels = vec![];
+            for row in 0..glyph.size.y as u32 {
+                for col in 0..glyph.size.x as u32 {
+                    //Anti-aliased edges only count once they are at least half covered
+                    if page.get_pixel(x + col, y + row).a() >= 128 {
+                        pixels.push(Vi2d::new(col as i32, row as i32));
+                    }
+                }
+            }
+            pixels
+        })
+        .unwrap_or_default()
+    }
+}
+
+impl TrueType {
+    fn rasterise(
+        &self,
+        c: char,
+        atlas: &mut TrueTypeAtlas,
+        renderer: &mut Renderer,
+    ) -> Option<Glyph> {
+        let scaled = self.font.as_scaled(self.scale);
+        let id = scaled.glyph_id(c);
+        if id == GlyphId(0) {
+            return None;
+        }
+        let advance = scaled.h_advance(id);
+        //Glyphs are placed with the pen at the top of the line, so bounds are relative to it
+        let glyph = id.with_scale_and_position(self.scale, ab_glyph::point(0.0, scaled.ascent()));
+        let outline = match scaled.outline_glyph(glyph) {
+            Some(outline) => outline,
+            //Spaces and the like have nothing to draw
+            None => {
+                let glyph = Glyph {
+                    advance,
+                    page: atlas.pages.len().saturating_sub(1),
+                    ..Default::default()
+                };
+                atlas.glyphs.insert(c, glyph);
+                return Some(glyph);
+            }
+        };
+        let bounds = outline.px_bounds();
+        let (w, h) = (bounds.width() as u32, bounds.height() as u32);
+        let (page, x, y) = atlas.allocate(w, h, renderer);
+        let mut region = vec![Pixel::BLANK; (w * h) as usize];
+        outline.draw(|gx, gy, coverage| {
+            if gx < w && gy < h {
+                let a = (coverage.min(1.0) * 255.0) as u8;
+                region[(gy * w + gx) as usize] = Pixel::rgba(255, 255, 255, a);
+            }
+        });
+        for gy in 0..h {
+            for gx in 0..w {
+                atlas.pages[page].set_pixel(x + gx, y + gy, region[(gy * w + gx) as usize]);
+            }
+        }
+        renderer.update_texture_region(atlas.decals[page].d_inst.id as u32, x, y, w, h, &region);
+        let glyph = Glyph {
+            pos: Vf2d::new(x as f32, y as f32),
+            size: Vf2d::new(w as f32, h as f32),
+            offset: Vf2d::new(bounds.min.x, bounds.min.y),
+            advance,
+            page,
+        };
+        atlas.glyphs.insert(c, glyph);
+        Some(glyph)
+    }
+}
+
+impl TrueTypeAtlas {
+    //Finds room for a w x h glyph, starting a new page when the last one is full.
+    // Glyphs are kept a pixel apart so filtering doesn't bleed between them.
+    fn allocate(&mut self, w: u32, h: u32, renderer: &mut Renderer) -> (usize, u32, u32) {
+        let (mut x, mut y, mut row_height) = self.cursor;
+        if let Some(page) = self.pages.last() {
+            if x + w > page.width {
+                x = 0;
+                y += row_height + 1;
+                row_height = 0;
+            }
+        }
+        let full = match self.pages.last() {
+            Some(page) => y + h > page.height,
+            None => true,
+        };
+        if full {
+            let size = TRUETYPE_PAGE_SIZE.max(w).max(h);
+            self.pages.push(Sprite::new(size, size));
+            self.decals.push(Decal::blank(size, size, renderer));
+            x = 0;
+            y = 0;
+            row_height = 0;
+        }
+        self.cursor = (x + w + 1, y, row_height.max(h));
+        (self.pages.len() - 1, x, y)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HorizontalAlign {
+    Left,
+    Center,
+    Right,
+}
+
+impl Default for HorizontalAlign {
+    fn default() -> Self {
+        Self::Left
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerticalAlign {
+    Top,
+    Middle,
+    Bottom,
+}
+
+impl Default for VerticalAlign {
+    fn default() -> Self {
+        Self::Top
+    }
+}
+
+//Splits `tag key=value key="quoted value"` into the tag and its values
+fn parse_bmfont_line(line: &str) -> (&str, HashMap<&str, &str>) {
+    let line = line.trim();
+    let (tag, mut rest) = match line.find(' ') {
+        Some(i) => (&line[..i], line[i..].trim_start()),
+        None => (line, ""),
+    };
+    let mut values = HashMap::new();
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let after = &rest[eq + 1..];
+        let (value, next) = if let Some(quoted) = after.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match after.find(' ') {
+                Some(end) => (&after[..end], &after[end..]),
+                None => (after, ""),
+            }
+        };
+        values.insert(key, value);
+        rest = next.trim_start();
+    }
+    (tag, values)
+}
@@ -2,9 +2,11 @@ use super::{
     olc::Olc,
     olc::OlcData,
     camera::Camera,
-    decal::{Decal},
+    console::{Console, ConsoleLogger},
+    decal::{Decal, DecalMode},
+    font::Font,
     engine::{OLCEngine},
-    layer::{LayerDesc, LayerType, LayerFunc, LayerInfo},
+    layer::{LayerDesc, LayerType, LayerFunc, LayerInfo, LayerUniform},
     platform::{PLATFORM_DATA, Platform, PlatformWindows},
     renderer::Renderer,
     util::{HWButton,  Vf2d, Vi2d, RoundTo},
@@ -35,8 +37,8 @@ pub fn construct<T: 'static + Olc<D>, D: 'static + OlcData>(
     //Set the olc object to be used in this crate
     #[cfg(target_arch = "wasm32")]
     console_error_panic_hook::set_once();
-    #[cfg(target_arch = "wasm32")]
-    console_log::init_with_level(log::Level::Warn);
+    //Fails if the game already installed a logger of its own, which is then kept
+    let _ = ConsoleLogger::init(log::LevelFilter::Info);
 
     unsafe {
         PLATFORM_DATA.init();
@@ -131,6 +133,11 @@ async fn finish_setup<T: 'static + Olc<D>, D: 'static + OlcData>(
         draw_target: 0,
         mouse_position: Vi2d::from((0, 0)),
         font_decal: Decal::empty(),
+        default_font: Font::default(),
+        prop_font: Font::default(),
+        decal_mode: DecalMode::Normal,
+        console: Console::new(),
+        gui_layer: None,
         depth_buffer: vec![],
         camera: Camera::default(),
         window,
@@ -167,6 +174,7 @@ async fn start_game<T: 'static + Olc<D>, D: OlcData + 'static>(
     }
     engine.construct_font_sheet();
     engine.renderer.setup_layer_pipeline();
+    engine.renderer.setup_decal_pipeline();
     engine.renderer.setup_3D_pipeline();
     //Create Primary Layer "0"
     let base_layer_id = engine.add_layer(LayerType::Image);
@@ -206,14 +214,28 @@ async fn start_game<T: 'static + Olc<D>, D: OlcData + 'static>(
                 engine.window.request_redraw();
             }
             Event::RedrawRequested(_) => {
-                engine.renderer.active_decals = vec![];
+                engine.renderer.free_deleted_textures();
+                let mut active_layers = vec![];
+                let mut render_targets = vec![];
                 for layer in engine.layers.iter_mut() {
-                    if let LayerInfo::Image(image_info) = &mut layer.layer_info {
+                    //Decals are redrawn every frame, even hidden layers shouldn't pile them up
+                    let decals = std::mem::take(&mut layer.vec_decal_instance);
+                    if let LayerInfo::Target(target) = &layer.layer_info {
+                        //A target's decals go into its texture, not on top of it
+                        let scale = Vf2d::new(
+                            engine.pixels_w as f32 / target.width as f32,
+                            engine.pixels_h as f32 / target.height as f32,
+                        );
+                        render_targets.push((layer.id, scale, decals));
+                        if layer.shown {
+                            active_layers.push((layer.z_index, layer.id, target.blend, vec![]));
+                        }
+                    } else if let LayerInfo::Image(image_info) = &mut layer.layer_info {
                         if layer.shown {
+                            active_layers.push((layer.z_index, layer.id, image_info.blend, decals));
                             engine
                                 .renderer
-                                .active_decals
-                                .insert(layer.id as usize, layer.id);
+                                .update_layer_uniform(layer.id, LayerUniform::from(&*image_info));
                         }
                         if image_info.update {
                             engine
@@ -223,6 +245,15 @@ async fn start_game<T: 'static + Olc<D>, D: OlcData + 'static>(
                         }
                     }
                 }
+                //sort_by_key is stable, so equal z_index layers stay in creation order
+                active_layers.sort_by_key(|(z_index, _, _, _)| *z_index);
+                engine.renderer.set_active_layers(
+                    active_layers
+                        .into_iter()
+                        .map(|(_, id, blend, decals)| (id, blend, decals))
+                        .collect(),
+                );
+                engine.renderer.set_render_targets(render_targets);
 
                 let mut encoder = engine.renderer.device.create_command_encoder(
                     &wgpu::CommandEncoderDescriptor {
@@ -363,11 +394,18 @@ async fn start_game<T: 'static + Olc<D>, D: OlcData + 'static>(
         //Only run the engine if the last frame was drawn
         if frame_processed{
             engine.renderer.new_frame();
-            if let Err(message) = olc.on_engine_update(&mut engine, elapsed_time) {
+            engine.update_console(&olc);
+            let update_time = if engine.console.shown && engine.console.suspend_time {
+                0.0
+            } else {
+                elapsed_time
+            };
+            if let Err(message) = olc.on_engine_update(&mut engine, update_time) {
                 log::error!("{}", message);
                 println!("{}", message);
                 *control_flow = ControlFlow::Exit;
             }
+            engine.draw_console();
             engine.window.request_redraw();
             frame_processed = false;
         }
@@ -450,5 +488,6 @@ fn update_inputs<D: OlcData>(engine: &mut OLCEngine<D>){
         }
         PLATFORM_DATA.mouse_wheel_delta = PLATFORM_DATA.mouse_wheel_delta_cache;
         PLATFORM_DATA.mouse_wheel_delta_cache = 0;
+        PLATFORM_DATA.text_input = std::mem::take(&mut PLATFORM_DATA.text_input_cache);
     }
 }
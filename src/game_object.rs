@@ -2,34 +2,49 @@
 use super::{
     camera::Camera,
     engine::OLCEngine,
-    geometry::{Mesh, MeshType, Triangle, Vertex},
+    geometry::{Aabb, BoundingSphere, Mesh, MeshType, Ray, RayHit, Triangle, Vertex},
     layer::{LayerMask, Mask},
+    light::Light,
     math_3d::{Rotor3, Vector3},
     sprite::Sprite,
     transform::Transform3,
 };
+use cgmath::{Matrix, SquareMatrix};
 use std::collections::HashMap;
 
 pub struct GameObject {
+    pub name: String,
+    //Relative to the parent, see set_transform
     pub transform: Transform3,
     pub sprite: Option<Sprite>,
     pub meshes: Vec<Mesh>,
     pub children: Vec<GameObject>,
+    //Shines from this object's world position, see Light
+    pub light: Option<Light>,
     pub active: bool,
     pub uid: i32,
     pub layer_mask: Mask,
+    //Cached by update_transforms, along with the local transform it was built from
+    world: cgmath::Matrix4<f32>,
+    world_from: Option<Transform3>,
+    dirty: bool,
 }
 
 impl Clone for GameObject {
     fn clone(&self) -> Self {
         Self {
+            name: self.name.clone(),
             transform: self.transform,
             sprite: self.sprite.clone(),
             meshes: self.meshes.clone(),
             children: self.children.clone(),
+            light: self.light,
             active: self.active,
             uid: -1,
             layer_mask: self.layer_mask,
+            world: self.world,
+            world_from: self.world_from,
+            dirty: self.dirty,
         }
     }
 }
@@ -42,13 +57,18 @@ pub struct GameObjectCollection {
 impl Default for GameObject {
     fn default() -> Self {
         Self {
+            name: String::new(),
             transform: Transform3::default(),
             sprite: None,
             meshes: vec![],
             active: false,
             children: vec![],
+            light: None,
             layer_mask: Mask::D3,
             uid: -1,
+            world: cgmath::Matrix4::identity(),
+            world_from: None,
+            dirty: true,
         }
     }
 }
@@ -72,11 +92,200 @@ impl GameObjectCollection {
     pub fn contains(&self, index: i32) -> bool {
         self.game_objects.contains_key(&index)
     }
+
+    ///Moves a top level object under another one, keeping where it is in the world.
+    /// Returns false if either isn't in the collection or they are the same object.
+    pub fn set_parent(&mut self, child: i32, parent: i32) -> bool {
+        if child == parent || !self.contains(parent) {
+            return false;
+        }
+        match self.game_objects.remove(&child) {
+            Some(obj) => {
+                let parent = self.game_objects.get_mut(&parent).unwrap();
+                parent.update_transforms();
+                parent.add_child(obj, true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    ///Takes the first descendant of parent called name out of its tree and adds it back
+    /// as a top level object where it was in the world. Returns its new uid.
+    pub fn detach(&mut self, parent: i32, name: &str) -> Option<i32> {
+        let obj = self.game_objects.get_mut(&parent)?.detach(name)?;
+        self.add(obj);
+        Some(self.current_index)
+    }
+
+    ///Brings the cached world transforms of every object up to date
+    pub fn update_transforms(&mut self) {
+        for obj in self.game_objects.values_mut() {
+            obj.update_transforms();
+        }
+    }
 }
 
 impl GameObject {
+    pub fn set_transform(&mut self, transform: Transform3) {
+        self.transform = transform;
+        self.dirty = true;
+    }
+
+    ///Where this object is in the world as of the last update_transforms on its root
+    pub fn world_transform(&self) -> cgmath::Matrix4<f32> {
+        self.world
+    }
+
+    ///Recomputes the world matrices of this object and everything under it, treating
+    /// this object as a root. Only branches whose transforms changed are rebuilt,
+    /// including changes made straight to the transform field.
+    pub fn update_transforms(&mut self) {
+        self.propagate(cgmath::Matrix4::identity(), false);
+    }
+
+    fn propagate(&mut self, parent: cgmath::Matrix4<f32>, parent_changed: bool) {
+        let changed = parent_changed || self.dirty || self.world_from != Some(self.transform);
+        if changed {
+            self.world = parent * self.transform.to_model_matrix();
+            self.world_from = Some(self.transform);
+            self.dirty = false;
+        }
+        let world = self.world;
+        for child in self.children.iter_mut() {
+            child.propagate(world, changed);
+        }
+    }
+
+    ///Adds a child. With keep_world the child stays where it is in the world, which needs
+    /// this object's world transform to be up to date, otherwise its transform is taken
+    /// as already relative to this object.
+    pub fn add_child(&mut self, mut child: GameObject, keep_world: bool) -> &mut GameObject {
+        if keep_world {
+            child.update_transforms();
+            if let Some(inverse) = self.world.invert() {
+                child.set_transform(Transform3::from_model_matrix(inverse * child.world));
+            }
+        }
+        child.dirty = true;
+        self.children.push(child);
+        self.children.last_mut().unwrap()
+    }
+
+    ///Removes the first descendant called name, giving it a transform that keeps it
+    /// where it was in the world
+    pub fn detach(&mut self, name: &str) -> Option<GameObject> {
+        self.update_transforms();
+        self.detach_from(name)
+    }
+
+    fn detach_from(&mut self, name: &str) -> Option<GameObject> {
+        if let Some(i) = self.children.iter().position(|c| c.name == name) {
+            let mut child = self.children.remove(i);
+            child.set_transform(Transform3::from_model_matrix(child.world));
+            return Some(child);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child| child.detach_from(name))
+    }
+
+    ///Gives this object and everything under it a uid, counting up from next.
+    /// Objects that already have one keep it, and next is moved past it.
+    pub fn assign_uids(&mut self, next: &mut i32) {
+        if self.uid < 0 {
+            self.uid = *next;
+        }
+        *next = (*next).max(self.uid.saturating_add(1));
+        for child in self.children.iter_mut() {
+            child.assign_uids(next);
+        }
+    }
+
+    ///The first descendant called name, searching depth first
+    pub fn find_child(&self, name: &str) -> Option<&GameObject> {
+        self.children.iter().find_map(|child| {
+            if child.name == name {
+                Some(child)
+            } else {
+                child.find_child(name)
+            }
+        })
+    }
+
+    pub fn find_child_mut(&mut self, name: &str) -> Option<&mut GameObject> {
+        for child in self.children.iter_mut() {
+            if child.name == name {
+                return Some(child);
+            }
+            if let Some(found) = child.find_child_mut(name) {
+                return Some(found);
+            }
+        }
+        None
+    }
+
     pub fn get_vertices_and_indices(
         &self,
+        vert_count: u32,
+        index_count: u32,
+    ) -> (Vec<Vertex>, Vec<u32>, u32, u32) {
+        self.world_vertices_and_indices(cgmath::Matrix4::identity(), vert_count, index_count)
+    }
+
+    //Worlds are composed on the way down rather than read from the cache, so objects
+    // that were never updated still draw in the right place
+    fn world_vertices_and_indices(
+        &self,
+        parent: cgmath::Matrix4<f32>,
+        mut vert_count: u32,
+        mut index_count: u32,
+    ) -> (Vec<Vertex>, Vec<u32>, u32, u32) {
+        let world = parent * self.transform.to_model_matrix();
+        //Normals need the inverse transpose so non uniform scale doesn't skew them
+        let normal_matrix = world.invert().unwrap_or(world).transpose();
+        let to_world = |v: &Vertex| {
+            let p = world * cgmath::Vector4::new(v.position.x, v.position.y, v.position.z, 1.0);
+            let n = normal_matrix * cgmath::Vector4::new(v.normal.x, v.normal.y, v.normal.z, 0.0);
+            let normal = Vector3::new(n.x, n.y, n.z);
+            Vertex {
+                position: Vector3::new(p.x, p.y, p.z),
+                normal: if normal.length_sqrd() > 0.0 {
+                    normal.normal()
+                } else {
+                    normal
+                },
+                tex_coords: v.tex_coords,
+                color: v.color,
+            }
+        };
+        let (mut vert_slice, mut ind_slice, mut vert_count, mut index_count) =
+            self.mesh_vertices_and_indices(to_world, vert_count, index_count);
+
+        for child in &self.children {
+            let (verts, i, vc, ic) =
+                child.world_vertices_and_indices(world, vert_count, index_count);
+            vert_count = vc;
+            index_count = ic;
+            ind_slice.extend(i);
+            vert_slice.extend(verts);
+        }
+
+        (vert_slice, ind_slice, vert_count, index_count)
+    }
+
+    ///This object's own meshes in its local space, leaving the transform to the GPU
+    pub fn get_local_vertices_and_indices(
+        &self,
+        vert_count: u32,
+        index_count: u32,
+    ) -> (Vec<Vertex>, Vec<u32>, u32, u32) {
+        self.mesh_vertices_and_indices(|v| *v, vert_count, index_count)
+    }
+
+    fn mesh_vertices_and_indices(
+        &self,
+        to_vertex: impl Fn(&Vertex) -> Vertex,
         mut vert_count: u32,
         mut index_count: u32,
     ) -> (Vec<Vertex>, Vec<u32>, u32, u32) {
@@ -92,19 +301,7 @@ impl GameObject {
                             .map(|i| i + vert_count)
                             .collect::<Vec<_>>(),
                     );
-                    vert_slice.extend(
-                        verts
-                            .iter()
-                            .map(|v| Vertex {
-                                position: ((v.position * self.transform.scale)
-                                    * self.transform.rot)
-                                    + self.transform.pos,
-                                normal: v.normal,
-                                tex_coords: v.tex_coords,
-                                color: v.color,
-                            })
-                            .collect::<Vec<_>>(),
-                    );
+                    vert_slice.extend(verts.iter().map(&to_vertex));
                     index_count += indices.len() as u32;
                     vert_count += verts.len() as u32;
                 }
@@ -114,14 +311,7 @@ impl GameObject {
                         .flat_map(|t| {
                             t.as_vertex_vec()
                                 .iter()
-                                .map(|v| Vertex {
-                                    position: ((v.position * self.transform.scale)
-                                        * self.transform.rot)
-                                        + self.transform.pos,
-                                    normal: v.normal,
-                                    tex_coords: v.tex_coords,
-                                    color: v.color,
-                                })
+                                .map(&to_vertex)
                                 .collect::<Vec<Vertex>>()
                         })
                         .collect();
@@ -138,28 +328,99 @@ impl GameObject {
             };
         }
 
-        for child in &self.children {
-            let (mut verts, mut i, vc, ic) =
-                child.get_vertices_and_indices(vert_count, index_count);
-            vert_count = vc;
-            index_count = ic;
-            ind_slice.extend(i);
-            //vert_slice.extend(verts);
-            vert_slice.extend::<Vec<Vertex>>(
-                verts
-                    .iter()
-                    .map(|v| Vertex {
-                        position: ((v.position * self.transform.scale) * self.transform.rot)
-                            + self.transform.pos,
-                        normal: v.normal,
-                        tex_coords: v.tex_coords,
-                        color: v.color,
-                    })
-                    .collect(),
-            );
+        (vert_slice, ind_slice, vert_count, index_count)
+    }
+
+    ///The box around this object's own meshes, in its local space
+    pub fn mesh_bounds(&self) -> Option<Aabb> {
+        self.meshes
+            .iter()
+            .filter_map(Mesh::bounds)
+            .fold(None, |acc: Option<Aabb>, b| {
+                Some(acc.map_or(b, |acc| acc.union(&b)))
+            })
+    }
+
+    ///The box around every mesh in the hierarchy, with this object as the root like flatten
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.flatten()
+            .iter()
+            .filter_map(|(go, world)| go.mesh_bounds().map(|b| b.transformed(world)))
+            .fold(None, |acc: Option<Aabb>, b| {
+                Some(acc.map_or(b, |acc| acc.union(&b)))
+            })
+    }
+
+    pub fn bounding_sphere(&self) -> Option<BoundingSphere> {
+        self.bounds().map(|b| b.bounding_sphere())
+    }
+
+    ///The nearest mesh in the hierarchy a world space ray hits, with the uid of the object
+    /// the mesh belongs to. This object is the root like in flatten.
+    pub fn raycast(&self, ray: &Ray) -> Option<(i32, RayHit)> {
+        let mut best: Option<(i32, RayHit)> = None;
+        let mut nearest = f32::INFINITY;
+        for (go, world) in self.flatten() {
+            let bounds = match go.mesh_bounds() {
+                Some(b) => b.transformed(&world),
+                None => continue,
+            };
+            match ray.intersect_aabb(&bounds) {
+                Some(t) if t < nearest => {}
+                _ => continue,
+            }
+            let inverse = match world.invert() {
+                Some(m) => m,
+                None => continue,
+            };
+            let local = ray.transformed(&inverse);
+            for mesh in &go.meshes {
+                let hit = match mesh.raycast(&local) {
+                    Some(hit) if hit.distance < nearest => hit,
+                    _ => continue,
+                };
+                //Normals go back to world space with the inverse transpose
+                let n = inverse.transpose()
+                    * cgmath::Vector4::new(hit.normal.x, hit.normal.y, hit.normal.z, 0.0);
+                nearest = hit.distance;
+                best = Some((
+                    go.uid,
+                    RayHit {
+                        distance: hit.distance,
+                        point: ray.at(hit.distance),
+                        normal: Vector3::new(n.x, n.y, n.z).normal(),
+                    },
+                ));
+            }
         }
+        best
+    }
 
-        (vert_slice, ind_slice, vert_count, index_count)
+    ///This object and everything under it, depth first, each with its world matrix.
+    /// The matrices cached by update_transforms are used where they are still current,
+    /// only changed branches are composed again, with this object as their root.
+    pub fn flatten(&self) -> Vec<(&GameObject, cgmath::Matrix4<f32>)> {
+        let mut out = vec![];
+        self.flatten_into(cgmath::Matrix4::identity(), false, &mut out);
+        out
+    }
+
+    fn flatten_into<'a>(
+        &'a self,
+        parent: cgmath::Matrix4<f32>,
+        parent_changed: bool,
+        out: &mut Vec<(&'a GameObject, cgmath::Matrix4<f32>)>,
+    ) {
+        let changed = parent_changed || self.dirty || self.world_from != Some(self.transform);
+        let world = if changed {
+            parent * self.transform.to_model_matrix()
+        } else {
+            self.world
+        };
+        out.push((self, world));
+        for child in &self.children {
+            child.flatten_into(world, changed, out);
+        }
     }
 
     pub fn get_indices(&self) -> Vec<u32> {
@@ -394,9 +655,7 @@ impl GameObject {
             sprite,
             meshes,
             active: true,
-            children: vec![],
-            layer_mask: Mask::D3,
-            uid: -1,
+            ..Default::default()
         }
     }
 
@@ -414,10 +673,7 @@ impl GameObject {
             },
             sprite,
             meshes,
-            layer_mask: Mask::D3,
-            active: false,
-            children: vec![],
-            uid: -1,
+            ..Default::default()
         }
     }
 }
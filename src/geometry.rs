@@ -1,4 +1,5 @@
-use super::{math_3d::*, pixel::Pixel, texture::Texture, transform::*};
+use super::{material::Material, math_3d::*, pixel::Pixel, texture::Texture, transform::*};
+use std::sync::Arc;
 
 pub enum NormalMode {
     Flat,
@@ -43,11 +44,188 @@ pub struct Plane {
     pub n: Vector3,
 }
 
+///An axis aligned box, in whatever space the points it was built from were in
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vector3,
+    pub radius: f32,
+}
+
+impl Aabb {
+    ///None when there are no points
+    pub fn from_points(points: impl IntoIterator<Item = Vector3>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |b, p| b.union(&Self { min: p, max: p }),
+        ))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: Vector3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Vector3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    pub fn center(&self) -> Vector3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn corners(&self) -> [Vector3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vector3::new(a.x, a.y, a.z),
+            Vector3::new(b.x, a.y, a.z),
+            Vector3::new(a.x, b.y, a.z),
+            Vector3::new(b.x, b.y, a.z),
+            Vector3::new(a.x, a.y, b.z),
+            Vector3::new(b.x, a.y, b.z),
+            Vector3::new(a.x, b.y, b.z),
+            Vector3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    ///The box around this one after a transform, e.g. from local to world space.
+    /// Rotated boxes grow to stay axis aligned.
+    pub fn transformed(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        let corners = self.corners();
+        Self::from_points(corners.iter().map(|c| {
+            let p = matrix * cgmath::Vector4::new(c.x, c.y, c.z, 1.0);
+            Vector3::new(p.x, p.y, p.z)
+        }))
+        .unwrap()
+    }
+
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        BoundingSphere {
+            center: self.center(),
+            radius: (self.max - self.min).length() * 0.5,
+        }
+    }
+}
+
+///A half line from origin along dir, which is normalized for world space rays so
+/// distances along it are in world units
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Ray {
+    pub origin: Vector3,
+    pub dir: Vector3,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RayHit {
+    //Along the ray, in multiples of its dir
+    pub distance: f32,
+    pub point: Vector3,
+    //Of the face that was hit, turned to face back along the ray
+    pub normal: Vector3,
+}
+
+impl Ray {
+    pub fn at(&self, t: f32) -> Vector3 {
+        self.origin + self.dir * t
+    }
+
+    ///The same ray in another space. Dir isn't normalized again, so distances along
+    /// the transformed ray still match the original.
+    pub fn transformed(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        let o = matrix * cgmath::Vector4::new(self.origin.x, self.origin.y, self.origin.z, 1.0);
+        let d = matrix * cgmath::Vector4::new(self.dir.x, self.dir.y, self.dir.z, 0.0);
+        Self {
+            origin: Vector3::new(o.x, o.y, o.z),
+            dir: Vector3::new(d.x, d.y, d.z),
+        }
+    }
+
+    ///Where the ray enters the box, 0 if it starts inside
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let (mut near, mut far) = (0.0_f32, f32::INFINITY);
+        let axes = [
+            (self.origin.x, self.dir.x, aabb.min.x, aabb.max.x),
+            (self.origin.y, self.dir.y, aabb.min.y, aabb.max.y),
+            (self.origin.z, self.dir.z, aabb.min.z, aabb.max.z),
+        ];
+        for (o, d, min, max) in axes.iter().copied() {
+            if d == 0.0 {
+                //Parallel to these faces, so it's either always between them or never
+                if o < min || o > max {
+                    return None;
+                }
+                continue;
+            }
+            let (a, b) = ((min - o) / d, (max - o) / d);
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+
+    ///Möller-Trumbore, hitting either side of the triangle
+    pub fn intersect_triangle(&self, a: Vector3, b: Vector3, c: Vector3) -> Option<RayHit> {
+        let (e1, e2) = (b - a, c - a);
+        let p = self.dir.cross(e2);
+        let det = e1.dot(p);
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+        let inv = 1.0 / det;
+        let s = self.origin - a;
+        let u = s.dot(p) * inv;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(e1);
+        let v = self.dir.dot(q) * inv;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = e2.dot(q) * inv;
+        if t < 0.0 {
+            return None;
+        }
+        let normal = e1.cross(e2).normal();
+        Some(RayHit {
+            distance: t,
+            point: self.at(t),
+            normal: if normal.dot(self.dir) > 0.0 {
+                normal * -1.0
+            } else {
+                normal
+            },
+        })
+    }
+}
+
 pub struct Mesh {
     pub mesh_type: MeshType,
     pub buffer_indices: Vec<u32>,
     pub buffer_offset: u32,
     pub texture: Option<Texture>,
+    //Shared between meshes using the same material, which then share a bind group.
+    //Without one the texture is drawn with an otherwise default material
+    pub material: Option<Arc<Material>>,
 }
 
 #[repr(C)]
@@ -69,6 +247,7 @@ impl Clone for Mesh {
             buffer_indices: self.buffer_indices.clone(),
             buffer_offset: self.buffer_offset,
             texture,
+            material: self.material.clone(),
         }
     }
 }
@@ -131,6 +310,52 @@ impl Vertex {
     }
 }
 
+///Per object data for the 3D pipeline, bound as a second vertex buffer stepped per
+/// instance. Normals use the inverse transpose so non uniform scale doesn't skew them.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ObjectTransform {
+    pub model: [[f32; 4]; 4],
+    pub normal: [[f32; 4]; 4],
+}
+
+impl ObjectTransform {
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        const ATTRIBUTES: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array![
+            4 => Float32x4,
+            5 => Float32x4,
+            6 => Float32x4,
+            7 => Float32x4,
+            8 => Float32x4,
+            9 => Float32x4,
+            10 => Float32x4,
+            11 => Float32x4,
+        ];
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<ObjectTransform>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+impl From<cgmath::Matrix4<f32>> for ObjectTransform {
+    fn from(model: cgmath::Matrix4<f32>) -> Self {
+        use cgmath::{Matrix, SquareMatrix};
+        Self {
+            model: model.into(),
+            normal: model.invert().unwrap_or(model).transpose().into(),
+        }
+    }
+}
+
+impl Default for ObjectTransform {
+    fn default() -> Self {
+        use cgmath::SquareMatrix;
+        cgmath::Matrix4::identity().into()
+    }
+}
+
 impl From<([f32; 3], [f32; 3])> for Vertex {
     fn from(v: ([f32; 3], [f32; 3])) -> Self {
         Self {
@@ -264,6 +489,7 @@ impl From<Vec<Triangle>> for Mesh {
             buffer_indices: vec![],
             buffer_offset: 0,
             texture: None,
+            material: None,
         }
     }
 }
@@ -275,6 +501,7 @@ impl From<(Vec<Vertex>, Vec<u32>)> for Mesh {
             buffer_indices: vec![],
             buffer_offset: 0,
             texture: None,
+            material: None,
         }
     }
 }
@@ -677,6 +904,48 @@ impl Mesh {
         }
     }
 
+    ///The box around the mesh's vertices in its own space, None for an empty mesh
+    pub fn bounds(&self) -> Option<Aabb> {
+        match &self.mesh_type {
+            MeshType::NonIndexed(tris) => {
+                Aabb::from_points(tris.iter().flat_map(|t| t.v.iter().map(|v| v.position)))
+            }
+            MeshType::Indexed(verts, _) => Aabb::from_points(verts.iter().map(|v| v.position)),
+        }
+    }
+
+    ///The nearest triangle the ray hits, with the ray in the mesh's own space
+    pub fn raycast(&self, ray: &Ray) -> Option<RayHit> {
+        ray.intersect_aabb(&self.bounds()?)?;
+        let nearest = |best: Option<RayHit>, hit: Option<RayHit>| match (best, hit) {
+            (Some(b), Some(h)) if h.distance < b.distance => Some(h),
+            (None, h) => h,
+            (b, _) => b,
+        };
+        match &self.mesh_type {
+            MeshType::NonIndexed(tris) => tris.iter().fold(None, |best, t| {
+                nearest(
+                    best,
+                    ray.intersect_triangle(t.v[0].position, t.v[1].position, t.v[2].position),
+                )
+            }),
+            MeshType::Indexed(verts, indices) => {
+                indices.chunks_exact(3).fold(None, |best, i| {
+                    let p = |i: u32| verts[i as usize].position;
+                    nearest(best, ray.intersect_triangle(p(i[0]), p(i[1]), p(i[2])))
+                })
+            }
+        }
+    }
+
+    ///Indices into vertices(), starting from 0 whatever buffer the mesh ends up in
+    pub fn indices(&self) -> Vec<u32> {
+        match &self.mesh_type {
+            MeshType::NonIndexed(tris) => (0..(tris.len() * 3) as u32).collect(),
+            MeshType::Indexed(verts, indices) => indices.clone(),
+        }
+    }
+
     pub fn vert_slice(&self) -> Vec<Vertex> {
         match &self.mesh_type {
             MeshType::NonIndexed(tris) => tris
@@ -10942,3 +11211,83 @@ f 3001 3004 3022";
         Mesh::load_from_string(teapot_string, false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb {
+            min: Vector3::new(-1.0, -1.0, -1.0),
+            max: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    fn ray(origin: (f32, f32, f32), dir: (f32, f32, f32)) -> Ray {
+        Ray {
+            origin: Vector3::new(origin.0, origin.1, origin.2),
+            dir: Vector3::new(dir.0, dir.1, dir.2),
+        }
+    }
+
+    #[test]
+    fn ray_enters_box() {
+        let hit = ray((0.5, 0.0, -5.0), (0.0, 0.0, 1.0)).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(4.0));
+        //Starting inside counts as hitting straight away
+        let hit = ray((0.0, 0.0, 0.0), (1.0, 0.0, 0.0)).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(0.0));
+    }
+
+    #[test]
+    fn ray_misses_box() {
+        //Pointing away
+        assert_eq!(
+            ray((0.0, 0.0, -5.0), (0.0, 0.0, -1.0)).intersect_aabb(&unit_box()),
+            None
+        );
+        //Parallel to the faces it would have to pass between
+        assert_eq!(
+            ray((2.0, 0.0, -5.0), (0.0, 0.0, 1.0)).intersect_aabb(&unit_box()),
+            None
+        );
+    }
+
+    #[test]
+    fn ray_hits_triangle_from_either_side() {
+        let (a, b, c) = (
+            Vector3::new(-1.0, -1.0, 0.0),
+            Vector3::new(1.0, -1.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        );
+        let hit = ray((0.0, 0.0, -2.0), (0.0, 0.0, 1.0))
+            .intersect_triangle(a, b, c)
+            .unwrap();
+        assert_eq!(hit.distance, 2.0);
+        assert_eq!(hit.normal.z, -1.0);
+        let hit = ray((0.0, 0.0, 3.0), (0.0, 0.0, -1.0))
+            .intersect_triangle(a, b, c)
+            .unwrap();
+        assert_eq!(hit.distance, 3.0);
+        assert_eq!(hit.normal.z, 1.0);
+    }
+
+    #[test]
+    fn ray_misses_triangle() {
+        let (a, b, c) = (
+            Vector3::new(-1.0, -1.0, 0.0),
+            Vector3::new(1.0, -1.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        );
+        //Beside it, behind it and along its plane
+        assert!(ray((2.0, 0.0, -2.0), (0.0, 0.0, 1.0))
+            .intersect_triangle(a, b, c)
+            .is_none());
+        assert!(ray((0.0, 0.0, 2.0), (0.0, 0.0, 1.0))
+            .intersect_triangle(a, b, c)
+            .is_none());
+        assert!(ray((-3.0, 0.0, 0.0), (1.0, 0.0, 0.0))
+            .intersect_triangle(a, b, c)
+            .is_none());
+    }
+}
@@ -1,28 +1,49 @@
-use super::{game_object::GameObject, geometry::*, math_3d::*, transform::*};
+use super::{
+    game_object::GameObject,
+    geometry::*,
+    material::{AlphaMode, Material},
+    math_3d::*,
+    texture::Texture,
+    transform::*,
+};
+use std::{collections::HashMap, sync::Arc};
+
+//Materials already loaded, by glTF material index (None is the glTF default material),
+// so meshes sharing one share the Arc and get drawn with the same bind group
+type MaterialCache = HashMap<Option<usize>, Arc<Material>>;
 
 
 pub fn get_game_objects(data: &[u8]) -> Vec<GameObject> {
     let (document, buffers, images) = gltf::import_slice(data).unwrap();
     let mut skip_nodes: Vec<usize> = document.nodes().map(get_children_id).flatten().collect();
+    let mut materials = MaterialCache::new();
     document
         .nodes()
         .filter(|node| !skip_nodes.contains(&node.index()))
-        .map(|node| process_node(node, &buffers, &images))
+        .map(|node| process_node_with(node, &buffers, &images, &mut materials))
         .collect()
 }
 
 pub fn get_game_objects_from_file(data: &str) -> Vec<GameObject> {
     let (document, buffers, images) = gltf::import(data).unwrap();
     let mut skip_nodes: Vec<usize> = document.nodes().map(get_children_id).flatten().collect();
+    let mut materials = MaterialCache::new();
     document
         .nodes()
         .filter(|node| !skip_nodes.contains(&node.index()))
-        .map(|node| process_node(node, &buffers, &images))
+        .map(|node| process_node_with(node, &buffers, &images, &mut materials))
         .collect()
 }
 
+//Every node below this one, so only the roots of each hierarchy become top level objects
 fn get_children_id(node: gltf::Node) -> Vec<usize> {
-    node.children().map(get_children_id).flatten().collect()
+    node.children()
+        .flat_map(|child| {
+            let mut ids = vec![child.index()];
+            ids.extend(get_children_id(child));
+            ids
+        })
+        .collect()
 }
 
 pub fn process_node(
@@ -30,16 +51,25 @@ pub fn process_node(
     buffers: &[gltf::buffer::Data],
     images: &[gltf::image::Data],
 ) -> GameObject {
-    let (node, transform, meshes) = extract_node(node, buffers, images);
+    process_node_with(node, buffers, images, &mut MaterialCache::new())
+}
+
+fn process_node_with(
+    node: gltf::Node,
+    buffers: &[gltf::buffer::Data],
+    images: &[gltf::image::Data],
+    materials: &mut MaterialCache,
+) -> GameObject {
+    let (node, transform, meshes) = extract_node_with(node, buffers, images, materials);
     let mut out_object = GameObject::new(transform, None, meshes);
+    out_object.name = node.name().unwrap_or_default().to_string();
     for node in node.children() {
-        //DONE: Node Transform, MESHES
-        //TODO: CAMERAS, PARENT TRANSFORMS, CHILDREN
-        out_object.children.insert(
-            out_object.children.len(),
-            process_node(node, buffers, images),
-        );
+        //DONE: Node Transform, MESHES, PARENT TRANSFORMS, CHILDREN
+        //TODO: CAMERAS
+        //Child transforms are relative to their parent, GameObject composes them
+        out_object.add_child(process_node_with(node, buffers, images, materials), false);
     }
+    out_object.update_transforms();
 
     out_object
 }
@@ -48,6 +78,15 @@ pub fn extract_node<'a>(
     node: gltf::Node<'a>,
     buffers: &[gltf::buffer::Data],
     images: &[gltf::image::Data],
+) -> (gltf::Node<'a>, Transform3, Vec<Mesh>) {
+    extract_node_with(node, buffers, images, &mut MaterialCache::new())
+}
+
+fn extract_node_with<'a>(
+    node: gltf::Node<'a>,
+    buffers: &[gltf::buffer::Data],
+    images: &[gltf::image::Data],
+    materials: &mut MaterialCache,
 ) -> (gltf::Node<'a>, Transform3, Vec<Mesh>) {
     use gltf::scene::Transform;
     let mut out_meshes: Vec<Mesh> = vec![];
@@ -73,58 +112,10 @@ pub fn extract_node<'a>(
         for primitive in mesh.primitives() {
             let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
             let material = primitive.material();
-            let get_texture =
-                |texture: gltf::texture::Texture| -> Option<(crate::sprite::Sprite, usize)> {
-                    let image_index = texture.source().index();
-                    let image = &images[image_index];
-                    let mut spr = crate::sprite::Sprite::new(image.width, image.height);
-                    match image.format {
-                        gltf::image::Format::R8 => spr.set_data(&image.pixels, 1),
-                        gltf::image::Format::R8G8 => spr.set_data(&image.pixels, 2),
-                        gltf::image::Format::R8G8B8 => spr.set_data(&image.pixels, 3),
-                        gltf::image::Format::R8G8B8A8 => spr.set_data(&image.pixels, 4),
-                        gltf::image::Format::B8G8R8 => spr.set_data(&image.pixels, 3),
-                        gltf::image::Format::B8G8R8A8 => spr.set_data(&image.pixels, 4),
-                        _ => {}
-                    }
-                    Some((spr, image_index))
-                };
-            if let Some(emissive) = material
-                .emissive_texture()
-                .and_then(|e| get_texture(e.texture()))
-            {
-                println!("emissive: {:?}", emissive.0.width);
-            }
-            if let Some(normal_map) = material
-                .normal_texture()
-                .and_then(|n| get_texture(n.texture()))
-            {
-                println!("normal: {:?}", normal_map.0.width);
-            }
-
-            let tex_i = material
-                .pbr_metallic_roughness()
-                .metallic_roughness_texture()
-                .and_then(|i| get_texture(i.texture()))
-                .map(|(tex, index)| (crate::texture::Texture::uninitialized(tex), index));
-            let (texture, index) = if let Some((tex, i)) = tex_i {
-                println!("roughness: {:?}", tex.data.width);
-                (Some(tex), i)
-            } else {
-                (None, 0)
-            };
-
-            let tex_i = material
-                .pbr_metallic_roughness()
-                .base_color_texture()
-                .and_then(|i| get_texture(i.texture()))
-                .map(|(tex, index)| (crate::texture::Texture::uninitialized(tex), index));
-            let (texture, index) = if let Some((tex, i)) = tex_i {
-                println!("color: {:?}", tex.data.width);
-                (Some(tex), i)
-            } else {
-                (None, 0)
-            };
+            let material = materials
+                .entry(material.index())
+                .or_insert_with(|| Arc::new(load_material(&material, images)))
+                .clone();
 
             if let Some(vert_iter) = reader.read_positions() {
                 let vertices: Vec<[f32; 3]> = vert_iter.into_iter().collect();
@@ -171,7 +162,8 @@ pub fn extract_node<'a>(
                         ),
                         buffer_indices: vec![],
                         buffer_offset: 0,
-                        texture,
+                        texture: None,
+                        material: Some(material.clone()),
                     };
                     new_mesh.calculate_normals(NormalMode::Shaded);
                     out_meshes.insert(out_meshes.len(), new_mesh);
@@ -204,7 +196,8 @@ pub fn extract_node<'a>(
                         ),
                         buffer_indices: vec![],
                         buffer_offset: 0,
-                        texture,
+                        texture: None,
+                        material: Some(material.clone()),
                     };
                     new_mesh.calculate_normals(NormalMode::Flat);
                     out_meshes.insert(out_meshes.len(), new_mesh);
@@ -215,3 +208,48 @@ pub fn extract_node<'a>(
 
     (node, transform, out_meshes)
 }
+
+pub fn load_material(material: &gltf::Material, images: &[gltf::image::Data]) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let texture = |texture: gltf::texture::Texture| load_texture(texture, images);
+    let emissive = material.emissive_factor();
+    let alpha_mode = match material.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+        gltf::material::AlphaMode::Mask => AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5)),
+        gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+    };
+    let normal = material.normal_texture();
+    let occlusion = material.occlusion_texture();
+    Material {
+        name: material.name().unwrap_or_default().to_string(),
+        base_color: pbr.base_color_factor(),
+        base_color_texture: pbr.base_color_texture().map(|i| texture(i.texture())),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        metallic_roughness_texture: pbr.metallic_roughness_texture().map(|i| texture(i.texture())),
+        normal_scale: normal.as_ref().map(|n| n.scale()).unwrap_or(1.0),
+        normal_texture: normal.map(|n| texture(n.texture())),
+        emissive,
+        emissive_texture: material.emissive_texture().map(|i| texture(i.texture())),
+        occlusion_strength: occlusion.as_ref().map(|o| o.strength()).unwrap_or(1.0),
+        occlusion_texture: occlusion.map(|o| texture(o.texture())),
+        alpha_mode,
+        double_sided: material.double_sided(),
+    }
+}
+
+//Converts the image behind a glTF texture, it's uploaded when the material is
+fn load_texture(texture: gltf::texture::Texture, images: &[gltf::image::Data]) -> Texture {
+    let image = &images[texture.source().index()];
+    let mut spr = crate::sprite::Sprite::new(image.width, image.height);
+    match image.format {
+        gltf::image::Format::R8 => spr.set_data(&image.pixels, 1),
+        gltf::image::Format::R8G8 => spr.set_data(&image.pixels, 2),
+        gltf::image::Format::R8G8B8 => spr.set_data(&image.pixels, 3),
+        gltf::image::Format::R8G8B8A8 => spr.set_data(&image.pixels, 4),
+        gltf::image::Format::B8G8R8 => spr.set_data(&image.pixels, 3),
+        gltf::image::Format::B8G8R8A8 => spr.set_data(&image.pixels, 4),
+        _ => {}
+    }
+    Texture::uninitialized(spr)
+}
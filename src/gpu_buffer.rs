@@ -0,0 +1,238 @@
+use std::ops::Range;
+
+///A GPU buffer handed out in ranges of elements. When it runs out of room it doubles,
+/// copying everything already uploaded across, and freed ranges are reused by later
+/// allocations. Ranges are in elements, not bytes.
+pub struct GpuBuffer {
+    pub buffer: wgpu::Buffer,
+    label: &'static str,
+    usage: wgpu::BufferUsage,
+    //Bytes per element, must be a multiple of 4 for copies
+    stride: u64,
+    capacity: u64,
+    ranges: FreeList,
+}
+
+//Which elements of a GpuBuffer are handed out, kept apart from the buffer itself
+#[derive(Debug, Default, PartialEq)]
+struct FreeList {
+    //Everything past here has never been handed out
+    end: u64,
+    //Sorted and never touching, neighbours are merged as they are freed
+    free: Vec<Range<u64>>,
+}
+
+impl FreeList {
+    //The first free range big enough, otherwise the range goes on the end
+    fn alloc(&mut self, count: u64) -> Range<u64> {
+        if count == 0 {
+            return 0..0;
+        }
+        if let Some(i) = self.free.iter().position(|r| r.end - r.start >= count) {
+            let start = self.free[i].start;
+            if self.free[i].end - start == count {
+                self.free.remove(i);
+            } else {
+                self.free[i].start += count;
+            }
+            return start..start + count;
+        }
+        let start = self.end;
+        self.end = start + count;
+        start..start + count
+    }
+
+    fn free(&mut self, range: Range<u64>) {
+        if range.start >= range.end {
+            return;
+        }
+        let i = self.free.partition_point(|r| r.start < range.start);
+        self.free.insert(i, range);
+        if i + 1 < self.free.len() && self.free[i].end == self.free[i + 1].start {
+            self.free[i].end = self.free.remove(i + 1).end;
+        }
+        if i > 0 && self.free[i - 1].end == self.free[i].start {
+            self.free[i - 1].end = self.free.remove(i).end;
+        }
+        //Whatever is free at the end goes back to being untouched
+        if let Some(last) = self.free.last() {
+            if last.end == self.end {
+                self.end = self.free.pop().unwrap().start;
+            }
+        }
+    }
+
+    fn used(&self) -> u64 {
+        self.end - self.free.iter().map(|r| r.end - r.start).sum::<u64>()
+    }
+}
+
+impl GpuBuffer {
+    pub fn new(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsage,
+        stride: u64,
+        capacity: u64,
+    ) -> Self {
+        let usage = usage | wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::COPY_SRC;
+        let capacity = capacity.max(1);
+        Self {
+            buffer: Self::create(device, label, usage, stride * capacity),
+            label,
+            usage,
+            stride,
+            capacity,
+            ranges: FreeList::default(),
+        }
+    }
+
+    fn create(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsage,
+        size: u64,
+    ) -> wgpu::Buffer {
+        //Not mapped, so nothing is allocated on the CPU side, wgpu zeroes it for us
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    ///Elements currently handed out
+    pub fn used(&self) -> u64 {
+        self.ranges.used()
+    }
+
+    pub fn stride(&self) -> u64 {
+        self.stride
+    }
+
+    ///Finds room for count elements, growing the buffer if nothing is free. Growing
+    /// replaces the buffer, so anything bound to the old one needs binding again.
+    pub fn alloc(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, count: u64) -> Range<u64> {
+        let uploaded = self.ranges.end;
+        let range = self.ranges.alloc(count);
+        if range.end > self.capacity {
+            self.grow(device, queue, range.end, uploaded);
+        }
+        range
+    }
+
+    pub fn free(&mut self, range: Range<u64>) {
+        self.ranges.free(range);
+    }
+
+    ///Frees everything without shrinking the buffer
+    pub fn clear(&mut self) {
+        self.ranges = FreeList::default();
+    }
+
+    ///Writes data starting at element start. Data is written as bytes, so T has to be
+    /// the same size as the elements, or a whole number of them.
+    pub fn write<T: bytemuck::Pod>(&self, queue: &wgpu::Queue, start: u64, data: &[T]) {
+        if data.is_empty() {
+            return;
+        }
+        queue.write_buffer(
+            &self.buffer,
+            start * self.stride,
+            bytemuck::cast_slice(data),
+        );
+    }
+
+    ///The bytes of a range, for binding part of the buffer
+    pub fn slice(&self, range: Range<u64>) -> wgpu::BufferSlice<'_> {
+        self.buffer
+            .slice(range.start * self.stride..range.end * self.stride)
+    }
+
+    //Only the first uploaded elements are copied across, the rest was never handed out
+    fn grow(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, needed: u64, uploaded: u64) {
+        let capacity = needed.max(self.capacity * 2);
+        let buffer = Self::create(device, self.label, self.usage, capacity * self.stride);
+        if uploaded > 0 {
+            //Writes queued on the old buffer are done before this copy runs
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Buffer Grow Encoder"),
+            });
+            encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, uploaded * self.stride);
+            queue.submit(std::iter::once(encoder.finish()));
+        }
+        log::debug!(
+            "{} grew from {} to {} elements",
+            self.label,
+            self.capacity,
+            capacity
+        );
+        self.buffer = buffer;
+        self.capacity = capacity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FreeList;
+
+    #[test]
+    fn allocations_go_on_the_end() {
+        let mut list = FreeList::default();
+        assert_eq!(list.alloc(3), 0..3);
+        assert_eq!(list.alloc(2), 3..5);
+        assert_eq!(list.alloc(0), 0..0);
+        assert_eq!(list.used(), 5);
+    }
+
+    #[test]
+    fn freed_neighbours_merge() {
+        let mut list = FreeList::default();
+        let ranges: Vec<_> = (0..4).map(|_| list.alloc(2)).collect();
+        list.free(ranges[0].clone());
+        list.free(ranges[2].clone());
+        assert_eq!(list.free, vec![0..2, 4..6]);
+        //Touches both, so all three become one
+        list.free(ranges[1].clone());
+        assert_eq!(list.free, vec![0..6]);
+        assert_eq!(list.used(), 2);
+    }
+
+    #[test]
+    fn freeing_the_end_trims_it() {
+        let mut list = FreeList::default();
+        let a = list.alloc(2);
+        let b = list.alloc(2);
+        let c = list.alloc(2);
+        list.free(b);
+        list.free(c);
+        assert_eq!(list.end, 2);
+        assert!(list.free.is_empty());
+        list.free(a);
+        assert_eq!(list, FreeList::default());
+    }
+
+    #[test]
+    fn first_fit_reuses_freed_space() {
+        let mut list = FreeList::default();
+        let a = list.alloc(2);
+        let _b = list.alloc(1);
+        let c = list.alloc(4);
+        let _d = list.alloc(1);
+        list.free(a);
+        list.free(c);
+        //Too big for the first gap, so it takes the start of the second
+        assert_eq!(list.alloc(3), 3..6);
+        assert_eq!(list.free, vec![0..2, 6..7]);
+        //Fills the first gap exactly
+        assert_eq!(list.alloc(2), 0..2);
+        assert_eq!(list.free, vec![6..7]);
+        //Nothing free is big enough
+        assert_eq!(list.alloc(2), 8..10);
+    }
+}
@@ -1,7 +1,9 @@
 use super::{
-    decal::DecalInstance,
+    decal::{Decal, DecalInstance},
     game_object::GameObject,
-    geometry::Vertex,
+    geometry::{Aabb, ObjectTransform, Vertex},
+    gpu_buffer::GpuBuffer,
+    material::{Material, MaterialBinding},
     pixel::Pixel,
     renderer::Renderer,
     sprite::{Sprite, SpriteMode},
@@ -10,6 +12,16 @@ use super::{
 };
 
 use bitflags::bitflags;
+use std::sync::Arc;
+
+//Buffers start this small and grow as objects are added
+const INITIAL_OBJECTS: u64 = 64;
+
+//Where the engine's GUI layer sits, far above any game layer but with room left for
+// move_layer either way
+pub const GUI_LAYER_ORDER: i32 = 1 << 24;
+//The console covers even the GUI
+pub const CONSOLE_LAYER_ORDER: i32 = GUI_LAYER_ORDER + 1;
 
 type Func<D> = Box<dyn Fn(&LayerDesc<D>, &Renderer, &mut D, &mut wgpu::CommandEncoder)>;
 
@@ -46,17 +58,110 @@ impl<D: OlcData + 'static> LayerFunc<D> {
 pub struct LayerDesc<D: OlcData + 'static> {
     pub id: u32,
     pub shown: bool,
+    //Layers are composited from the lowest z_index to the highest.
+    //Layers with the same z_index keep the order they were created in
+    pub z_index: i32,
     pub layer_info: LayerInfo<D>,
     pub vec_decal_instance: Vec<DecalInstance>,
 }
 
-#[derive(Default)]
 pub struct Image {
     pub sprite: Sprite,
     pub update: bool,
     pub offset: Vf2d,
     pub scale: Vf2d,
     pub tint: Pixel,
+    pub blend: LayerBlend,
+}
+
+impl Default for Image {
+    fn default() -> Self {
+        default_image()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LayerBlend {
+    Alpha,
+    Additive,
+    Multiply,
+    Screen,
+    Replace,
+}
+
+impl Default for LayerBlend {
+    fn default() -> Self {
+        Self::Alpha
+    }
+}
+
+impl LayerBlend {
+    pub const ALL: [LayerBlend; 5] = [
+        LayerBlend::Alpha,
+        LayerBlend::Additive,
+        LayerBlend::Multiply,
+        LayerBlend::Screen,
+        LayerBlend::Replace,
+    ];
+
+    pub fn blend_state(&self) -> wgpu::BlendState {
+        let (src_factor, dst_factor) = match self {
+            LayerBlend::Alpha => (wgpu::BlendFactor::SrcAlpha, wgpu::BlendFactor::OneMinusSrcAlpha),
+            LayerBlend::Additive => (wgpu::BlendFactor::SrcAlpha, wgpu::BlendFactor::One),
+            //src * dst, transparent pixels leave the destination alone
+            LayerBlend::Multiply => (wgpu::BlendFactor::Dst, wgpu::BlendFactor::OneMinusSrcAlpha),
+            //1 - (1 - src) * (1 - dst) == src * (1 - dst) + dst
+            LayerBlend::Screen => (wgpu::BlendFactor::OneMinusDst, wgpu::BlendFactor::One),
+            LayerBlend::Replace => (wgpu::BlendFactor::One, wgpu::BlendFactor::Zero),
+        };
+        wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                operation: wgpu::BlendOperation::Add,
+                src_factor,
+                dst_factor,
+            },
+            alpha: wgpu::BlendComponent {
+                operation: wgpu::BlendOperation::Add,
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+            },
+        }
+    }
+}
+
+//This is what the layer shader gets for each Image layer.
+//Offset and scale are applied to the texture coordinates, the same way PGE does it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LayerUniform {
+    pub offset: [f32; 2],
+    pub scale: [f32; 2],
+    pub tint: [f32; 4],
+}
+
+impl Default for LayerUniform {
+    fn default() -> Self {
+        Self {
+            offset: [0.0, 0.0],
+            scale: [1.0, 1.0],
+            tint: [1.0, 1.0, 1.0, 1.0],
+        }
+    }
+}
+
+impl From<&Image> for LayerUniform {
+    fn from(image: &Image) -> Self {
+        Self {
+            offset: [image.offset.x, image.offset.y],
+            scale: [image.scale.x, image.scale.y],
+            tint: [
+                image.tint.r() as f32 / 255.0,
+                image.tint.g() as f32 / 255.0,
+                image.tint.b() as f32 / 255.0,
+                image.tint.a() as f32 / 255.0,
+            ],
+        }
+    }
 }
 
 pub struct Render<D: OlcData + 'static> {
@@ -64,14 +169,26 @@ pub struct Render<D: OlcData + 'static> {
     pub pipeline_bundle: Option<PipelineBundle<D>>,
 }
 
+//An offscreen texture. Decals drawn while it is the draw target, and masks drawn with
+// Renderer::draw_mask_to_target, end up in it instead of on the screen
+pub struct Target {
+    pub width: u32,
+    pub height: u32,
+    //Owns the texture, clones of it can be drawn like any other decal
+    pub decal: Decal,
+    pub blend: LayerBlend,
+}
+
 pub enum LayerInfo<D: OlcData + 'static> {
     Image(Image),
     Render(Render<D>),
+    Target(Target),
 }
 
 pub enum LayerType {
     Image,
     Render,
+    Target,
 }
 
 pub trait LayerMask {
@@ -101,11 +218,54 @@ bitflags! {
 //   Put all the mesh textures in the pipeline_data::BindGroup
 //   The pipeline_data::BindGroupLayout should have one SAMPLER and one TEXTUREARRAY2D
 //   If we want a new shader, we can supply it. Otherwise, use the defauly
+//   Vertices stay in each object's local space, every object (children included, depth first)
+//   gets an ObjectTransform slot in transforms, so moving things only rewrites that
 pub struct DrawData {
     pub mask: Mask,
-    pub index_buffer: wgpu::Buffer,
-    pub vertex_buffer: wgpu::Buffer,
-    pub texture_groups: Vec<(std::ops::Range<u32>, Option<wgpu::BindGroup>)>,
+    pub indices: GpuBuffer,
+    pub vertices: GpuBuffer,
+    pub transforms: GpuBuffer,
+    //One for each game object given to update or add_objects, in the same order
+    pub objects: Vec<DrawObject>,
+    //Every material the objects use, uploaded once however many meshes share it
+    pub materials: Vec<Arc<MaterialBinding>>,
+    //Stand-ins for meshes with only a texture, kept so later uploads find the same one
+    texture_materials: Vec<Arc<Material>>,
+    //Bound wherever a material has no texture
+    white: wgpu::TextureView,
+}
+
+//Where one game object and its children live in a DrawData's buffers
+pub struct DrawObject {
+    pub vertices: std::ops::Range<u64>,
+    pub indices: std::ops::Range<u64>,
+    //One slot per node, depth first
+    pub transforms: std::ops::Range<u64>,
+    //Index range in the whole index buffer, material and the node it belongs to.
+    //Meshes without a material or texture are None and use the renderer's default
+    pub material_groups: Vec<(std::ops::Range<u32>, Option<Arc<MaterialBinding>>, u32)>,
+    //Each node's layer mask, as of the last upload
+    pub masks: Vec<Mask>,
+    //Each node's meshes in its own space, None for nodes without any
+    pub local_bounds: Vec<Option<Aabb>>,
+    //The same in world space, as of the last upload or update_transforms
+    pub bounds: Vec<Option<Aabb>>,
+    //Around the whole object, so culling can skip it in one test
+    pub total_bounds: Option<Aabb>,
+}
+
+impl DrawObject {
+    fn update_bounds(&mut self, worlds: &[cgmath::Matrix4<f32>]) {
+        self.bounds = self
+            .local_bounds
+            .iter()
+            .zip(worlds)
+            .map(|(b, world)| b.map(|b| b.transformed(world)))
+            .collect();
+        self.total_bounds = self.bounds.iter().flatten().fold(None, |acc, b| {
+            Some(acc.map_or(*b, |acc: Aabb| acc.union(b)))
+        });
+    }
 }
 
 pub struct PipelineData {
@@ -133,8 +293,9 @@ const fn default_image() -> Image {
         },
         update: false,
         offset: Vf2d { x: 0.0, y: 0.0 },
-        scale: Vf2d { x: 0.0, y: 0.0 },
-        tint: Pixel::BLANK,
+        scale: Vf2d { x: 1.0, y: 1.0 },
+        tint: Pixel::WHITE,
+        blend: LayerBlend::Alpha,
     }
 }
 
@@ -144,24 +305,35 @@ impl<D: OlcData + 'static> LayerDesc<D> {
             LayerType::Image => LayerDesc {
                 id: 0,
                 shown: false,
+                z_index: 0,
                 layer_info: LayerInfo::Image(Image {
-                    offset: Default::default(),
-                    scale: Default::default(),
-                    tint: Pixel::rgb(0, 0, 0),
                     sprite: Sprite::new(0, 0),
-                    update: false,
+                    ..Default::default()
                 }),
                 vec_decal_instance: vec![],
             },
             LayerType::Render => LayerDesc {
                 id: 0,
                 shown: false,
+                z_index: 0,
                 layer_info: LayerInfo::Render(Render {
                     pipeline_bundle: None,
                     mask: 0xFFFFFFFF,
                 }),
                 vec_decal_instance: vec![],
             },
+            LayerType::Target => LayerDesc {
+                id: 0,
+                shown: false,
+                z_index: 0,
+                layer_info: LayerInfo::Target(Target {
+                    width: 0,
+                    height: 0,
+                    decal: Decal::empty(),
+                    blend: LayerBlend::Alpha,
+                }),
+                vec_decal_instance: vec![],
+            },
         }
     }
 
@@ -169,6 +341,7 @@ impl<D: OlcData + 'static> LayerDesc<D> {
         LayerDesc {
             id: 0,
             shown: false,
+            z_index: 0,
             layer_info,
             vec_decal_instance: vec![],
         }
@@ -190,6 +363,14 @@ impl<D: OlcData + 'static> LayerDesc<D> {
         }
     }
 
+    pub fn as_target_layer(&self) -> Option<&Target> {
+        if let LayerInfo::Target(target) = &self.layer_info {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
     pub fn setup_default_pipeline_data(&mut self, renderer: &Renderer) {
         if let LayerInfo::Render(render_info) = &mut self.layer_info {
             render_info.pipeline_bundle = Some(PipelineBundle::default(renderer))
@@ -231,57 +412,194 @@ impl DrawData {
         self
     }
 
-    pub fn update(&mut self,
+    ///Uploads every mesh again along with the transforms. Only needed when objects or
+    /// meshes are changed, otherwise use update_transforms, add_objects and remove_object.
+    pub fn update(
+        &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        game_objects: &[&GameObject],){
+        game_objects: &[&GameObject],
+    ) {
+        self.vertices.clear();
+        self.indices.clear();
+        self.transforms.clear();
+        self.objects.clear();
+        self.add_objects(device, queue, game_objects);
+        self.drop_unused_materials();
+    }
+
+    ///Uploads more objects after the ones already here, reusing freed space first
+    pub fn add_objects(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        game_objects: &[&GameObject],
+    ) {
+        for go in game_objects {
+            let object = self.upload_object(device, queue, go);
+            self.objects.push(object);
+        }
+    }
+
+    fn upload_object(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        go: &GameObject,
+    ) -> DrawObject {
+        let nodes = go.flatten();
         let mut vertices: Vec<Vertex> = vec![];
         let mut indices: Vec<u32> = vec![];
-        let (mut index_count, mut vertex_count) = (0, 0);
-        for (_i, go) in game_objects.iter().enumerate() {
-            let (verts, inds, vc, _) = go.get_vertices_and_indices(vertex_count, index_count);
+        //Index ranges are relative to this object until its indices are placed
+        let mut groups = vec![];
+        for (node, (go, _)) in nodes.iter().enumerate() {
             for mesh in &go.meshes {
-                let tex = mesh.get_texture().as_ref().and_then(|tex| {
-                    tex.texture_bundle.as_ref().map(|bundle| {
-                        device.create_bind_group(&wgpu::BindGroupDescriptor {
-                            entries: &[wgpu::BindGroupEntry {
-                                binding: 0,
-                                resource: wgpu::BindingResource::TextureView(&bundle.view),
-                            }],
-                            label: None,
-                            layout: &device
-                                .create_bind_group_layout(&DrawData::default_bind_group_layout()),
-                        })
-                    })
-                });
-
-                let mesh_index_count = mesh.buffer_indices.len() as u32;
-                let i_range = index_count..index_count + mesh_index_count;
-                self.texture_groups
-                    .insert(self.texture_groups.len(), (i_range, tex));
-                index_count += mesh_index_count;
+                let material = self.material_binding(device, queue, mesh);
+                //Indices point into this object's vertices until they are placed
+                let base = vertices.len() as u32;
+                let start = indices.len() as u32;
+                indices.extend(mesh.indices().iter().map(|i| i + base));
+                vertices.extend(mesh.vertices());
+                groups.push((start..indices.len() as u32, material, node as u32));
             }
+        }
+        let vertex_range = self.vertices.alloc(device, queue, vertices.len() as u64);
+        let index_range = self.indices.alloc(device, queue, indices.len() as u64);
+        let transform_range = self.transforms.alloc(device, queue, nodes.len() as u64);
+        //WebGL can't draw indexed with a base vertex, so the indices point at the vertices'
+        // place in the whole buffer instead
+        let vertex_offset = vertex_range.start as u32;
+        for index in indices.iter_mut() {
+            *index += vertex_offset;
+        }
+        self.vertices.write(queue, vertex_range.start, &vertices);
+        self.indices.write(queue, index_range.start, &indices);
+        let transforms: Vec<ObjectTransform> =
+            nodes.iter().map(|(_, world)| (*world).into()).collect();
+        self.transforms.write(queue, transform_range.start, &transforms);
+        let offset = index_range.start as u32;
+        let worlds: Vec<cgmath::Matrix4<f32>> = nodes.iter().map(|(_, world)| *world).collect();
+        let mut object = DrawObject {
+            vertices: vertex_range,
+            indices: index_range,
+            transforms: transform_range,
+            material_groups: groups
+                .into_iter()
+                .map(|(range, material, node)| {
+                    (range.start + offset..range.end + offset, material, node)
+                })
+                .collect(),
+            masks: nodes.iter().map(|(go, _)| go.layer_mask).collect(),
+            local_bounds: nodes.iter().map(|(go, _)| go.mesh_bounds()).collect(),
+            bounds: vec![],
+            total_bounds: None,
+        };
+        object.update_bounds(&worlds);
+        object
+    }
 
-            vertices.extend(verts);
-            indices.extend(inds);
-            vertex_count = vc;
+    //Finds the mesh's material among the uploaded ones, uploading it if it's new
+    fn material_binding(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mesh: &super::geometry::Mesh,
+    ) -> Option<Arc<MaterialBinding>> {
+        let material = match (&mesh.material, mesh.get_texture()) {
+            (Some(material), _) => material.clone(),
+            //Meshes from before materials only have a texture
+            (None, Some(texture)) => self.texture_material(&texture.data),
+            (None, None) => return None,
+        };
+        if let Some(binding) = self
+            .materials
+            .iter()
+            .find(|b| Arc::ptr_eq(&b.material, &material))
+        {
+            return Some(binding.clone());
         }
-        println!("Verts: {}, Indices: {}", vertices.len(), indices.len());
-        //Fill index_buffer
-        queue.write_buffer(
-            &self.index_buffer,
-            0,
-            bytemuck::cast_slice(indices.as_slice()),
-        );
-        //Fill vertex::buffer
-        queue.write_buffer(
-            &self.vertex_buffer,
-            0,
-            bytemuck::cast_slice(vertices.as_slice()),
-        );
-    }
-
-    //This would be a good time to send the transforms so they are processed on the GPU instead
+        let binding = Arc::new(MaterialBinding::new(
+            device,
+            queue,
+            material,
+            &self.white,
+        ));
+        self.materials.push(binding.clone());
+        Some(binding)
+    }
+
+    //The stand-in material for a texture, meshes with the same pixels share one
+    fn texture_material(&mut self, sprite: &Sprite) -> Arc<Material> {
+        let pixels: &[u8] = bytemuck::cast_slice(&sprite.col_data);
+        let existing = self.texture_materials.iter().find(|m| {
+            m.base_color_texture.as_ref().is_some_and(|t| {
+                t.data.width == sprite.width
+                    && t.data.height == sprite.height
+                    && bytemuck::cast_slice::<Pixel, u8>(&t.data.col_data) == pixels
+            })
+        });
+        if let Some(material) = existing {
+            return material.clone();
+        }
+        let material = Arc::new(Material::from_texture(sprite.clone()));
+        self.texture_materials.push(material.clone());
+        material
+    }
+
+    //Materials only referenced from here aren't drawn by anything anymore
+    fn drop_unused_materials(&mut self) {
+        self.materials.retain(|b| Arc::strong_count(b) > 1);
+        self.texture_materials.retain(|m| Arc::strong_count(m) > 1);
+    }
+
+    ///Frees the buffer space of the object at index, the same index it had in the slice
+    /// given to update or add_objects. Later objects move down one.
+    pub fn remove_object(&mut self, index: usize) {
+        if index < self.objects.len() {
+            let object = self.objects.remove(index);
+            self.vertices.free(object.vertices);
+            self.indices.free(object.indices);
+            self.transforms.free(object.transforms);
+            self.drop_unused_materials();
+        }
+    }
+
+    ///Writes just the object transforms, as few writes as the objects' slots allow.
+    /// Falls back to a full update when the objects no longer match what was uploaded.
+    pub fn update_transforms(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        game_objects: &[&GameObject],
+    ) {
+        let nodes: Vec<Vec<cgmath::Matrix4<f32>>> = game_objects
+            .iter()
+            .map(|go| go.flatten().iter().map(|(_, world)| *world).collect())
+            .collect();
+        let matches = nodes.len() == self.objects.len()
+            && nodes
+                .iter()
+                .zip(&self.objects)
+                .all(|(n, o)| n.len() as u64 == o.transforms.end - o.transforms.start);
+        if !matches {
+            self.update(device, queue, game_objects);
+            return;
+        }
+        //Objects uploaded together sit next to each other, so they go in one write
+        let mut run: Vec<ObjectTransform> = vec![];
+        let mut run_start = 0;
+        for (worlds, object) in nodes.iter().zip(self.objects.iter_mut()) {
+            if object.transforms.start != run_start + run.len() as u64 {
+                self.transforms.write(queue, run_start, &run);
+                run.clear();
+                run_start = object.transforms.start;
+            }
+            run.extend(worlds.iter().map(|world| ObjectTransform::from(*world)));
+            object.update_bounds(worlds);
+        }
+        self.transforms.write(queue, run_start, &run);
+    }
+
     pub fn default_bind_group_layout<'a>() -> wgpu::BindGroupLayoutDescriptor<'a> {
         wgpu::BindGroupLayoutDescriptor {
             entries: &[wgpu::BindGroupLayoutEntry {
@@ -299,11 +617,24 @@ impl DrawData {
     }
 
     pub fn empty(mask: Mask, renderer: &Renderer) -> Self {
+        let white = renderer.white_texture.texture_bundle.as_ref().unwrap();
         Self {
             mask,
-            index_buffer: renderer.new_index_buffer(),
-            vertex_buffer: renderer.new_vertex_buffer(),
-            texture_groups: vec![],
+            indices: renderer.new_index_buffer(),
+            vertices: renderer.new_vertex_buffer(),
+            transforms: GpuBuffer::new(
+                &renderer.device,
+                "Object Transform Buffer",
+                wgpu::BufferUsage::VERTEX,
+                std::mem::size_of::<ObjectTransform>() as u64,
+                INITIAL_OBJECTS,
+            ),
+            objects: vec![],
+            materials: vec![],
+            texture_materials: vec![],
+            white: white
+                .texture
+                .create_view(&wgpu::TextureViewDescriptor::default()),
         }
     }
 }
@@ -334,32 +665,10 @@ impl PipelineData {
             write_mask: wgpu::ColorWrite::ALL,
         }];
         let bind_group_layouts = vec![
+            //Same as the default 3D pipeline's, so its bind group fits both
             renderer
                 .device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
-                            ty: wgpu::BindingType::Sampler {
-                                comparison: false,
-                                filtering: false,
-                            },
-                            count: None,
-                        },
-                    ],
-                }),
+                .create_bind_group_layout(&Renderer::camera_bind_group_layout()),
             renderer
                 .device
                 .create_bind_group_layout(&DrawData::default_bind_group_layout()),
@@ -379,6 +688,20 @@ impl PipelineData {
                                 renderer.texture_sampler.as_ref().unwrap(),
                             ),
                         },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: renderer.light_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: wgpu::BindingResource::TextureView(
+                                &renderer.shadow_maps.view,
+                            ),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 4,
+                            resource: wgpu::BindingResource::Sampler(&renderer.shadow_maps.sampler),
+                        },
                     ],
                     layout: &bind_group_layouts[0],
                     label: Some("blur group"),
@@ -479,6 +802,17 @@ impl PipelineData {
         self.shader = shader;
     }
 
+    ///The Depth32Float depth test every 3D pipeline uses, the shadow pass included
+    pub fn depth_stencil_state(depth_write_enabled: bool) -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format: wgpu::TextureFormat::Depth32Float,
+            depth_write_enabled,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }
+    }
+
     pub fn rebuild_pipeline(&mut self, renderer: &Renderer, use_depth: bool) {
         self.pipeline_layout =
             renderer
@@ -505,13 +839,7 @@ impl PipelineData {
             write_mask: wgpu::ColorWrite::ALL,
         }];
         let depth_stencil = if use_depth {
-            Some(wgpu::DepthStencilState {
-                format: wgpu::TextureFormat::Depth32Float,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            })
+            Some(Self::depth_stencil_state(true))
         } else {
             None
         };
@@ -521,7 +849,7 @@ impl PipelineData {
             vertex: wgpu::VertexState {
                 module: &self.shader,
                 entry_point: "vs_main",
-                buffers: &[Vertex::desc()],
+                buffers: &[Vertex::desc(), ObjectTransform::desc()],
             },
             fragment: Some(wgpu::FragmentState {
                 // 3.
@@ -7,30 +7,39 @@
 pub mod olc;
 // pub mod app;
 pub mod camera;
-// pub mod debug_gui;
+pub mod camera_controller;
+pub mod console;
+pub mod debug_gui;
 pub mod decal;
 pub mod engine;
+pub mod font;
 pub mod game;
 pub mod game_object;
 pub mod geometry;
 pub mod gltf_ext;
+pub mod gpu_buffer;
 pub mod layer;
+pub mod light;
+pub mod material;
 pub mod math_3d;
 pub mod math_4d;
 pub mod pixel;
 pub mod platform;
 pub mod renderer;
+pub mod rich_text;
+pub mod shadow;
 pub mod sprite;
 pub mod texture;
 pub mod transform;
+pub mod ui;
 pub mod util;
 
 pub mod prelude {
     pub use crate::{
-        camera, camera::*, decal, decal::*, engine, engine::*, game, game::*, game_object,
-        game_object::*, geometry, geometry::*, gltf_ext, gltf_ext::*, layer, layer::*, math_3d, math_3d::*, math_4d,
-        math_4d::*, pixel, pixel::*, platform, platform::*, renderer, renderer::*, sprite,
-        sprite::*, texture, texture::*, transform, transform::*, util, util::*,
+        camera, camera::*, camera_controller, camera_controller::*, console, console::*, debug_gui, debug_gui::*, decal, decal::*, engine, engine::*, font, font::*, game, game::*, game_object,
+        game_object::*, geometry, geometry::*, gltf_ext, gltf_ext::*, gpu_buffer, gpu_buffer::*, layer, layer::*, light, light::*, material, material::*, math_3d, math_3d::*, math_4d,
+        math_4d::*, pixel, pixel::*, platform, platform::*, renderer, renderer::*, rich_text, rich_text::*, shadow, shadow::*, sprite,
+        sprite::*, texture, texture::*, transform, transform::*, ui, ui::*, util, util::*,
         olc::Olc,
         olc::OlcData,
         olc::OlcFuture,
@@ -0,0 +1,216 @@
+use super::{
+    game_object::GameObject,
+    pixel::Pixel,
+    shadow::{light_transform, ShadowSettings, MAX_SHADOWS},
+};
+
+//The default 3D shader's light array is this long, any more lights are left out
+pub const MAX_LIGHTS: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightKind {
+    //Lights everything from the direction the object faces, like the sun
+    Directional,
+    Point,
+    //Angles in radians from the direction the object faces, full brightness inside
+    // the inner angle fading to nothing at the outer one
+    Spot { inner_angle: f32, outer_angle: f32 },
+}
+
+///A light that shines from the GameObject it's attached to, facing the object's forward
+/// direction. Lights are read from the objects every frame, so changing the intensity
+/// each frame makes it flicker.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: Pixel,
+    pub intensity: f32,
+    //Distance at which point and spot lights fade out completely, 0 never does
+    pub range: f32,
+    pub enabled: bool,
+    //Directional and spot lights only, the first MAX_SHADOWS of them get shadows
+    pub cast_shadows: bool,
+}
+
+impl Light {
+    pub fn directional(color: Pixel, intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Directional,
+            color,
+            intensity,
+            range: 0.0,
+            enabled: true,
+            cast_shadows: false,
+        }
+    }
+
+    pub fn point(color: Pixel, intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            color,
+            intensity,
+            range,
+            enabled: true,
+            cast_shadows: false,
+        }
+    }
+
+    pub fn spot(
+        color: Pixel,
+        intensity: f32,
+        range: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    ) -> Self {
+        Self {
+            kind: LightKind::Spot {
+                inner_angle,
+                outer_angle,
+            },
+            color,
+            intensity,
+            range,
+            enabled: true,
+            cast_shadows: false,
+        }
+    }
+}
+
+///Light that reaches everything equally, so nothing is ever fully black
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AmbientLight {
+    pub color: Pixel,
+    pub intensity: f32,
+}
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        Self {
+            color: Pixel::WHITE,
+            intensity: 0.03,
+        }
+    }
+}
+
+fn linear_color(color: Pixel, intensity: f32) -> [f32; 4] {
+    [
+        color.r() as f32 / 255.0 * intensity,
+        color.g() as f32 / 255.0 * intensity,
+        color.b() as f32 / 255.0 * intensity,
+        0.0,
+    ]
+}
+
+//One light as the shader sees it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniform {
+    //World position, w is the kind (0 directional, 1 point, 2 spot)
+    pub position: [f32; 4],
+    //World direction the light faces, w is the range
+    pub direction: [f32; 4],
+    //Color times intensity
+    pub color: [f32; 4],
+    //Cosines of the inner and outer spot angles
+    pub cone: [f32; 4],
+    //Shadow map layer (-1 for none) and bias
+    pub shadow: [f32; 4],
+    //World to shadow map, when there is one
+    pub shadow_transform: [[f32; 4]; 4],
+}
+
+impl LightUniform {
+    pub fn new(light: &Light, world: cgmath::Matrix4<f32>) -> Self {
+        use cgmath::InnerSpace;
+        let (kind, cone) = match light.kind {
+            LightKind::Directional => (0.0, [0.0; 4]),
+            LightKind::Point => (1.0, [0.0; 4]),
+            LightKind::Spot {
+                inner_angle,
+                outer_angle,
+            } => (2.0, [inner_angle.cos(), outer_angle.cos(), 0.0, 0.0]),
+        };
+        let forward = (world * cgmath::Vector4::new(0.0, 0.0, 1.0, 0.0)).truncate();
+        let forward = if forward.magnitude2() > 0.0 {
+            forward.normalize()
+        } else {
+            cgmath::Vector3::unit_z()
+        };
+        Self {
+            position: [world.w.x, world.w.y, world.w.z, kind],
+            direction: [forward.x, forward.y, forward.z, light.range],
+            color: linear_color(light.color, light.intensity),
+            cone,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for LightUniform {
+    fn default() -> Self {
+        Self {
+            position: [0.0; 4],
+            direction: [0.0; 4],
+            color: [0.0; 4],
+            cone: [0.0; 4],
+            shadow: [-1.0, 0.0, 0.0, 0.0],
+            shadow_transform: [[0.0; 4]; 4],
+        }
+    }
+}
+
+//Group 0 binding 2 of the default 3D shader
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightsUniform {
+    pub ambient: [f32; 4],
+    //Only x is used, the rest pads it out to 16 bytes
+    pub count: [u32; 4],
+    pub lights: [LightUniform; MAX_LIGHTS],
+}
+
+impl LightsUniform {
+    ///Collects the enabled lights of the objects and all of their children, handing out
+    /// shadow map layers to the ones casting shadows. Lights on inactive objects are off.
+    /// Focus is what directional light shadows are centered on, usually the camera.
+    pub fn new(
+        ambient: AmbientLight,
+        game_objects: &[&GameObject],
+        focus: [f32; 3],
+        shadows: &ShadowSettings,
+    ) -> Self {
+        let mut lights = [LightUniform::default(); MAX_LIGHTS];
+        let mut count = 0;
+        let mut shadow_layers = 0;
+        for go in game_objects {
+            for (node, world) in go.flatten() {
+                let light = match &node.light {
+                    Some(light) if light.enabled && node.active => light,
+                    _ => continue,
+                };
+                if count == MAX_LIGHTS {
+                    log::debug!("More than {} lights, the rest are ignored", MAX_LIGHTS);
+                    return Self::with(ambient, count, lights);
+                }
+                lights[count] = LightUniform::new(light, world);
+                if light.cast_shadows && shadow_layers < MAX_SHADOWS {
+                    if let Some(transform) = light_transform(light, world, focus, shadows) {
+                        lights[count].shadow = [shadow_layers as f32, shadows.bias, 0.0, 0.0];
+                        lights[count].shadow_transform = transform.into();
+                        shadow_layers += 1;
+                    }
+                }
+                count += 1;
+            }
+        }
+        Self::with(ambient, count, lights)
+    }
+
+    fn with(ambient: AmbientLight, count: usize, lights: [LightUniform; MAX_LIGHTS]) -> Self {
+        Self {
+            ambient: linear_color(ambient.color, ambient.intensity),
+            count: [count as u32, 0, 0, 0],
+            lights,
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use super::{sprite::Sprite, texture::Texture};
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum AlphaMode {
+    #[default]
+    Opaque,
+    //Pixels with less alpha than the cutoff are discarded, the rest are opaque
+    Mask(f32),
+    //Drawn after everything opaque, blended over it
+    Blend,
+}
+
+///A glTF style metallic-roughness material. Each texture is multiplied by its factor,
+/// a missing texture counts as white so only the factor is used.
+pub struct Material {
+    pub name: String,
+    pub base_color: [f32; 4],
+    pub base_color_texture: Option<Texture>,
+    pub metallic: f32,
+    pub roughness: f32,
+    //Roughness in green and metallic in blue, as glTF packs them
+    pub metallic_roughness_texture: Option<Texture>,
+    pub normal_texture: Option<Texture>,
+    pub normal_scale: f32,
+    pub emissive: [f32; 3],
+    pub emissive_texture: Option<Texture>,
+    //Ambient occlusion in red
+    pub occlusion_texture: Option<Texture>,
+    pub occlusion_strength: f32,
+    pub alpha_mode: AlphaMode,
+    //Back faces are drawn too instead of being culled
+    pub double_sided: bool,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            base_color: [1.0; 4],
+            base_color_texture: None,
+            metallic: 0.0,
+            roughness: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            emissive: [0.0; 3],
+            emissive_texture: None,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+        }
+    }
+}
+
+impl Clone for Material {
+    //Like Mesh, clones get the texture data but have to be uploaded again
+    fn clone(&self) -> Self {
+        let copy = |t: &Option<Texture>| t.as_ref().map(|t| Texture::uninitialized(t.data.clone()));
+        Self {
+            name: self.name.clone(),
+            base_color: self.base_color,
+            base_color_texture: copy(&self.base_color_texture),
+            metallic: self.metallic,
+            roughness: self.roughness,
+            metallic_roughness_texture: copy(&self.metallic_roughness_texture),
+            normal_texture: copy(&self.normal_texture),
+            normal_scale: self.normal_scale,
+            emissive: self.emissive,
+            emissive_texture: copy(&self.emissive_texture),
+            occlusion_texture: copy(&self.occlusion_texture),
+            occlusion_strength: self.occlusion_strength,
+            alpha_mode: self.alpha_mode,
+            double_sided: self.double_sided,
+        }
+    }
+}
+
+//Layout of the material uniform in the default 3D shader
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    pub base_color: [f32; 4],
+    pub emissive: [f32; 4],
+    //Metallic, roughness, normal scale (0 without a normal map), occlusion strength
+    pub params: [f32; 4],
+    //Mode (0 opaque, 1 mask, 2 blend) and cutoff
+    pub alpha: [f32; 4],
+}
+
+impl From<&Material> for MaterialUniform {
+    fn from(m: &Material) -> Self {
+        let (mode, cutoff) = match m.alpha_mode {
+            AlphaMode::Opaque => (0.0, 0.0),
+            AlphaMode::Mask(cutoff) => (1.0, cutoff),
+            AlphaMode::Blend => (2.0, 0.0),
+        };
+        Self {
+            base_color: m.base_color,
+            emissive: [m.emissive[0], m.emissive[1], m.emissive[2], 0.0],
+            params: [
+                m.metallic,
+                m.roughness,
+                if m.normal_texture.is_some() {
+                    m.normal_scale
+                } else {
+                    0.0
+                },
+                m.occlusion_strength,
+            ],
+            alpha: [mode, cutoff, 0.0, 0.0],
+        }
+    }
+}
+
+///A material uploaded to the GPU, made by DrawData for each material its meshes use
+pub struct MaterialBinding {
+    pub material: std::sync::Arc<Material>,
+    pub bind_group: wgpu::BindGroup,
+    //Kept so the bind group's views stay valid
+    pub uniform: wgpu::Buffer,
+    pub textures: Vec<Texture>,
+}
+
+impl MaterialBinding {
+    ///Uploads the material's textures, using fallback where it has none
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        material: std::sync::Arc<Material>,
+        fallback: &wgpu::TextureView,
+    ) -> Self {
+        use wgpu::util::DeviceExt;
+        let uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Material Uniform"),
+            contents: bytemuck::cast_slice(&[MaterialUniform::from(material.as_ref())]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        //Colours are stored in sRGB, the other maps are data and have to be read as is.
+        // Sprites are RGBA, so a BGRA format would swap their channels.
+        let srgb = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let linear = wgpu::TextureFormat::Rgba8Unorm;
+        let sources = [
+            (&material.base_color_texture, srgb),
+            (&material.metallic_roughness_texture, linear),
+            (&material.normal_texture, linear),
+            (&material.emissive_texture, srgb),
+            (&material.occlusion_texture, linear),
+        ];
+        let textures: Vec<Option<Texture>> = sources
+            .iter()
+            .map(|(source, format)| {
+                source.as_ref().map(|t| {
+                    let mut texture = Texture::new(device, t.data.width, t.data.height, *format);
+                    texture.update(queue, &t.data);
+                    texture
+                })
+            })
+            .collect();
+        let views: Vec<&wgpu::TextureView> = textures
+            .iter()
+            .map(|t| match t {
+                Some(t) => &t.texture_bundle.as_ref().unwrap().view,
+                None => fallback,
+            })
+            .collect();
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: uniform.as_entire_binding(),
+        }];
+        entries.extend(
+            views
+                .iter()
+                .enumerate()
+                .map(|(i, view)| wgpu::BindGroupEntry {
+                    binding: i as u32 + 1,
+                    resource: wgpu::BindingResource::TextureView(view),
+                }),
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Material Bind Group"),
+            layout: &device.create_bind_group_layout(&Self::bind_group_layout()),
+            entries: &entries,
+        });
+        Self {
+            material,
+            bind_group,
+            uniform,
+            textures: textures.into_iter().flatten().collect(),
+        }
+    }
+
+    ///Group 1 of the default 3D pipeline: the uniform, then base colour, metallic
+    /// roughness, normal, emissive and occlusion textures
+    pub fn bind_group_layout<'a>() -> wgpu::BindGroupLayoutDescriptor<'a> {
+        const fn texture(binding: u32) -> wgpu::BindGroupLayoutEntry {
+            wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }
+        }
+        const ENTRIES: [wgpu::BindGroupLayoutEntry; 6] = [
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            texture(1),
+            texture(2),
+            texture(3),
+            texture(4),
+            texture(5),
+        ];
+        wgpu::BindGroupLayoutDescriptor {
+            label: Some("Material Bind Group Layout"),
+            entries: &ENTRIES,
+        }
+    }
+}
+
+impl Material {
+    ///A plain material showing a texture, what meshes with only a texture are drawn with
+    pub fn from_texture(sprite: Sprite) -> Self {
+        Self {
+            base_color_texture: Some(Texture::uninitialized(sprite)),
+            ..Default::default()
+        }
+    }
+}
+
+///Which variant of the default 3D pipeline a material is drawn with
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MaterialPipeline {
+    pub double_sided: bool,
+    pub blend: bool,
+}
+
+impl MaterialPipeline {
+    pub const ALL: [MaterialPipeline; 4] = [
+        MaterialPipeline {
+            double_sided: false,
+            blend: false,
+        },
+        MaterialPipeline {
+            double_sided: true,
+            blend: false,
+        },
+        MaterialPipeline {
+            double_sided: false,
+            blend: true,
+        },
+        MaterialPipeline {
+            double_sided: true,
+            blend: true,
+        },
+    ];
+
+    pub fn cull_mode(&self) -> Option<wgpu::Face> {
+        if self.double_sided {
+            None
+        } else {
+            Some(wgpu::Face::Back)
+        }
+    }
+
+    //Blended surfaces still test depth, but don't hide what is drawn behind them later
+    pub fn depth_write(&self) -> bool {
+        !self.blend
+    }
+}
+
+impl Default for MaterialPipeline {
+    fn default() -> Self {
+        Self::ALL[0]
+    }
+}
+
+impl From<&Material> for MaterialPipeline {
+    fn from(m: &Material) -> Self {
+        Self {
+            double_sided: m.double_sided,
+            blend: m.alpha_mode == AlphaMode::Blend,
+        }
+    }
+}
@@ -20,6 +20,12 @@ pub trait Olc<D: 'static + OlcData> {
     fn on_engine_update(&self, engine: &mut OLCEngine<D>, elapsedTime: f64) -> Result<(), &str>;
 
     fn on_engine_destroy(&self, engine: &mut OLCEngine<D>) -> Result<(), &str>;
+
+    ///Gets console lines that aren't a registered command, like PGE's OnConsoleCommand.
+    /// Return false when the game doesn't know the command either.
+    fn on_console_command(&self, engine: &mut OLCEngine<D>, command: &str) -> bool {
+        false
+    }
 }
 
 pub trait OlcData {}
@@ -84,6 +84,9 @@ pub struct PlatformData {
     pub mouse_map: Option<Vec<HWButton>>,
     pub mouse_wheel_delta: i32,
     pub mouse_wheel_delta_cache: i32,
+    //Characters typed since the last frame, for text entry
+    pub text_input: String,
+    pub text_input_cache: String,
     pub mouse_position: Option<Vf2d>,
     pub raw_mouse_position: Option<Vf2d>,
     pub view_position: Option<Vi2d>,
@@ -117,6 +120,8 @@ impl PlatformData {
             mouse_map: None,
             mouse_wheel_delta: 0,
             mouse_wheel_delta_cache: 0,
+            text_input: String::new(),
+            text_input_cache: String::new(),
             mouse_position: None,
             raw_mouse_position: None,
             view_position: None,
@@ -185,6 +190,9 @@ impl PlatformData {
     pub fn update_mouse_wheel(&mut self, delta: i32) {
         self.mouse_wheel_delta_cache += delta;
     }
+    pub fn update_text_input(&mut self, c: char) {
+        self.text_input_cache.push(c);
+    }
     pub fn update_mouse_focus(&mut self, b: bool) {
         self.mouse_focus = b
     }
@@ -272,6 +280,9 @@ impl Platform for PlatformWindows {
                     } => {
                         PLATFORM_DATA.update_mouse_wheel(*v as i32);
                     }
+                    WindowEvent::ReceivedCharacter(c) if !c.is_control() => {
+                        PLATFORM_DATA.update_text_input(*c);
+                    }
                     WindowEvent::CursorLeft { device_id: _ } => {
                         PLATFORM_DATA.update_mouse_focus(false);
                     }
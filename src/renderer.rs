@@ -1,9 +1,13 @@
 use super::{
-    camera::{Camera, RawMat},
-    decal::DecalInstance,
+    camera::{Camera, Frustum, RawMat},
+    decal::{DecalInstance, DecalMode, DecalVertex},
     game_object::GameObject,
-    geometry::{Mesh, Primitives, Triangle, Vertex},
-    layer::{DrawData, LayerMask, Mask},
+    geometry::{Aabb, Mesh, ObjectTransform, Primitives, Ray, Triangle, Vertex},
+    gpu_buffer::GpuBuffer,
+    layer::{DrawData, LayerBlend, LayerMask, LayerUniform, Mask, PipelineData},
+    light::{AmbientLight, LightsUniform},
+    shadow::{ShadowMaps, ShadowSettings},
+    material::{Material, MaterialBinding, MaterialPipeline},
     math_3d::Vector3,
     pixel::Pixel,
     platform::PLATFORM_DATA,
@@ -12,6 +16,9 @@ use super::{
     util::{Vf2d, Vi2d},
     olc::Rcode,
 };
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use wgpu::util::DeviceExt;
 
 #[cfg(target_arch = "wasm32")]
@@ -19,9 +26,49 @@ use winit::platform::web::WindowBuilderExtWebSys;
 #[cfg(target_arch = "wasm32")]
 use winit::platform::web::WindowExtWebSys;
 
-pub const VERT_BUFFER_SIZE: usize = 25 /*MB*/ * 1024 * 1024 / std::mem::size_of::<Vertex>();
-pub const MAX_VERTICES: usize = VERT_BUFFER_SIZE;
-pub const INDEX_BUFFER_SIZE: usize = 5 /*MB*/ * 1024 * 1024 / std::mem::size_of::<u32>();
+//Starting sizes of each DrawData's buffers, they grow when a scene needs more
+pub const INITIAL_VERTICES: u64 = 4096;
+pub const INITIAL_INDICES: u64 = 3 * INITIAL_VERTICES;
+
+lazy_static::lazy_static! {
+    //Decals are dropped without access to the Renderer, so their texture ids wait here
+    // until the next frame frees them
+    static ref DELETED_TEXTURES: Mutex<Vec<u32>> = Mutex::new(vec![]);
+}
+
+//A run of decal vertices drawn with one texture (None for untextured) and mode
+type DecalBatch = (std::ops::Range<u32>, Option<u32>, DecalMode);
+
+///What the last frame's layer pass submitted, useful for spotting batches that didn't merge
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DecalStats {
+    pub decals: u32,
+    pub batches: u32,
+    pub draw_calls: u32,
+}
+
+///How much of the 3D scene the last frame's draw_mask calls skipped for being off screen.
+/// Objects count once per draw_mask call, draws are one per mesh.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CullStats {
+    pub objects: u32,
+    pub culled_objects: u32,
+    pub draws: u32,
+    pub culled_draws: u32,
+}
+
+///The nearest game object a ray hit, see Renderer::raycast
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PickHit {
+    //Of the object in the hierarchy whose mesh was hit, children included. Given out by
+    // add_game_object, objects pushed straight onto game_objects keep theirs, -1 by default.
+    pub uid: i32,
+    //Of the root object in game_objects
+    pub index: usize,
+    pub distance: f32,
+    pub point: Vector3,
+    pub normal: Vector3,
+}
 
 pub struct Renderer {
     pub surface: wgpu::Surface,
@@ -30,21 +77,48 @@ pub struct Renderer {
     pub sc_desc: wgpu::SwapChainDescriptor,
     pub swap_chain: wgpu::SwapChain,
     pub size: winit::dpi::PhysicalSize<u32>,
-    pub render_pipeline: Option<wgpu::RenderPipeline>,
+    pub layer_pipelines: HashMap<LayerBlend, wgpu::RenderPipeline>,
     pub render_3D_pipeline_indexed: Option<wgpu::RenderPipeline>,
+    //The default 3D pipeline for each kind of material, render_3D_pipeline_indexed is
+    // the opaque single sided one
+    pub render_3D_pipelines: HashMap<MaterialPipeline, wgpu::RenderPipeline>,
+    pub decal_pipelines: HashMap<DecalMode, wgpu::RenderPipeline>,
     pub decal_buffer: wgpu::Buffer,
     pub decals: Vec<Texture>,
     pub active_decals: Vec<u32>,
+    pub layer_blends: Vec<LayerBlend>,
+    pub layer_decal_instances: Vec<Vec<DecalInstance>>,
+    pub target_decal_instances: Vec<(u32, Vf2d, Vec<DecalInstance>)>,
+    pub target_depth_textures: HashMap<u32, Texture>,
+    pub decal_vertex_buffer: Option<wgpu::Buffer>,
+    pub decal_vertex_capacity: usize,
+    pub decal_stats: DecalStats,
+    pub cull_stats: CullStats,
+    //Counts up while a frame is drawn, becomes cull_stats when the next one starts
+    cull_counter: Cell<CullStats>,
+    //Given to game objects and their children as they are added, see PickHit
+    next_uid: i32,
     pub decal_counter: i32,
+    pub free_texture_ids: Vec<u32>,
     pub layer_textures: Option<Vec<wgpu::BindGroup>>,
+    pub layer_uniforms: HashMap<u32, wgpu::Buffer>,
+    pub frame_uniform: wgpu::Buffer,
     pub texture_sampler: Option<wgpu::Sampler>,
     pub bind_group_layout: Option<wgpu::BindGroupLayout>,
     pub bind_group: Option<wgpu::BindGroup>,
+    pub decal_bind_group_layout: Option<wgpu::BindGroupLayout>,
+    pub decal_bind_groups: HashMap<u32, wgpu::BindGroup>,
     pub draw_data: Vec<DrawData>,
     pub frame: Option<wgpu::SwapChainFrame>,
     pub layer_shader: wgpu::ShaderModule,
+    pub decal_shader: wgpu::ShaderModule,
     pub indexed_vert_shader: wgpu::ShaderModule,
     pub camera_buffer: wgpu::Buffer,
+    //Rewritten from the game objects' lights whenever a mask is drawn
+    pub light_buffer: wgpu::Buffer,
+    pub ambient_light: AmbientLight,
+    pub shadow_maps: ShadowMaps,
+    pub shadow_settings: ShadowSettings,
     pub cam_sampler_uniform_group: Option<wgpu::BindGroup>,
     pub game_objects: Vec<GameObject>,
     pub meshes: Vec<Mesh>,
@@ -53,11 +127,16 @@ pub struct Renderer {
     pub index_count: u32,
     pub indexed_vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
+    //Bound for draws that don't give an object transform
+    pub identity_transform_buffer: wgpu::Buffer,
     pub frame_texture_backbuffer: Texture,
     pub frame_texture: Texture,
     pub depth_texture: Texture,
     pub default_texture: Texture,
-    pub default_texture_bind: wgpu::BindGroup,
+    //The checkerboard, for meshes with no material or texture
+    pub default_material: MaterialBinding,
+    pub white_texture: Texture,
+    pub white_texture_bind: Option<wgpu::BindGroup>,
     pub camera: Camera,
 }
 
@@ -116,6 +195,12 @@ impl Renderer {
             source: wgpu::ShaderSource::Wgsl(include_str!("shaders/layer.wgsl").into()),
         });
 
+        let decal_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("decal_shader"),
+            flags: wgpu::ShaderFlags::all(),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/decal.wgsl").into()),
+        });
+
         let indexed_vert_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
             label: Some("indexed_vert_shader"),
             flags: wgpu::ShaderFlags::all(),
@@ -230,17 +315,20 @@ impl Renderer {
         );
 
         default_texture.update(&queue, &spr);
-        let default_texture_bind = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(
-                    &default_texture.texture_bundle.as_ref().unwrap().view,
-                ),
-            }],
-            label: None,
-            layout: &device
-                .create_bind_group_layout(&super::layer::DrawData::default_bind_group_layout()),
-        });
+        //Untextured decals sample this so their tint comes through unchanged
+        let mut white_texture = Texture::new(
+            &device,
+            1,
+            1,
+            default_texture.texture_bundle.as_ref().unwrap().format,
+        );
+        white_texture.update(&queue, &Sprite::new_with_data(1, 1, vec![Pixel::WHITE]));
+        let default_material = MaterialBinding::new(
+            &device,
+            &queue,
+            Arc::new(Material::from_texture(spr.clone())),
+            &white_texture.texture_bundle.as_ref().unwrap().view,
+        );
         /* let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("sampler"),
             entries: &[
@@ -264,45 +352,28 @@ impl Renderer {
             contents: bytemuck::cast_slice(Primitives::cube().vertices().as_slice()),
             usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
         });
-        let blank_buff: Vec<Vertex> = vec![Vertex::default(); VERT_BUFFER_SIZE];
-        let indexed_vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        //Only drawn from when no DrawData buffers are given, these get replaced on upload
+        let indexed_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Indexed Vertex Buffer"),
-            //This is the maximum number of vertices that can be indexed with a u16
-            contents: bytemuck::cast_slice(blank_buff.as_slice()),
+            size: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
             usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
         });
+        let identity_transform_buffer =
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Identity Transform Buffer"),
+                contents: bytemuck::cast_slice(&[ObjectTransform::default()]),
+                usage: wgpu::BufferUsage::VERTEX,
+            });
 
-        let blank_buff: Vec<u32> = vec![0; INDEX_BUFFER_SIZE];
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Index Buffer"),
-            contents: bytemuck::cast_slice(blank_buff.as_slice()),
+            size: std::mem::size_of::<u32>() as wgpu::BufferAddress,
             usage: wgpu::BufferUsage::INDEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
         });
 
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: None,
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
-                    ty: wgpu::BindingType::Sampler {
-                        comparison: false,
-                        filtering: false,
-                    },
-                    count: None,
-                },
-            ],
-        });
+        let bind_group_layout = device.create_bind_group_layout(&Self::camera_bind_group_layout());
         let mut cam_data: Vec<u8> = bytemuck::cast_slice(&[RawMat::default()]).into();
         let window_size = unsafe { PLATFORM_DATA.window_size.as_ref().unwrap() };
         unsafe {
@@ -316,6 +387,19 @@ impl Renderer {
             contents: cam_data.as_slice(),
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
+        let ambient_light = AmbientLight::default();
+        let shadow_settings = ShadowSettings::default();
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&[LightsUniform::new(
+                ambient_light,
+                &[],
+                [0.0; 3],
+                &shadow_settings,
+            )]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let shadow_maps = ShadowMaps::new(&device);
 
         let cam_sampler_uniform_group =
             Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -336,6 +420,18 @@ impl Renderer {
                             },
                         )),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: light_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(&shadow_maps.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::Sampler(&shadow_maps.sampler),
+                    },
                 ],
             }));
 
@@ -358,6 +454,11 @@ impl Renderer {
             window_size.y as u32,
             sc_desc.format,
         );
+        let frame_uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Frame Layer Uniform"),
+            contents: bytemuck::cast_slice(&[LayerUniform::default()]),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
         let active_decals = vec![];
         Self {
             surface,
@@ -366,18 +467,39 @@ impl Renderer {
             sc_desc,
             swap_chain,
             size,
-            render_pipeline: None,
+            layer_pipelines: HashMap::new(),
             render_3D_pipeline_indexed: None,
+            render_3D_pipelines: HashMap::new(),
+            decal_pipelines: HashMap::new(),
             decal_buffer,
             decals,
             active_decals,
+            layer_blends: vec![],
+            layer_decal_instances: vec![],
+            target_decal_instances: vec![],
+            target_depth_textures: HashMap::new(),
+            decal_vertex_buffer: None,
+            decal_vertex_capacity: 0,
+            decal_stats: DecalStats::default(),
+            cull_stats: CullStats::default(),
+            cull_counter: Cell::new(CullStats::default()),
+            next_uid: 1,
             decal_counter: 0,
+            free_texture_ids: vec![],
             texture_sampler: Some(decal_sampler),
             bind_group_layout: None,
             bind_group: None,
+            decal_bind_group_layout: None,
+            decal_bind_groups: HashMap::new(),
             layer_textures: None,
+            layer_uniforms: HashMap::new(),
+            frame_uniform,
             frame: None,
             camera_buffer,
+            light_buffer,
+            ambient_light,
+            shadow_maps,
+            shadow_settings,
             cam_sampler_uniform_group,
             meshes: vec![],
             game_objects: vec![],
@@ -385,13 +507,17 @@ impl Renderer {
             vertex_buffer,
             indexed_vertex_buffer,
             index_buffer,
+            identity_transform_buffer,
             layer_shader,
+            decal_shader,
             indexed_vert_shader,
             depth_texture,
             frame_texture,
             frame_texture_backbuffer,
             default_texture,
-            default_texture_bind,
+            default_material,
+            white_texture,
+            white_texture_bind: None,
             index_count: 0,
             tri_count: 0,
             camera: Camera::default(),
@@ -402,25 +528,24 @@ impl Renderer {
         self
     }
 
-    pub fn new_vertex_buffer(&self) -> wgpu::Buffer {
-        self.device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Indexed Vertex Buffer"),
-                //This is the maximum number of vertices that can be indexed with a u16
-                contents: bytemuck::cast_slice(
-                    vec![Vertex::default(); VERT_BUFFER_SIZE].as_slice(),
-                ),
-                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-            })
+    pub fn new_vertex_buffer(&self) -> GpuBuffer {
+        GpuBuffer::new(
+            &self.device,
+            "Indexed Vertex Buffer",
+            wgpu::BufferUsage::VERTEX,
+            std::mem::size_of::<Vertex>() as u64,
+            INITIAL_VERTICES,
+        )
     }
 
-    pub fn new_index_buffer(&self) -> wgpu::Buffer {
-        self.device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Index Buffer"),
-                contents: bytemuck::cast_slice(vec![0; INDEX_BUFFER_SIZE].as_slice()),
-                usage: wgpu::BufferUsage::INDEX | wgpu::BufferUsage::COPY_DST,
-            })
+    pub fn new_index_buffer(&self) -> GpuBuffer {
+        GpuBuffer::new(
+            &self.device,
+            "Index Buffer",
+            wgpu::BufferUsage::INDEX,
+            std::mem::size_of::<u32>() as u64,
+            INITIAL_INDICES,
+        )
     }
 
     pub fn setup_layer_pipeline(&mut self) {
@@ -448,6 +573,16 @@ impl Renderer {
                             },
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
                     ],
                 });
         let render_pipeline_layout =
@@ -457,73 +592,67 @@ impl Renderer {
                     bind_group_layouts: &[&bind_group_layout],
                     push_constant_ranges: &[],
                 });
-        let vert_descriptor = wgpu::VertexState {
-            module: &self.layer_shader,
-            entry_point: "vs_main",     // 1.
-            buffers: &[Vertex::desc()], // 2.
-        };
-        let sc_desc = &[wgpu::ColorTargetState {
-            format: self.sc_desc.format,
-            blend: Some(wgpu::BlendState {
-                color: wgpu::BlendComponent {
-                    operation: wgpu::BlendOperation::Add,
-                    src_factor: wgpu::BlendFactor::SrcAlpha,
-                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
-                },
-                alpha: wgpu::BlendComponent {
-                    operation: wgpu::BlendOperation::Add,
-                    src_factor: wgpu::BlendFactor::One,
-                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
-                },
-            }),
-            write_mask: wgpu::ColorWrite::ALL,
-        }];
-        let pipe_line_desc = wgpu::RenderPipelineDescriptor {
-            label: Some("Setup Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: vert_descriptor,
-            fragment: Some(wgpu::FragmentState {
-                // 3.
-                module: &self.layer_shader,
-                entry_point: "fs_main",
-                targets: sc_desc,
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList, // 1.
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw, // 2.
-                cull_mode: Some(wgpu::Face::Back),
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                clamp_depth: false,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                conservative: false,
-            },
-            depth_stencil: None, // 1.
-            multisample: wgpu::MultisampleState::default(),
-        };
+        //One pipeline per blend mode, so each layer can pick how it is composited
+        self.layer_pipelines = LayerBlend::ALL
+            .iter()
+            .map(|blend| {
+                let sc_desc = &[wgpu::ColorTargetState {
+                    format: self.sc_desc.format,
+                    blend: Some(blend.blend_state()),
+                    write_mask: wgpu::ColorWrite::ALL,
+                }];
+                let pipe_line_desc = wgpu::RenderPipelineDescriptor {
+                    label: Some("Setup Pipeline"),
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &self.layer_shader,
+                        entry_point: "vs_main",     // 1.
+                        buffers: &[Vertex::desc()], // 2.
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        // 3.
+                        module: &self.layer_shader,
+                        entry_point: "fs_main",
+                        targets: sc_desc,
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList, // 1.
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw, // 2.
+                        cull_mode: Some(wgpu::Face::Back),
+                        // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+                        clamp_depth: false,
+                        polygon_mode: wgpu::PolygonMode::Fill,
+                        conservative: false,
+                    },
+                    depth_stencil: None, // 1.
+                    multisample: wgpu::MultisampleState::default(),
+                };
+                (*blend, self.device.create_render_pipeline(&pipe_line_desc))
+            })
+            .collect();
         self.bind_group_layout = Some(bind_group_layout);
-        self.render_pipeline = Some(self.device.create_render_pipeline(&pipe_line_desc));
     }
 
-    pub fn setup_3D_pipeline(&mut self) {
+    pub fn setup_decal_pipeline(&mut self) {
         let bind_group_layout =
             self.device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
+                    label: Some("decal bind group layout"),
                     entries: &[
                         wgpu::BindGroupLayoutEntry {
                             binding: 0,
-                            visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
                             },
                             count: None,
                         },
                         wgpu::BindGroupLayoutEntry {
                             binding: 1,
-                            visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
+                            visibility: wgpu::ShaderStage::FRAGMENT,
                             ty: wgpu::BindingType::Sampler {
                                 comparison: false,
                                 filtering: false,
@@ -532,42 +661,203 @@ impl Renderer {
                         },
                     ],
                 });
-        let bind_group_layout_layer = self
+        let pipeline_layout = self
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Decal Pipeline Layout"),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            });
+        //One pipeline per decal mode, batches switch between them as the mode changes
+        self.decal_pipelines = DecalMode::ALL
+            .iter()
+            .map(|mode| {
+                let pipeline = self.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Decal Pipeline"),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &self.decal_shader,
+                        entry_point: "vs_main",
+                        buffers: &[DecalVertex::desc()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &self.decal_shader,
+                        entry_point: "fs_main",
+                        targets: &[wgpu::ColorTargetState {
+                            format: self.sc_desc.format,
+                            blend: Some(mode.blend_state()),
+                            write_mask: wgpu::ColorWrite::ALL,
+                        }],
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw,
+                        //Flipped and rotated decals wind the other way
+                        cull_mode: None,
+                        clamp_depth: false,
+                        polygon_mode: mode.polygon_mode(),
+                        conservative: false,
+                    },
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                });
+                (*mode, pipeline)
+            })
+            .collect();
+        self.decal_bind_group_layout = Some(bind_group_layout);
+        self.white_texture_bind = Some(
+            self.decal_bind_group(&self.white_texture.texture_bundle.as_ref().unwrap().view),
+        );
+    }
+
+    fn decal_bind_group(&self, view: &wgpu::TextureView) -> wgpu::BindGroup {
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(
+                        self.texture_sampler.as_ref().unwrap(),
+                    ),
+                },
+            ],
+            layout: self
+                .decal_bind_group_layout
+                .as_ref()
+                .expect("No Decal Bind Group Layout"),
+            label: Some("decal bind group"),
+        })
+    }
+
+    //Decal textures are bound lazily the first time they are drawn
+    fn update_decal_bind_groups(&mut self) {
+        let ids: Vec<u32> = self
+            .layer_decal_instances
+            .iter()
+            .chain(self.target_decal_instances.iter().map(|(_, _, decals)| decals))
+            .flatten()
+            .filter_map(|di| di.decal.as_ref().map(|d| d.id as u32))
+            .filter(|id| !self.decal_bind_groups.contains_key(id))
+            .collect();
+        for id in ids {
+            let group = self
+                .decals
+                .get(id as usize)
+                .and_then(|tex| tex.texture_bundle.as_ref())
+                .map(|bundle| self.decal_bind_group(&bundle.view));
+            if let Some(group) = group {
+                self.decal_bind_groups.insert(id, group);
+            }
+        }
+    }
+
+    ///Group 0 of the default 3D pipeline: the camera, texture sampler, lights, shadow
+    /// maps and the sampler comparing against them
+    pub fn camera_bind_group_layout<'a>() -> wgpu::BindGroupLayoutDescriptor<'a> {
+        const fn uniform(
+            binding: u32,
+            visibility: wgpu::ShaderStage,
+        ) -> wgpu::BindGroupLayoutEntry {
+            wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }
+        }
+        const ENTRIES: [wgpu::BindGroupLayoutEntry; 5] = [
+            uniform(0, wgpu::ShaderStage::VERTEX_FRAGMENT),
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    comparison: false,
+                    filtering: false,
+                },
+                count: None,
+            },
+            uniform(2, wgpu::ShaderStage::FRAGMENT),
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Depth,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    comparison: true,
+                    filtering: false,
+                },
+                count: None,
+            },
+        ];
+        wgpu::BindGroupLayoutDescriptor {
+            label: Some("Camera Bind Group Layout"),
+            entries: &ENTRIES,
+        }
+    }
+
+    pub fn setup_3D_pipeline(&mut self) {
+        let bind_group_layout = self
             .device
-            .create_bind_group_layout(&super::layer::DrawData::default_bind_group_layout());
+            .create_bind_group_layout(&Self::camera_bind_group_layout());
+        let bind_group_layout_material = self
+            .device
+            .create_bind_group_layout(&MaterialBinding::bind_group_layout());
         let pipeline_layout = self
             .device
             .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: None,
-                bind_group_layouts: &[&bind_group_layout, &bind_group_layout_layer],
+                bind_group_layouts: &[&bind_group_layout, &bind_group_layout_material],
                 push_constant_ranges: &[],
             });
-        self.render_3D_pipeline_indexed = Some(self.device.create_render_pipeline(
-            &wgpu::RenderPipelineDescriptor {
+        self.render_3D_pipelines = MaterialPipeline::ALL
+            .iter()
+            .map(|kind| (*kind, self.create_3D_pipeline(&pipeline_layout, *kind)))
+            .collect();
+        self.render_3D_pipeline_indexed =
+            Some(self.create_3D_pipeline(&pipeline_layout, MaterialPipeline::default()));
+    }
+
+    fn create_3D_pipeline(
+        &self,
+        pipeline_layout: &wgpu::PipelineLayout,
+        kind: MaterialPipeline,
+    ) -> wgpu::RenderPipeline {
+        self.device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
                 label: None,
-                layout: Some(&pipeline_layout),
+                layout: Some(pipeline_layout),
                 vertex: wgpu::VertexState {
                     module: &self.indexed_vert_shader,
                     entry_point: "vs_main",
-                    buffers: &[Vertex::desc()],
+                    buffers: &[Vertex::desc(), ObjectTransform::desc()],
                 },
                 primitive: wgpu::PrimitiveState {
                     topology: wgpu::PrimitiveTopology::TriangleList, // 1.
                     strip_index_format: None,
                     front_face: wgpu::FrontFace::Ccw, // 2.
-                    cull_mode: Some(wgpu::Face::Back),
+                    cull_mode: kind.cull_mode(),
                     // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
                     clamp_depth: false,
                     polygon_mode: wgpu::PolygonMode::Fill,
                     conservative: false,
                 },
-                depth_stencil: Some(wgpu::DepthStencilState {
-                    format: wgpu::TextureFormat::Depth32Float,
-                    depth_write_enabled: true,
-                    depth_compare: wgpu::CompareFunction::Less,
-                    stencil: wgpu::StencilState::default(),
-                    bias: wgpu::DepthBiasState::default(),
-                }),
+                depth_stencil: Some(PipelineData::depth_stencil_state(kind.depth_write())),
                 multisample: Default::default(),
                 fragment: Some(wgpu::FragmentState {
                     module: &self.indexed_vert_shader,
@@ -589,74 +879,98 @@ impl Renderer {
                         write_mask: wgpu::ColorWrite::ALL,
                     }],
                 }),
-            },
-        ));
+            })
+    }
+
+    pub fn update_layer_uniform(&mut self, id: u32, uniform: LayerUniform) {
+        if let Some(buffer) = self.layer_uniforms.get(&id) {
+            self.queue
+                .write_buffer(buffer, 0, bytemuck::cast_slice(&[uniform]));
+        } else {
+            let buffer = self
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Layer Uniform"),
+                    contents: bytemuck::cast_slice(&[uniform]),
+                    usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                });
+            self.layer_uniforms.insert(id, buffer);
+        }
+    }
+
+    fn layer_bind_group(&self, view: &wgpu::TextureView, uniform: &wgpu::Buffer) -> wgpu::BindGroup {
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(
+                        self.texture_sampler.as_ref().unwrap(),
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform.as_entire_binding(),
+                },
+            ],
+            layout: self
+                .bind_group_layout
+                .as_ref()
+                .expect("No Bind Group Layout"),
+            label: Some("bind group"),
+        })
     }
 
     pub fn update_layer_texture_groups(&mut self) {
+        //Every layer needs a uniform before it can be bound
+        for id in self.active_decals.clone() {
+            if !self.layer_uniforms.contains_key(&id) {
+                self.update_layer_uniform(id, LayerUniform::default());
+            }
+        }
         //Put the frame_texture as the first thing drawn
         self.layer_textures = Some(
-            std::iter::once(
-                self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: wgpu::BindingResource::TextureView(
-                                &self.frame_texture.texture_bundle.as_ref().unwrap().view,
-                            ),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: wgpu::BindingResource::Sampler(
-                                self.texture_sampler.as_ref().unwrap(),
-                            ),
-                        },
-                    ],
-                    layout: self
-                        .bind_group_layout
-                        .as_ref()
-                        .expect("No Bind Group Layout"),
-                    label: Some("bind group"),
-                }),
-            )
-            .chain(
-                self.active_decals
-                    .iter()
-                    .map(|k| &self.decals[*k as usize])
-                    .filter_map(|tex| {
-                        if let Some(bundle) = tex.texture_bundle.as_ref() {
-                            Some(
-                                self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                                    entries: &[
-                                        wgpu::BindGroupEntry {
-                                            binding: 0,
-                                            resource: wgpu::BindingResource::TextureView(
-                                                &bundle.view,
-                                            ),
-                                        },
-                                        wgpu::BindGroupEntry {
-                                            binding: 1,
-                                            resource: wgpu::BindingResource::Sampler(
-                                                self.texture_sampler.as_ref().unwrap(),
-                                            ),
-                                        },
-                                    ],
-                                    layout: self
-                                        .bind_group_layout
-                                        .as_ref()
-                                        .expect("No Bind Group Layout"),
-                                    label: Some("bind group"),
-                                }),
-                            )
-                        } else {
-                            None
-                        }
-                    }),
-            )
+            std::iter::once(self.layer_bind_group(
+                &self.frame_texture.texture_bundle.as_ref().unwrap().view,
+                &self.frame_uniform,
+            ))
+            .chain(self.active_decals.iter().map(|k| {
+                let bundle = self.decals[*k as usize]
+                    .texture_bundle
+                    .as_ref()
+                    .expect("Layer texture was never created");
+                self.layer_bind_group(&bundle.view, &self.layer_uniforms[k])
+            }))
             .collect(),
         );
     }
 
+    //Layers are given in the order they should be composited, back to front,
+    // along with the decals to draw on top of each one this frame
+    pub fn set_active_layers(&mut self, layers: Vec<(u32, LayerBlend, Vec<DecalInstance>)>) {
+        let mut ids = Vec::with_capacity(layers.len());
+        self.layer_blends.clear();
+        self.layer_decal_instances.clear();
+        for (id, blend, decals) in layers {
+            ids.push(id);
+            self.layer_blends.push(blend);
+            self.layer_decal_instances.push(decals);
+        }
+        if self.active_decals != ids {
+            self.active_decals = ids;
+            self.update_layer_texture_groups();
+        }
+    }
+
+    //Each target gets its decals and the scale that maps screen sized decal positions
+    // onto the target, so decals use the target's own pixel coordinates
+    pub fn set_render_targets(&mut self, targets: Vec<(u32, Vf2d, Vec<DecalInstance>)>) {
+        self.target_decal_instances = targets;
+    }
+
     pub fn create_shader_module(&self, shader: &str) -> wgpu::ShaderModule {
         self.device
             .create_shader_module(&wgpu::ShaderModuleDescriptor {
@@ -678,6 +992,7 @@ impl Renderer {
     }
 
     pub fn new_frame(&mut self) {
+        self.cull_stats = self.cull_counter.take();
         match self.frame.as_ref() {
             None => {
                 self.frame = Some(match self.swap_chain.get_current_frame() {
@@ -753,6 +1068,7 @@ impl Renderer {
 
     pub fn add_game_object(&mut self, mut go: GameObject) {
         go.set_buffer_indices(0);
+        go.assign_uids(&mut self.next_uid);
         self.game_objects.insert(self.game_objects.len(), go);
 
         // let mut verts: Vec<geometry::Vertex> = vec![];
@@ -768,11 +1084,32 @@ impl Renderer {
     pub fn add_game_objects(&mut self, mut gos: Vec<GameObject>) {
         for go in gos.iter_mut() {
             go.set_buffer_indices(0);
+            go.assign_uids(&mut self.next_uid);
         }
 
         self.game_objects.extend(gos);
     }
 
+    ///Removes a game object and frees its space in every DrawData it was uploaded to
+    pub fn remove_game_object(&mut self, index: usize) -> Option<GameObject> {
+        if index >= self.game_objects.len() {
+            return None;
+        }
+        for draw_data in self.draw_data.iter_mut() {
+            //Each DrawData only holds the objects in its mask
+            let mask = draw_data.mask;
+            if !self.game_objects[index].layer_mask.contains(mask) {
+                continue;
+            }
+            let position = self.game_objects[..index]
+                .iter()
+                .filter(|go| go.layer_mask.contains(mask))
+                .count();
+            draw_data.remove_object(position);
+        }
+        Some(self.game_objects.remove(index))
+    }
+
     pub fn add_object_textures(&mut self) {
         todo!()
     }
@@ -807,6 +1144,25 @@ impl Renderer {
             .flatten()
             .collect()
     }
+    ///The nearest game object in the mask a world space ray hits, testing every triangle
+    /// of the objects whose bounds it passes through
+    pub fn raycast(&self, ray: &Ray, mask: Mask) -> Option<PickHit> {
+        self.game_objects
+            .iter()
+            .enumerate()
+            .filter(|(_, go)| go.in_layer_mask(mask))
+            .filter_map(|(index, go)| {
+                go.raycast(ray).map(|(uid, hit)| PickHit {
+                    uid,
+                    index,
+                    distance: hit.distance,
+                    point: hit.point,
+                    normal: hit.normal,
+                })
+            })
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
     pub fn get_objects_by_layer(&self, mask: Mask) -> Vec<&GameObject> {
         self.game_objects
             .iter()
@@ -816,20 +1172,23 @@ impl Renderer {
 
     ///This is the actual 3D drawing part.
     /// Takes a pipeline, and a range of indices and creates an end to end encoder and processes it.
+    /// The transform is a buffer of ObjectTransforms and the slot in it, None draws untransformed.
+    /// The material bind group is a MaterialBinding's, None uses the default material.
     pub fn draw<'a>(
         &'a self,
         indices: std::ops::Range<u32>,
-        tex_bind: Option<&'a wgpu::BindGroup>,
+        material_bind: Option<&'a wgpu::BindGroup>,
         vertex_buffer: Option<&'a wgpu::Buffer>,
         index_buffer: Option<&'a wgpu::Buffer>,
+        transform: Option<(&'a wgpu::Buffer, u32)>,
         render_pass: &mut wgpu::RenderPass<'a>,
     ) {
         render_pass.set_bind_group(0, self.cam_sampler_uniform_group.as_ref().unwrap(), &[]);
 
-        if let Some(bg) = tex_bind.as_ref() {
+        if let Some(bg) = material_bind.as_ref() {
             render_pass.set_bind_group(1, bg, &[]);
         } else {
-            render_pass.set_bind_group(1, &self.default_texture_bind, &[]);
+            render_pass.set_bind_group(1, &self.default_material.bind_group, &[]);
         }
 
         if let Some(v_buffer) = vertex_buffer {
@@ -843,9 +1202,42 @@ impl Renderer {
         } else {
             render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         }
+
+        //Slicing to the object keeps the instance range at 0, which every backend supports
+        let (transforms, slot) = transform.unwrap_or((&self.identity_transform_buffer, 0));
+        let size = std::mem::size_of::<ObjectTransform>() as wgpu::BufferAddress;
+        let start = slot as wgpu::BufferAddress * size;
+        render_pass.set_vertex_buffer(1, transforms.slice(start..start + size));
         render_pass.draw_indexed(indices, 0, 0..1);
     }
 
+    ///Sends the lights of every game object, and the ambient light, to the default 3D shader.
+    /// Directional shadows are centered on the camera.
+    pub fn update_lights(&self, camera: &Camera) -> LightsUniform {
+        let game_objects: Vec<&GameObject> = self.game_objects.iter().collect();
+        let lights = LightsUniform::new(
+            self.ambient_light,
+            &game_objects,
+            camera.mat.position,
+            &self.shadow_settings,
+        );
+        self.queue
+            .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[lights]));
+        lights
+    }
+
+    ///Renders the shadow maps of the lights given shadows by update_lights
+    pub fn draw_shadows(&self, lights: &LightsUniform, encoder: &mut wgpu::CommandEncoder) {
+        let mask = self.shadow_settings.mask;
+        self.shadow_maps.render(
+            &self.queue,
+            encoder,
+            lights,
+            self.draw_data.iter().filter(|d| d.mask.contains(mask)),
+            self.shadow_settings.no_shadow,
+        );
+    }
+
     pub fn draw_mask(
         &self,
         camera: &Camera,
@@ -854,6 +1246,59 @@ impl Renderer {
         clear_color: Option<wgpu::Color>,
         clear_depth: bool,
         pipeline: Option<&wgpu::RenderPipeline>,
+    ) {
+        self.draw_mask_with_depth(
+            camera,
+            mask,
+            target,
+            &self.depth_texture,
+            clear_color,
+            clear_depth,
+            pipeline,
+        );
+    }
+
+    ///Draws a mask into a render target, with a depth buffer the size of the target
+    pub fn draw_mask_to_target(
+        &mut self,
+        camera: &Camera,
+        mask: Mask,
+        target_id: u32,
+        clear_color: Option<wgpu::Color>,
+        clear_depth: bool,
+    ) {
+        let (width, height) = match self.decals.get(target_id as usize) {
+            Some(tex) if tex.texture_bundle.is_some() => (tex.data.width, tex.data.height),
+            _ => return,
+        };
+        let depth_fits = matches!(
+            self.target_depth_textures.get(&target_id),
+            Some(depth) if depth.data.width == width && depth.data.height == height
+        );
+        if !depth_fits {
+            let depth = Texture::new(&self.device, width, height, wgpu::TextureFormat::Depth32Float);
+            self.target_depth_textures.insert(target_id, depth);
+        }
+        self.draw_mask_with_depth(
+            camera,
+            mask,
+            &self.decals[target_id as usize],
+            &self.target_depth_textures[&target_id],
+            clear_color,
+            clear_depth,
+            None,
+        );
+    }
+
+    fn draw_mask_with_depth(
+        &self,
+        camera: &Camera,
+        mask: Mask,
+        target: &Texture,
+        depth: &Texture,
+        clear_color: Option<wgpu::Color>,
+        clear_depth: bool,
+        pipeline: Option<&wgpu::RenderPipeline>,
     ) {
         if self.meshes.is_empty() && self.game_objects.is_empty() {
             return;
@@ -870,11 +1315,7 @@ impl Renderer {
         cam_data.extend_from_slice(&y_bytes);
         self.queue
             .write_buffer(&self.camera_buffer, 0, cam_data.as_slice());
-        let pipeline = if let Some(pipeline) = pipeline.as_ref() {
-            pipeline
-        } else {
-            self.render_3D_pipeline_indexed.as_ref().unwrap()
-        };
+        let lights = self.update_lights(camera);
         let color_attachment = if let Some(color) = clear_color {
             [wgpu::RenderPassColorAttachment {
                 view: &target.texture_bundle.as_ref().unwrap().view,
@@ -904,12 +1345,13 @@ impl Renderer {
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
+        self.draw_shadows(&lights, &mut encoder);
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &color_attachment,
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.texture_bundle.as_ref().unwrap().view,
+                    view: &depth.texture_bundle.as_ref().unwrap().view,
                     depth_ops: Some(wgpu::Operations {
                         load: depth_ops,
                         store: true,
@@ -917,24 +1359,126 @@ impl Renderer {
                     stencil_ops: None,
                 }),
             });
-            render_pass.set_pipeline(pipeline);
-            //Draw all layers that contain the mask
-            for layer_mask in self.draw_data.iter().filter(|d| d.mask.contains(mask)) {
-                for (range, bg) in &layer_mask.texture_groups {
-                    self.draw(
-                        range.clone(),
-                        bg.as_ref(),
-                        Some(&layer_mask.vertex_buffer),
-                        Some(&layer_mask.index_buffer),
-                        &mut render_pass,
-                    );
+            if let Some(pipeline) = pipeline {
+                render_pass.set_pipeline(pipeline);
+            }
+            //Culled with the matrix the shader gets, a camera without one culls nothing
+            let frustum = Frustum::from_matrix(camera.mat.view_proj.into());
+            let visible = |bounds: &Option<Aabb>| match bounds {
+                Some(bounds) => frustum.intersects_aabb(bounds),
+                //Nothing to draw anyway
+                None => true,
+            };
+            let mut stats = self.cull_counter.get();
+            //Draw all layers that contain the mask, blended materials after everything
+            // else so what's behind them is already there
+            let mut current = None;
+            for blended in [false, true].iter() {
+                for layer_mask in self.draw_data.iter().filter(|d| d.mask.contains(mask)) {
+                    for object in &layer_mask.objects {
+                        if !*blended {
+                            stats.objects += 1;
+                        }
+                        if !visible(&object.total_bounds) {
+                            if !*blended {
+                                stats.culled_objects += 1;
+                                stats.draws += object.material_groups.len() as u32;
+                                stats.culled_draws += object.material_groups.len() as u32;
+                            }
+                            continue;
+                        }
+                        for (range, binding, node) in &object.material_groups {
+                            let binding = binding.as_deref().unwrap_or(&self.default_material);
+                            let kind = MaterialPipeline::from(binding.material.as_ref());
+                            if kind.blend != *blended {
+                                continue;
+                            }
+                            stats.draws += 1;
+                            if !visible(&object.bounds[*node as usize]) {
+                                stats.culled_draws += 1;
+                                continue;
+                            }
+                            //A custom pipeline is used for every material
+                            if pipeline.is_none() && current != Some(kind) {
+                                render_pass.set_pipeline(&self.render_3D_pipelines[&kind]);
+                                current = Some(kind);
+                            }
+                            self.draw(
+                                range.clone(),
+                                Some(&binding.bind_group),
+                                Some(&layer_mask.vertices.buffer),
+                                Some(&layer_mask.indices.buffer),
+                                Some((
+                                    &layer_mask.transforms.buffer,
+                                    (object.transforms.start + *node as u64) as u32,
+                                )),
+                                &mut render_pass,
+                            );
+                        }
+                    }
                 }
             }
+            self.cull_counter.set(stats);
         }
         self.queue.submit(std::iter::once(encoder.finish()));
     }
 
     pub fn draw_layers(&mut self, encoder: &mut wgpu::CommandEncoder) -> Rcode {
+        self.update_decal_bind_groups();
+        let mut stats = DecalStats::default();
+        //Every decal, on targets and layers, goes into one buffer
+        let mut decal_vertices: Vec<DecalVertex> = vec![];
+        let target_batches: Vec<(u32, Vec<DecalBatch>)> = self
+            .target_decal_instances
+            .iter()
+            .map(|(id, scale, instances)| {
+                let start = decal_vertices.len();
+                let batches = Self::batch_decals(instances, &mut decal_vertices, &mut stats);
+                for vertex in decal_vertices[start..].iter_mut() {
+                    let [x, y] = vertex.position;
+                    vertex.position = [(x + 1.0) * scale.x - 1.0, 1.0 - (1.0 - y) * scale.y];
+                }
+                (*id, batches)
+            })
+            .collect();
+        let decal_batches: Vec<Vec<DecalBatch>> = self
+            .layer_decal_instances
+            .iter()
+            .map(|instances| Self::batch_decals(instances, &mut decal_vertices, &mut stats))
+            .collect();
+        self.upload_decal_vertices(&decal_vertices);
+
+        //Targets are drawn first, so layers showing them get this frame's decals
+        for (id, batches) in target_batches.iter().filter(|(_, b)| !b.is_empty()) {
+            let view = match self
+                .decals
+                .get(*id as usize)
+                .and_then(|tex| tex.texture_bundle.as_ref())
+            {
+                Some(bundle) => &bundle.view,
+                None => continue,
+            };
+            //A target can't sample itself while it is being drawn to
+            let batches: Vec<DecalBatch> = batches
+                .iter()
+                .filter(|(_, decal_id, _)| *decal_id != Some(*id))
+                .cloned()
+                .collect();
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Target Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            self.draw_decal_batches(&mut render_pass, &batches, &mut stats);
+        }
+
         let frame = self.get_frame().expect("Couldn't get frame");
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -949,20 +1493,111 @@ impl Renderer {
                 }],
                 depth_stencil_attachment: None,
             });
-            if let Some(render_pipeline) = self.render_pipeline.as_ref() {
-                render_pass.set_pipeline(render_pipeline);
-            }
             if let Some(textures) = self.layer_textures.as_ref() {
-                for tex_group in textures {
+                //The first group is the frame texture, the rest follow active_decals
+                let blends = std::iter::once(&LayerBlend::Alpha).chain(self.layer_blends.iter());
+                for (i, (tex_group, blend)) in textures.iter().zip(blends).enumerate() {
+                    if let Some(render_pipeline) = self.layer_pipelines.get(blend) {
+                        render_pass.set_pipeline(render_pipeline);
+                    }
                     render_pass.set_bind_group(0, tex_group, &[]);
                     render_pass.set_vertex_buffer(0, self.decal_buffer.slice(..));
                     render_pass.draw(0..6, 0..1);
+                    stats.draw_calls += 1;
+
+                    //A layer's decals go straight on top of its image
+                    if let Some(batches) = i.checked_sub(1).and_then(|i| decal_batches.get(i)) {
+                        self.draw_decal_batches(&mut render_pass, batches, &mut stats);
+                    }
                 }
             }
         }
+        //Decals only last a frame, dropping them here also lets unused textures be freed
+        self.layer_decal_instances.clear();
+        self.target_decal_instances.clear();
+        self.decal_stats = stats;
         Rcode::Ok
     }
 
+    //Consecutive decals that share a texture and mode are merged into one batch
+    // so they only cost a single draw
+    fn batch_decals(
+        instances: &[DecalInstance],
+        vertices: &mut Vec<DecalVertex>,
+        stats: &mut DecalStats,
+    ) -> Vec<DecalBatch> {
+        let mut batches: Vec<DecalBatch> = vec![];
+        for di in instances {
+            let start = vertices.len() as u32;
+            di.append_vertices(vertices);
+            let end = vertices.len() as u32;
+            let id = di.decal.as_ref().map(|d| d.id as u32);
+            match batches.last_mut() {
+                Some((range, last_id, last_mode)) if *last_id == id && *last_mode == di.mode => {
+                    range.end = end
+                }
+                _ => batches.push((start..end, id, di.mode)),
+            }
+        }
+        stats.decals += instances.len() as u32;
+        stats.batches += batches.len() as u32;
+        batches
+    }
+
+    fn draw_decal_batches<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        batches: &[DecalBatch],
+        stats: &mut DecalStats,
+    ) {
+        let buffer = match self.decal_vertex_buffer.as_ref() {
+            Some(buffer) if !batches.is_empty() => buffer,
+            _ => return,
+        };
+        render_pass.set_vertex_buffer(0, buffer.slice(..));
+        let mut current_mode = None;
+        for (range, id, mode) in batches {
+            let pipeline = match self.decal_pipelines.get(mode) {
+                Some(pipeline) => pipeline,
+                None => continue,
+            };
+            if current_mode != Some(*mode) {
+                render_pass.set_pipeline(pipeline);
+                current_mode = Some(*mode);
+            }
+            let group = match id {
+                Some(id) => self.decal_bind_groups.get(id),
+                None => self.white_texture_bind.as_ref(),
+            };
+            if let Some(group) = group {
+                render_pass.set_bind_group(0, group, &[]);
+                render_pass.draw(range.clone(), 0..1);
+                stats.draw_calls += 1;
+            }
+        }
+    }
+
+    //The decal buffer only grows, so steady scenes upload into the same buffer every frame
+    fn upload_decal_vertices(&mut self, vertices: &[DecalVertex]) {
+        if vertices.is_empty() {
+            return;
+        }
+        if vertices.len() > self.decal_vertex_capacity {
+            let capacity = vertices.len().next_power_of_two();
+            self.decal_vertex_buffer = Some(self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Decal Vertex Buffer"),
+                size: (capacity * std::mem::size_of::<DecalVertex>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }));
+            self.decal_vertex_capacity = capacity;
+        }
+        if let Some(buffer) = self.decal_vertex_buffer.as_ref() {
+            self.queue
+                .write_buffer(buffer, 0, bytemuck::cast_slice(vertices));
+        }
+    }
+
     pub fn clear_buffer(&mut self, p: Pixel, depth: bool) {
         let frame = self
             .swap_chain
@@ -1005,17 +1640,173 @@ impl Renderer {
         let format = wgpu::TextureFormat::Rgba8Unorm;
 
         let texture = Texture::new(&self.device, width, height, format);
+        self.insert_texture(texture)
+    }
 
+    //Render targets use the swap chain format, so the decal and 3D pipelines can draw to them
+    pub fn create_target_texture(&mut self, width: u32, height: u32) -> i32 {
+        let texture = Texture::new(&self.device, width, height, self.sc_desc.format);
+        self.insert_texture(texture)
+    }
+
+    fn insert_texture(&mut self, texture: Texture) -> i32 {
+        //Reuse the id of a deleted texture before growing the list
+        if let Some(id) = self.free_texture_ids.pop() {
+            self.decals[id as usize] = texture;
+            return id as i32;
+        }
         self.decals.insert(self.decal_counter as usize, texture);
-        self.active_decals
-            .insert(self.active_decals.len(), self.decal_counter as u32);
         self.decal_counter += 1;
         //return the newly created layer's id
         self.decal_counter - 1
     }
 
-    pub fn delete_texture(mut id: &mut u32) -> u32 {
-        0
+    pub fn delete_texture(id: u32) {
+        DELETED_TEXTURES
+            .lock()
+            .expect("Deleted texture list is poisoned")
+            .push(id);
+    }
+
+    pub fn free_deleted_textures(&mut self) {
+        let ids: Vec<u32> = DELETED_TEXTURES
+            .lock()
+            .expect("Deleted texture list is poisoned")
+            .drain(..)
+            .collect();
+        for id in ids {
+            self.free_texture(id);
+        }
+    }
+
+    pub fn free_texture(&mut self, id: u32) {
+        let bundle = self
+            .decals
+            .get_mut(id as usize)
+            .and_then(|tex| tex.texture_bundle.take());
+        if let Some(bundle) = bundle {
+            bundle.texture.destroy();
+            self.decals[id as usize].data = Sprite::default();
+            self.layer_uniforms.remove(&id);
+            self.decal_bind_groups.remove(&id);
+            self.target_depth_textures.remove(&id);
+            //The old bind group still points at the texture, so it has to be rebuilt
+            if let Some(i) = self.active_decals.iter().position(|k| *k == id) {
+                self.active_decals.remove(i);
+                if i < self.layer_blends.len() {
+                    self.layer_blends.remove(i);
+                }
+                if i < self.layer_decal_instances.len() {
+                    self.layer_decal_instances.remove(i);
+                }
+                self.update_layer_texture_groups();
+            }
+            self.free_texture_ids.push(id);
+        }
+    }
+
+    pub fn clear_texture(&self, id: u32, p: Pixel) {
+        let bundle = match self
+            .decals
+            .get(id as usize)
+            .and_then(|tex| tex.texture_bundle.as_ref())
+        {
+            Some(bundle) => bundle,
+            None => return,
+        };
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Clear Encoder"),
+            });
+        {
+            let _render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Clear Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &bundle.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: p.r() as f64 / 255.0,
+                            g: p.g() as f64 / 255.0,
+                            b: p.b() as f64 / 255.0,
+                            a: p.a() as f64 / 255.0,
+                        }),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+        }
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    ///Copies a texture back into a Sprite. Anything submitted before this is included,
+    /// decals for the current frame are not drawn until the frame ends.
+    pub async fn read_texture(&self, id: u32) -> Option<Sprite> {
+        let tex = self.decals.get(id as usize)?;
+        let bundle = tex.texture_bundle.as_ref()?;
+        let (width, height) = (tex.data.width, tex.data.height);
+        //Rows in a copy have to be aligned to 256 bytes
+        let unpadded_row = width * 4;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_row = unpadded_row + (align - unpadded_row % align) % align;
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Read Back Buffer"),
+            size: (padded_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Read Back Encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &bundle.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: core::num::NonZeroU32::new(padded_row),
+                    rows_per_image: core::num::NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        mapping.await.ok()?;
+        let swap_red_blue = matches!(
+            bundle.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        );
+        let data = slice.get_mapped_range();
+        let mut sprite = Sprite::new(width, height);
+        for (y, row) in data.chunks(padded_row as usize).enumerate() {
+            for (x, px) in row[..unpadded_row as usize].chunks(4).enumerate() {
+                let p = if swap_red_blue {
+                    Pixel::rgba(px[2], px[1], px[0], px[3])
+                } else {
+                    Pixel::rgba(px[0], px[1], px[2], px[3])
+                };
+                sprite.set_pixel(x as u32, y as u32, p);
+            }
+        }
+        drop(data);
+        buffer.unmap();
+        Some(sprite)
     }
 
     pub fn apply_texture(id: u32) {
@@ -1105,6 +1896,7 @@ impl Renderer {
     }
 
     pub fn update_layer_draw_data(&mut self, mask: Mask) {
+        self.update_object_transforms(mask);
         for draw_data in &mut self
             .draw_data
             .iter_mut()
@@ -1121,6 +1913,39 @@ impl Renderer {
             );
         }
     }
+    //Brings the cached world matrices of the mask's objects up to date, so uploading,
+    // lights and picking read them instead of composing every matrix again
+    fn update_object_transforms(&mut self, mask: Mask) {
+        for go in self
+            .game_objects
+            .iter_mut()
+            .filter(|go| go.layer_mask.contains(mask))
+        {
+            go.update_transforms();
+        }
+    }
+
+    ///Sends the current transforms of the mask's objects without touching their meshes.
+    /// This is all that's needed each frame for objects that only move.
+    pub fn update_layer_transforms(&mut self, mask: Mask) {
+        self.update_object_transforms(mask);
+        for draw_data in &mut self
+            .draw_data
+            .iter_mut()
+            .filter(|dd| dd.mask.contains(mask))
+        {
+            draw_data.update_transforms(
+                &self.device,
+                &self.queue,
+                self.game_objects
+                    .iter()
+                    .filter(|go| go.layer_mask.contains(mask))
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+            );
+        }
+    }
+
     pub fn draw_decal_quad(decal: &mut DecalInstance) {
         //I'm wrapping this whole thing in unsafe because
         // it accesses Union values
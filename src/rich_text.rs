@@ -0,0 +1,235 @@
+use super::{
+    font::{Font, Glyph},
+    pixel::Pixel,
+    util::Vf2d,
+};
+
+//What corrupted characters turn into, the ones the font doesn't have are skipped
+pub const DEFAULT_CORRUPTION_GLYPHS: &str = "#$%&*+<=>?@^{|}~\\/";
+
+///One character of parsed markup. Colour is None where no colour tag applies,
+/// so the colour passed in when drawing is used.
+#[derive(Clone, Copy, Debug)]
+pub struct RichChar {
+    pub c: char,
+    //Position in the visible text, tags don't count
+    pub index: usize,
+    pub col: Option<Pixel>,
+    pub scale: f32,
+}
+
+//Per character offset and tint, see RichText::with_effect
+pub type CharEffect<'a> = Box<dyn Fn(&RichChar) -> (Vf2d, Pixel) + 'a>;
+
+///Text with inline `[color=rrggbb]`, `[color=rrggbbaa]` and `[scale=2]` tags, each closed
+/// by `[/color]` and `[/scale]`. Tags nest, `[[` is a literal `[`.
+pub struct RichText<'a> {
+    pub chars: Vec<RichChar>,
+    //0 leaves the text alone, 1 turns every visible character into a glyph
+    pub corruption: f32,
+    pub corruption_seed: u32,
+    pub corruption_glyphs: Vec<char>,
+    effect: Option<CharEffect<'a>>,
+}
+
+///Where a character of rich text ends up, before the draw position and scale are applied
+#[derive(Clone, Copy, Debug)]
+pub struct RichGlyph {
+    pub pos: Vf2d,
+    pub glyph: Glyph,
+    pub col: Option<Pixel>,
+    pub tint: Pixel,
+    pub scale: f32,
+}
+
+impl<'a> RichText<'a> {
+    pub fn parse(markup: &str) -> Self {
+        let mut chars = vec![];
+        let mut colors: Vec<Pixel> = vec![];
+        let mut scales: Vec<f32> = vec![];
+        let mut rest = markup;
+        while let Some(c) = rest.chars().next() {
+            if c == '[' && !rest.starts_with("[[") {
+                if let Some(end) = rest.find(']') {
+                    let tag = &rest[1..end];
+                    let known = match tag.split_once('=') {
+                        Some(("color", value)) => parse_color(value).map(|p| colors.push(p)),
+                        Some(("scale", value)) => value.parse().ok().map(|s| scales.push(s)),
+                        None if tag == "/color" => colors.pop().map(|_| ()),
+                        None if tag == "/scale" => scales.pop().map(|_| ()),
+                        _ => None,
+                    };
+                    //Anything that isn't a tag we know is drawn as it was written
+                    if known.is_some() {
+                        rest = &rest[end + 1..];
+                        continue;
+                    }
+                }
+            }
+            chars.push(RichChar {
+                c,
+                index: chars.len(),
+                col: colors.last().copied(),
+                scale: scales.last().copied().unwrap_or(1.0),
+            });
+            rest = match rest.strip_prefix("[[") {
+                Some(after) => after,
+                None => &rest[c.len_utf8()..],
+            };
+        }
+        Self {
+            chars,
+            corruption: 0.0,
+            corruption_seed: 0,
+            corruption_glyphs: DEFAULT_CORRUPTION_GLYPHS.chars().collect(),
+            effect: None,
+        }
+    }
+
+    ///Called for every character as it is laid out, returns an offset in font pixels
+    /// and a tint multiplied into its colour. See wave and shake.
+    pub fn with_effect(mut self, effect: impl Fn(&RichChar) -> (Vf2d, Pixel) + 'a) -> Self {
+        self.effect = Some(Box::new(effect));
+        self
+    }
+
+    ///Swaps characters for glyphs. The same seed always picks the same characters,
+    /// and raising the intensity only ever adds to them, so text decays steadily.
+    pub fn with_corruption(mut self, intensity: f32, seed: u32) -> Self {
+        self.corruption = intensity.clamp(0.0, 1.0);
+        self.corruption_seed = seed;
+        self
+    }
+
+    pub fn text(&self) -> String {
+        self.chars.iter().map(|rc| rc.c).collect()
+    }
+
+    fn corrupt(&self, rc: &RichChar, font: &Font) -> char {
+        if self.corruption <= 0.0 || rc.c.is_whitespace() {
+            return rc.c;
+        }
+        let roll = hash(self.corruption_seed, rc.index as u32);
+        if (roll % 10_000) as f32 >= self.corruption * 10_000.0 {
+            return rc.c;
+        }
+        let pool: Vec<char> = self
+            .corruption_glyphs
+            .iter()
+            .copied()
+            .filter(|c| font.glyphs.contains_key(c))
+            .collect();
+        if pool.is_empty() {
+            return rc.c;
+        }
+        pool[hash(roll, rc.index as u32) as usize % pool.len()]
+    }
+
+    ///Same rules as Font::layout, except every character carries its own scale.
+    /// A line is as tall as its biggest character.
+    pub fn layout(&self, font: &Font) -> Vec<RichGlyph> {
+        let mut placed = vec![];
+        let mut pen = Vf2d::new(0.0, 0.0);
+        let mut line_scale: f32 = 0.0;
+        let mut prev = None;
+        for rc in &self.chars {
+            match rc.c {
+                '\n' => {
+                    //Empty lines take the scale of their newline
+                    if line_scale == 0.0 {
+                        line_scale = rc.scale;
+                    }
+                    pen.x = 0.0;
+                    pen.y += font.line_height * line_scale;
+                    line_scale = 0.0;
+                    prev = None;
+                }
+                '\t' => {
+                    let stop = font.tab_width() * rc.scale;
+                    if stop > 0.0 {
+                        pen.x = ((pen.x / stop).floor() + 1.0) * stop;
+                    }
+                    prev = None;
+                }
+                _ => {
+                    let c = self.corrupt(rc, font);
+                    if let Some(glyph) = font.glyph(c) {
+                        if let Some(p) = prev {
+                            pen.x += font.kerning(p, c) * rc.scale;
+                        }
+                        let (offset, tint) = match &self.effect {
+                            Some(effect) => effect(rc),
+                            None => (Vf2d::new(0.0, 0.0), Pixel::WHITE),
+                        };
+                        placed.push(RichGlyph {
+                            pos: pen + (glyph.offset + offset) * rc.scale,
+                            glyph: *glyph,
+                            col: rc.col,
+                            tint,
+                            scale: rc.scale,
+                        });
+                        pen.x += glyph.advance * rc.scale;
+                        line_scale = line_scale.max(rc.scale);
+                        prev = Some(c);
+                    }
+                }
+            }
+        }
+        placed
+    }
+}
+
+///Characters bob up and down in a wave that travels along the text
+pub fn wave(time: f32, amplitude: f32, speed: f32) -> impl Fn(&RichChar) -> (Vf2d, Pixel) {
+    move |rc| {
+        let y = (time * speed + rc.index as f32 * 0.5).sin() * amplitude;
+        (Vf2d::new(0.0, y), Pixel::WHITE)
+    }
+}
+
+///Characters jitter randomly, changing position rate times a second
+pub fn shake(time: f32, amplitude: f32, rate: f32) -> impl Fn(&RichChar) -> (Vf2d, Pixel) {
+    move |rc| {
+        let step = (time * rate) as u32;
+        let h = hash(step, rc.index as u32);
+        let unit = |n: u32| (n % 2001) as f32 / 1000.0 - 1.0;
+        let offset = Vf2d::new(unit(h), unit(h >> 11)) * amplitude;
+        (offset, Pixel::WHITE)
+    }
+}
+
+//Multiplies two colours channel by channel, Pixel's own Mul blends instead
+pub fn modulate(a: Pixel, b: Pixel) -> Pixel {
+    let mul = |x: u8, y: u8| ((x as u32 * y as u32) / 255) as u8;
+    Pixel::rgba(
+        mul(a.r(), b.r()),
+        mul(a.g(), b.g()),
+        mul(a.b(), b.b()),
+        mul(a.a(), b.a()),
+    )
+}
+
+//Small integer hash so effects are repeatable without pulling in an rng
+fn hash(seed: u32, n: u32) -> u32 {
+    let mut h = seed ^ n.wrapping_mul(0x9E37_79B9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85EB_CA6B);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xC2B2_AE35);
+    h ^ (h >> 16)
+}
+
+fn parse_color(value: &str) -> Option<Pixel> {
+    let value = value.trim_start_matches('#');
+    let n = u32::from_str_radix(value, 16).ok()?;
+    match value.len() {
+        6 => Some(Pixel::rgb((n >> 16) as u8, (n >> 8) as u8, n as u8)),
+        8 => Some(Pixel::rgba(
+            (n >> 24) as u8,
+            (n >> 16) as u8,
+            (n >> 8) as u8,
+            n as u8,
+        )),
+        _ => None,
+    }
+}
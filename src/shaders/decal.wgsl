@@ -0,0 +1,33 @@
+struct VertexInput{
+    [[location(0)]] position: vec2<f32>;
+    [[location(1)]] tex_coord: vec3<f32>;
+    [[location(2)]] tint: vec4<f32>;
+};
+
+struct VertexOutput{
+    [[builtin(position)]] pos: vec4<f32>;
+    [[location(0)]] tex_coord: vec3<f32>;
+    [[location(1)]] tint: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var r_texture: texture_2d<f32>;
+[[group(0), binding(1)]]
+var r_sampler: sampler;
+
+[[stage(vertex)]]
+fn vs_main(in: VertexInput) -> VertexOutput
+{
+    var v_out: VertexOutput;
+    v_out.pos = vec4<f32>(in.position, 0.0, 1.0);
+    v_out.tex_coord = in.tex_coord;
+    v_out.tint = in.tint;
+    return v_out;
+}
+
+[[stage(fragment)]]
+fn fs_main( in: VertexOutput) -> [[location(0)]] vec4<f32>{
+    //The uvs are premultiplied by w so warped decals get perspective correct sampling
+    let uv = in.tex_coord.xy / in.tex_coord.z;
+    return textureSample(r_texture, r_sampler, uv) * in.tint;
+}
@@ -10,6 +10,9 @@ struct Uniforms{
     camera_transform: mat4x4<f32>;
     camera_inverse_transform: mat4x4<f32>;
     camera_position: vec3<f32>;
+    //1 for orthographic and oblique projections, whose view rays all point along view_dir
+    parallel: f32;
+    view_dir: vec4<f32>;
     screen_width: f32;
     screen_height: f32;
 };
@@ -7,11 +7,26 @@ struct VertexInput {
     [[location(3)]] v_color: vec4<f32>;
 };
 
+//One per object, vertices arrive in the object's local space
+struct ObjectInput {
+    [[location(4)]] model_0: vec4<f32>;
+    [[location(5)]] model_1: vec4<f32>;
+    [[location(6)]] model_2: vec4<f32>;
+    [[location(7)]] model_3: vec4<f32>;
+    [[location(8)]] normal_0: vec4<f32>;
+    [[location(9)]] normal_1: vec4<f32>;
+    [[location(10)]] normal_2: vec4<f32>;
+    [[location(11)]] normal_3: vec4<f32>;
+};
+
 [[block]]
 struct Uniforms{
     camera_transform: mat4x4<f32>;
     camera_inverse_transform: mat4x4<f32>;
     camera_position: vec3<f32>;
+    //1 for orthographic and oblique projections, whose view rays all point along view_dir
+    parallel: f32;
+    view_dir: vec4<f32>;
     screen_width: f32;
     screen_height: f32;
 };
@@ -21,8 +36,57 @@ var uniforms: Uniforms;
 [[group(0), binding(1)]]
 var r_sampler: sampler;
 
+//See LightUniform
+struct Light{
+    //w is the kind, 0 directional, 1 point, 2 spot
+    position: vec4<f32>;
+    //w is the range, 0 for endless
+    direction: vec4<f32>;
+    color: vec4<f32>;
+    //Cosines of the inner and outer spot angles
+    cone: vec4<f32>;
+    //Shadow map layer (negative for none) and bias
+    shadow: vec4<f32>;
+    shadow_transform: mat4x4<f32>;
+};
+
+[[block]]
+struct Lights{
+    ambient: vec4<f32>;
+    count: vec4<u32>;
+    lights: [[stride(144)]] array<Light, 16>;
+};
+
+[[group(0), binding(2)]]
+var lights: Lights;
+[[group(0), binding(3)]]
+var shadow_maps: texture_depth_2d_array;
+[[group(0), binding(4)]]
+var shadow_sampler: sampler_comparison;
+
+//What Material gives the shader, see MaterialUniform
+[[block]]
+struct MaterialUniform{
+    base_color: vec4<f32>;
+    emissive: vec4<f32>;
+    //Metallic, roughness, normal scale (0 without a normal map), occlusion strength
+    params: vec4<f32>;
+    //Mode (0 opaque, 1 mask, 2 blend) and cutoff
+    alpha: vec4<f32>;
+};
+
 [[group(1), binding(0)]]
-var r_texture: texture_2d<f32>;
+var material: MaterialUniform;
+[[group(1), binding(1)]]
+var base_color_texture: texture_2d<f32>;
+[[group(1), binding(2)]]
+var metallic_roughness_texture: texture_2d<f32>;
+[[group(1), binding(3)]]
+var normal_texture: texture_2d<f32>;
+[[group(1), binding(4)]]
+var emissive_texture: texture_2d<f32>;
+[[group(1), binding(5)]]
+var occlusion_texture: texture_2d<f32>;
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
@@ -34,13 +98,17 @@ struct VertexOutput {
 
 [[stage(vertex)]]
 fn vs_main(
-           in_vertex: VertexInput
+           in_vertex: VertexInput,
+           object: ObjectInput,
            ) -> VertexOutput {
     var out: VertexOutput;
 
-    out.clip_position = uniforms.camera_transform * vec4<f32>(in_vertex.v_position, 1.0);
-    out.real_position = vec4<f32>(in_vertex.v_position, 1.0);
-    out.vertex_normal = in_vertex.v_normal;
+    let model = mat4x4<f32>(object.model_0, object.model_1, object.model_2, object.model_3);
+    let normal = mat4x4<f32>(object.normal_0, object.normal_1, object.normal_2, object.normal_3);
+    let world_position = model * vec4<f32>(in_vertex.v_position, 1.0);
+    out.clip_position = uniforms.camera_transform * world_position;
+    out.real_position = world_position;
+    out.vertex_normal = (normal * vec4<f32>(in_vertex.v_normal, 0.0)).xyz;
     out.tex_coords = in_vertex.v_tex_coords;
 
     //For some reason the colors in WebGL are not normalized to 1.0, so this fixes that
@@ -53,14 +121,178 @@ fn vs_main(
     return out;
 }
 
+//Builds a tangent frame from screen space derivatives, so meshes don't need tangents
+fn perturb_normal(n: vec3<f32>, p: vec3<f32>, uv: vec2<f32>, mapped: vec3<f32>) -> vec3<f32> {
+    let dp1 = dpdx(p);
+    let dp2 = dpdy(p);
+    let duv1 = dpdx(uv);
+    let duv2 = dpdy(uv);
+    let dp2perp = cross(dp2, n);
+    let dp1perp = cross(n, dp1);
+    let t = dp2perp * duv1.x + dp1perp * duv2.x;
+    let b = dp2perp * duv1.y + dp1perp * duv2.y;
+    let scale = 1.0 / sqrt(max(max(dot(t, t), dot(b, b)), 0.00000001));
+    return normalize(mat3x3<f32>(t * scale, b * scale, n) * mapped);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / max(3.14159265 * d * d, 0.0001);
+}
+
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let gv = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let gl = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return gv * gl;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+//Cook-Torrance for one light, l points from the surface to the light
+fn shade(n: vec3<f32>, v: vec3<f32>, l: vec3<f32>, radiance: vec3<f32>, albedo: vec3<f32>, metallic: f32, roughness: f32) -> vec3<f32> {
+    let h = normalize(v + l);
+    let n_dot_v = max(dot(n, v), 0.0001);
+    let n_dot_l = max(dot(n, l), 0.0);
+    let n_dot_h = max(dot(n, h), 0.0);
+    let f0 = mix(vec3<f32>(0.04), albedo, vec3<f32>(metallic));
+    let f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+    let specular = f * distribution_ggx(n_dot_h, roughness) * geometry_smith(n_dot_v, n_dot_l, roughness)
+        / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+    let k_d = (vec3<f32>(1.0) - f) * (1.0 - metallic);
+    return (k_d * albedo / 3.14159265 + specular) * radiance * n_dot_l;
+}
+
+//Smoothly reaches 0 at the range instead of cutting off
+fn attenuation(dist: f32, range: f32) -> f32 {
+    let falloff = 1.0 / max(dist * dist, 0.0001);
+    if(range <= 0.0){
+        return falloff;
+    }
+    let ratio = dist / range;
+    let window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return falloff * window * window;
+}
+
+//How lit a point is by a light with a shadow map, 3x3 samples so edges are soft
+fn shadow_factor(light: Light, world_position: vec3<f32>) -> f32 {
+    let clip = light.shadow_transform * vec4<f32>(world_position, 1.0);
+    let ndc = clip.xyz / clip.w;
+    //Outside what the shadow map covers counts as lit
+    if(clip.w <= 0.0 || ndc.x < -1.0 || ndc.x > 1.0 || ndc.y < -1.0 || ndc.y > 1.0 || ndc.z > 1.0){
+        return 1.0;
+    }
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+    let depth = ndc.z - light.shadow.y;
+    let layer = i32(light.shadow.x);
+    let texel = 1.0 / f32(textureDimensions(shadow_maps).x);
+    var lit: f32 = 0.0;
+    var y: i32 = -1;
+    loop {
+        if(y > 1){
+            break;
+        }
+        var x: i32 = -1;
+        loop {
+            if(x > 1){
+                break;
+            }
+            let offset = vec2<f32>(f32(x), f32(y)) * texel;
+            lit = lit + textureSampleCompareLevel(shadow_maps, shadow_sampler, uv + offset, layer, depth);
+            continuing {
+                x = x + 1;
+            }
+        }
+        continuing {
+            y = y + 1;
+        }
+    }
+    return lit / 9.0;
+}
+
 [[stage(fragment)]]
 fn fs_main(
            in: VertexOutput,
-           //[[builtin(position)]] frag_position: vec4<f32>
+           [[builtin(front_facing)]] front_facing: bool,
            ) -> [[location(0)]] vec4<f32>{
-    let ls = normalize(uniforms.camera_position - in.real_position.xyz);
-    let angle = max(dot(ls, normalize(in.vertex_normal)), 0.0);
-    let color = textureSample(r_texture, r_sampler, in.tex_coords.xy);
-    // + (in.vertex_color.xyz * angle)
-    return color;// + (in.vertex_color * 0.05);
+    let uv = in.tex_coords.xy;
+    let base_color = textureSample(base_color_texture, r_sampler, uv) * material.base_color;
+    //glTF packs roughness in green and metallic in blue
+    let metallic_roughness = textureSample(metallic_roughness_texture, r_sampler, uv);
+    let metallic = clamp(material.params.x * metallic_roughness.b, 0.0, 1.0);
+    let roughness = clamp(material.params.y * metallic_roughness.g, 0.04, 1.0);
+
+    var n: vec3<f32> = normalize(in.vertex_normal);
+    //Double sided materials light their back faces like front faces
+    if(!front_facing){
+        n = -n;
+    }
+    //Derivatives have to be taken outside of branches
+    let mapped = textureSample(normal_texture, r_sampler, uv).xyz * 2.0 - vec3<f32>(1.0);
+    let perturbed = perturb_normal(n, in.real_position.xyz, uv, mapped * vec3<f32>(material.params.z, material.params.z, 1.0));
+    if(material.params.z > 0.0){
+        n = perturbed;
+    }
+
+    var v: vec3<f32> = normalize(uniforms.camera_position - in.real_position.xyz);
+    if(uniforms.parallel > 0.5){
+        v = -uniforms.view_dir.xyz;
+    }
+    let albedo = base_color.rgb;
+    var color: vec3<f32> = vec3<f32>(0.0);
+    var i: u32 = 0u;
+    loop {
+        if(i >= lights.count.x){
+            break;
+        }
+        let light = lights.lights[i];
+        var l: vec3<f32> = -light.direction.xyz;
+        var radiance: vec3<f32> = light.color.rgb;
+        if(light.position.w > 0.5){
+            let to_light = light.position.xyz - in.real_position.xyz;
+            l = normalize(to_light);
+            radiance = radiance * attenuation(length(to_light), light.direction.w);
+            if(light.position.w > 1.5){
+                let cos_angle = dot(-l, light.direction.xyz);
+                let spot = clamp((cos_angle - light.cone.y) / max(light.cone.x - light.cone.y, 0.0001), 0.0, 1.0);
+                radiance = radiance * spot * spot;
+            }
+        }
+        if(light.shadow.x >= 0.0){
+            radiance = radiance * shadow_factor(light, in.real_position.xyz);
+        }
+        color = color + shade(n, v, l, radiance, albedo, metallic, roughness);
+        continuing {
+            i = i + 1u;
+        }
+    }
+    //Scenes without any lights get one following the camera, so they aren't just ambient
+    if(lights.count.x == 0u){
+        //A radiance of pi makes a lambertian surface facing the light show its albedo
+        color = shade(n, v, v, vec3<f32>(3.14159265), albedo, metallic, roughness);
+    }
+
+    let occlusion = textureSample(occlusion_texture, r_sampler, uv).r;
+    //Occlusion only darkens the ambient light, direct light is already shadowed by angle
+    let ambient = lights.ambient.rgb * albedo * (1.0 + material.params.w * (occlusion - 1.0));
+    color = color + ambient;
+
+    let emissive = textureSample(emissive_texture, r_sampler, uv).rgb * material.emissive.rgb;
+    color = color + emissive;
+
+    //Discarding comes last, samples after it wouldn't be in uniform control flow
+    var alpha: f32 = base_color.a;
+    if(material.alpha.x < 0.5){
+        alpha = 1.0;
+    } elseif(material.alpha.x < 1.5){
+        if(alpha < material.alpha.y){
+            discard;
+        }
+        alpha = 1.0;
+    }
+    return vec4<f32>(color, alpha);
 }
@@ -3,10 +3,19 @@ struct VertexOutput{
     [[location(0)]] tex_coord: vec2<f32>;
 };
 
+[[block]]
+struct LayerUniforms{
+    offset: vec2<f32>;
+    scale: vec2<f32>;
+    tint: vec4<f32>;
+};
+
 [[group(0), binding(0)]]
 var r_texture: texture_2d<f32>;
 [[group(0), binding(1)]]
 var r_sampler: sampler;
+[[group(0), binding(2)]]
+var<uniform> layer: LayerUniforms;
 
 [[stage(vertex)]]
 fn vs_main(
@@ -16,11 +25,11 @@ fn vs_main(
 {
     var v_out: VertexOutput;
     v_out.pos = vec4<f32>(in_position, 1.0);
-    v_out.tex_coord = in_tex_coord;
+    v_out.tex_coord = in_tex_coord * layer.scale + layer.offset;
     return v_out;
 }
 
 [[stage(fragment)]]
 fn fs_main( in: VertexOutput) -> [[location(0)]] vec4<f32>{
-    return textureSample(r_texture, r_sampler, in.tex_coord);
+    return textureSample(r_texture, r_sampler, in.tex_coord) * layer.tint;
 }
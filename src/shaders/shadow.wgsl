@@ -0,0 +1,29 @@
+// Depth only, renders the scene from a light into its layer of the shadow maps
+
+struct VertexInput {
+    [[location(0)]] v_position: vec3<f32>;
+};
+
+struct ObjectInput {
+    [[location(4)]] model_0: vec4<f32>;
+    [[location(5)]] model_1: vec4<f32>;
+    [[location(6)]] model_2: vec4<f32>;
+    [[location(7)]] model_3: vec4<f32>;
+};
+
+[[block]]
+struct ShadowUniform{
+    light_transform: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var shadow: ShadowUniform;
+
+[[stage(vertex)]]
+fn vs_main(
+           in_vertex: VertexInput,
+           object: ObjectInput,
+           ) -> [[builtin(position)]] vec4<f32> {
+    let model = mat4x4<f32>(object.model_0, object.model_1, object.model_2, object.model_3);
+    return shadow.light_transform * model * vec4<f32>(in_vertex.v_position, 1.0);
+}
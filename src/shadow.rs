@@ -0,0 +1,285 @@
+use super::{
+    camera::OPENGL_TO_WGPU_MATRIX,
+    geometry::{ObjectTransform, Vertex},
+    layer::{DrawData, Mask, PipelineData},
+    light::{Light, LightKind, LightsUniform},
+    material::AlphaMode,
+};
+use cgmath::InnerSpace;
+
+//Layers in the shadow map array, so at most this many lights cast shadows at once
+pub const MAX_SHADOWS: usize = 4;
+pub const SHADOW_MAP_SIZE: u32 = 1024;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ShadowSettings {
+    //Only draw data that draw_mask would draw for this mask casts shadows, so GUI and
+    // effects layers can stay out of them
+    pub mask: Mask,
+    //Objects whose own layer mask shares a bit with this cast no shadow, even in draw
+    // data that does
+    pub no_shadow: Mask,
+    //How far around the camera directional lights cast shadows, and how far spot lights
+    // without a range do
+    pub distance: f32,
+    //Pulls the depth being compared towards the light, too little makes surfaces shadow
+    // themselves in stripes, too much detaches shadows from their casters
+    pub bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            mask: Mask::D3,
+            no_shadow: Mask::empty(),
+            distance: 30.0,
+            bias: 0.002,
+        }
+    }
+}
+
+///The world to shadow map transform for a light, None for lights that can't cast shadows.
+/// Directional lights cover settings.distance around focus, usually the camera position.
+pub fn light_transform(
+    light: &Light,
+    world: cgmath::Matrix4<f32>,
+    focus: [f32; 3],
+    settings: &ShadowSettings,
+) -> Option<cgmath::Matrix4<f32>> {
+    let dir = (world * cgmath::Vector4::new(0.0, 0.0, 1.0, 0.0)).truncate();
+    if dir.magnitude2() == 0.0 {
+        return None;
+    }
+    let dir = dir.normalize();
+    //Any up works as long as it isn't along the direction
+    let up = if dir.y.abs() > 0.99 {
+        cgmath::Vector3::unit_x()
+    } else {
+        cgmath::Vector3::unit_y()
+    };
+    match light.kind {
+        LightKind::Directional => {
+            let d = settings.distance;
+            let focus = cgmath::Point3::new(focus[0], focus[1], focus[2]);
+            let view = cgmath::Matrix4::look_at_dir(focus - dir * d, dir, up);
+            let proj = cgmath::ortho(-d, d, -d, d, 0.0, 2.0 * d);
+            Some(OPENGL_TO_WGPU_MATRIX * proj * view)
+        }
+        LightKind::Spot { outer_angle, .. } => {
+            let far = if light.range > 0.0 {
+                light.range
+            } else {
+                settings.distance
+            };
+            let eye = cgmath::Point3::new(world.w.x, world.w.y, world.w.z);
+            let view = cgmath::Matrix4::look_at_dir(eye, dir, up);
+            let fov = (2.0 * outer_angle).min(3.0);
+            let proj = cgmath::perspective(cgmath::Rad(fov), 1.0, 0.05, far);
+            Some(OPENGL_TO_WGPU_MATRIX * proj * view)
+        }
+        LightKind::Point => None,
+    }
+}
+
+///A Depth32Float texture array with a layer for each shadow casting light, and what's
+/// needed to render into it
+pub struct ShadowMaps {
+    pub texture: wgpu::Texture,
+    //All the layers, sampled by the default 3D shader
+    pub view: wgpu::TextureView,
+    //One per layer, rendered into
+    pub layers: Vec<wgpu::TextureView>,
+    //Compares instead of filtering, the shader does PCF with it
+    pub sampler: wgpu::Sampler,
+    pub pipeline: wgpu::RenderPipeline,
+    //Each layer is rendered with its own light transform, so it needs its own buffer
+    uniforms: Vec<wgpu::Buffer>,
+    bind_groups: Vec<wgpu::BindGroup>,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Shadow Maps"),
+            size: wgpu::Extent3d {
+                width: SHADOW_MAP_SIZE,
+                height: SHADOW_MAP_SIZE,
+                depth_or_array_layers: MAX_SHADOWS as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Depth32Float,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::RENDER_ATTACHMENT,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layers = (0..MAX_SHADOWS as u32)
+            .map(|layer| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Shadow Bind Group Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let uniforms: Vec<wgpu::Buffer> = (0..MAX_SHADOWS)
+            .map(|_| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Shadow Uniform"),
+                    size: std::mem::size_of::<[[f32; 4]; 4]>() as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+        let bind_groups = uniforms
+            .iter()
+            .map(|uniform| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Shadow Bind Group"),
+                    layout: &bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform.as_entire_binding(),
+                    }],
+                })
+            })
+            .collect();
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("shadow_shader"),
+            flags: wgpu::ShaderFlags::all(),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/shadow.wgsl").into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Shadow Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex::desc(), ObjectTransform::desc()],
+            },
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                //Both sides cast, so open and double sided meshes still shadow
+                cull_mode: None,
+                clamp_depth: false,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                //Slope scaled bias, on top of the shader's constant one
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+                ..PipelineData::depth_stencil_state(true)
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            fragment: None,
+        });
+
+        Self {
+            texture,
+            view,
+            layers,
+            sampler,
+            pipeline,
+            uniforms,
+            bind_groups,
+        }
+    }
+
+    ///Renders the draw data into the layer of each light given a shadow by LightsUniform::new.
+    /// Objects in the no_shadow mask and blended materials are left out.
+    pub fn render<'a>(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        lights: &LightsUniform,
+        draw_data: impl Iterator<Item = &'a DrawData> + Clone,
+        no_shadow: Mask,
+    ) {
+        let count = lights.count[0] as usize;
+        for light in lights.lights[..count].iter().filter(|l| l.shadow[0] >= 0.0) {
+            let layer = light.shadow[0] as usize;
+            queue.write_buffer(
+                &self.uniforms[layer],
+                0,
+                bytemuck::cast_slice(&[light.shadow_transform]),
+            );
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.layers[layer],
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.bind_groups[layer], &[]);
+            let size = std::mem::size_of::<ObjectTransform>() as wgpu::BufferAddress;
+            for data in draw_data.clone() {
+                render_pass.set_vertex_buffer(0, data.vertices.buffer.slice(..));
+                render_pass
+                    .set_index_buffer(data.indices.buffer.slice(..), wgpu::IndexFormat::Uint32);
+                for object in &data.objects {
+                    for (range, binding, node) in &object.material_groups {
+                        if object.masks[*node as usize].intersects(no_shadow) {
+                            continue;
+                        }
+                        //Blended surfaces are see-through, so they don't block the light
+                        if let Some(binding) = binding {
+                            if binding.material.alpha_mode == AlphaMode::Blend {
+                                continue;
+                            }
+                        }
+                        let start = (object.transforms.start + *node as u64) * size;
+                        render_pass.set_vertex_buffer(
+                            1,
+                            data.transforms.buffer.slice(start..start + size),
+                        );
+                        render_pass.draw_indexed(range.clone(), 0, 0..1);
+                    }
+                }
+            }
+        }
+    }
+}
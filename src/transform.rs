@@ -10,7 +10,7 @@ pub struct Transform4 {
     pub scale: Vector4,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Transform3 {
     pub rot: Rotor3,
     pub pos: Vector3,
@@ -31,11 +31,80 @@ impl Transform3 {
         )
     }
 
+    ///Takes points from this transform's space to its parent's, scaling then rotating then
+    /// moving them the same way Mesh::apply_transform does. Unlike to_transform_matrix this
+    /// isn't a view matrix, so objects can multiply their parents' in front of it.
+    #[rustfmt::skip]
+    pub fn to_model_matrix(self) -> cgmath::Matrix4<f32> {
+        let v0 = (Vector3::new(1.0, 0.0, 0.0) * self.rot) * self.scale.x;
+        let v1 = (Vector3::new(0.0, 1.0, 0.0) * self.rot) * self.scale.y;
+        let v2 = (Vector3::new(0.0, 0.0, 1.0) * self.rot) * self.scale.z;
+        cgmath::Matrix4::new(
+            v0.x, v0.y, v0.z, 0.0,
+            v1.x, v1.y, v1.z, 0.0,
+            v2.x, v2.y, v2.z, 0.0,
+            self.pos.x, self.pos.y, self.pos.z, 1.0,
+        )
+    }
+
+    ///Splits a model matrix back into position, rotation and scale. A non uniformly scaled
+    /// parent with a rotated child makes a shear that a Transform3 can't hold, it is lost.
+    pub fn from_model_matrix(m: cgmath::Matrix4<f32>) -> Self {
+        let column = |i: usize| Vector3::new(m[i][0], m[i][1], m[i][2]);
+        let (x, y, z) = (column(0), column(1), column(2));
+        let mut scale = Vector3::new(x.length(), y.length(), z.length());
+        //A mirrored basis is kept as a negative x scale
+        if x.cross(y).dot(z) < 0.0 {
+            scale.x = -scale.x;
+        }
+        let unit = |v: Vector3, s: f32| if s != 0.0 { v / s } else { v };
+        let (x, y, z) = (unit(x, scale.x), unit(y, scale.y), unit(z, scale.z));
+        let rot = rotor_from_basis([[x.x, x.y, x.z], [y.x, y.y, y.z], [z.x, z.y, z.z]]);
+        Self {
+            pos: column(3),
+            rot: rot.normal(),
+            scale,
+        }
+    }
+
     pub fn forward(&self) -> Vector3 {
         self.rot * Vector3::forward()
     }
 }
 
+//Inverts Rotor3::rotate_vector for the images of the x, y and z axes, picking whichever
+// component is largest to divide by so it stays accurate for any angle
+fn rotor_from_basis(m: [[f32; 3]; 3]) -> Rotor3 {
+    let trace = m[0][0] + m[1][1] + m[2][2];
+    let (a, b12, b13, b23);
+    if trace > 0.0 {
+        a = (trace + 1.0).sqrt() * 0.5;
+        let f = 0.25 / a;
+        b12 = (m[0][1] - m[1][0]) * f;
+        b13 = (m[0][2] - m[2][0]) * f;
+        b23 = (m[1][2] - m[2][1]) * f;
+    } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+        b23 = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 0.5;
+        let f = 0.25 / b23;
+        a = (m[1][2] - m[2][1]) * f;
+        b12 = (m[0][2] + m[2][0]) * f;
+        b13 = -(m[0][1] + m[1][0]) * f;
+    } else if m[1][1] > m[2][2] {
+        b13 = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 0.5;
+        let f = 0.25 / b13;
+        a = (m[0][2] - m[2][0]) * f;
+        b12 = -(m[1][2] + m[2][1]) * f;
+        b23 = -(m[0][1] + m[1][0]) * f;
+    } else {
+        b12 = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 0.5;
+        let f = 0.25 / b12;
+        a = (m[0][1] - m[1][0]) * f;
+        b13 = -(m[1][2] + m[2][1]) * f;
+        b23 = (m[0][2] + m[2][0]) * f;
+    }
+    (a, b12, b13, b23).into()
+}
+
 impl Default for Transform3 {
     fn default() -> Self {
         Self {
@@ -46,39 +115,10 @@ impl Default for Transform3 {
     }
 }
 
-#[rustfmt::skip]
-impl From<[[f32; 4]; 4]> for Transform3{
+//Column major, the layout glTF and cgmath use
+impl From<[[f32; 4]; 4]> for Transform3 {
     fn from(m_a: [[f32; 4]; 4]) -> Self {
-        let pos = Vector3{
-            x: m_a[3][0],
-            y: m_a[3][1],
-            z: m_a[3][2],
-        };
-        let scale = Vector3{
-            x: m_a[0][0].signum() * (m_a[0][0] * m_a[0][0]
-                                     + m_a[0][1] * m_a[0][1]
-                                     + m_a[0][2] * m_a[0][2]).sqrt(),
-
-            y: m_a[1][1].signum() * (m_a[1][0] * m_a[1][0]
-                                     + m_a[1][1] * m_a[1][1]
-                                     + m_a[1][2] * m_a[1][2]).sqrt(),
-
-            z: m_a[2][2].signum() * (m_a[2][0] * m_a[2][0]
-                                    + m_a[2][1] * m_a[2][1]
-                                    + m_a[2][2] * m_a[2][2]).sqrt(),
-        };
-        let m_b = cgmath::Matrix3::new(
-            (m_a[0][0] / scale.x).round_to(5), (m_a[0][1] / scale.x).round_to(5), (m_a[0][2] / scale.x).round_to(5),
-            (m_a[1][0] / scale.y).round_to(5), (m_a[1][1] / scale.y).round_to(5), (m_a[1][2] / scale.y).round_to(5),
-            (m_a[2][0] / scale.z).round_to(5), (m_a[2][1] / scale.z).round_to(5), (m_a[2][2] / scale.z).round_to(5)
-        );
-        let rot: Rotor3 = m_b.into();
-
-        Transform3{
-            pos,
-            rot,
-            scale
-        }
+        Self::from_model_matrix(m_a.into())
     }
 }
 
@@ -91,3 +131,55 @@ impl From<(Vector3, Rotor3)> for Transform3 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::SquareMatrix;
+
+    fn assert_close(a: cgmath::Matrix4<f32>, b: cgmath::Matrix4<f32>) {
+        for column in 0..4 {
+            for row in 0..4 {
+                let diff = (a[column][row] - b[column][row]).abs();
+                assert!(diff < 1e-5, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    fn sample() -> Transform3 {
+        Transform3 {
+            pos: Vector3::new(1.0, -2.0, 3.5),
+            rot: Rotor3::from_angle_and_axis(1.1, (0.3, 0.5, -0.8).into()),
+            scale: Vector3::new(2.0, 0.5, 1.5),
+        }
+    }
+
+    #[test]
+    fn model_matrix_round_trips() {
+        let m = sample().to_model_matrix();
+        assert_close(Transform3::from_model_matrix(m).to_model_matrix(), m);
+    }
+
+    #[test]
+    fn mirrored_model_matrix_round_trips() {
+        let mut t = sample();
+        t.scale.y = -t.scale.y;
+        let m = t.to_model_matrix();
+        assert_close(Transform3::from_model_matrix(m).to_model_matrix(), m);
+    }
+
+    #[test]
+    fn child_is_recovered_from_its_world_matrix() {
+        //Uniformly scaled, so the rotated child picks up no shear
+        let parent = Transform3 {
+            pos: Vector3::new(-4.0, 0.5, 2.0),
+            rot: Rotor3::from_angle_and_axis(-0.7, (1.0, 0.0, 0.2).into()),
+            scale: Vector3::new(3.0, 3.0, 3.0),
+        };
+        let child = sample();
+        let world = parent.to_model_matrix() * child.to_model_matrix();
+        let inverse = parent.to_model_matrix().invert().unwrap();
+        let local = Transform3::from_model_matrix(inverse * world);
+        assert_close(local.to_model_matrix(), child.to_model_matrix());
+    }
+}
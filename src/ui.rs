@@ -0,0 +1,940 @@
+use super::{
+    decal::SmallD,
+    engine::OLCEngine,
+    font::Font,
+    olc::OlcData,
+    pixel::Pixel,
+    platform::Key,
+    util::{Mouse, Vf2d},
+};
+use std::sync::Arc;
+
+//How far one notch of the mouse wheel scrolls a ScrollView
+const SCROLL_STEP: f32 = 16.0;
+//Space between a button's text and its edge
+const BUTTON_PADDING: f32 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WidgetId(pub usize);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dimension {
+    //As big as the content
+    Auto,
+    Px(f32),
+    //Of the parent's size inside its padding
+    Percent(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlexDirection {
+    Row,
+    Column,
+}
+
+//Where children sit across the direction they are laid out in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Start,
+    Center,
+    End,
+    Stretch,
+}
+
+//How leftover space along the direction is shared out, once no child wants to grow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Justify {
+    Start,
+    Center,
+    End,
+    SpaceBetween,
+    SpaceAround,
+}
+
+///A cut down flexbox. Sizes are in game pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct Style {
+    pub direction: FlexDirection,
+    pub width: Dimension,
+    pub height: Dimension,
+    //Share of the parent's free space this widget takes, 0 keeps its own size
+    pub grow: f32,
+    pub padding: f32,
+    pub margin: f32,
+    //Space between children
+    pub gap: f32,
+    pub align_items: Align,
+    pub justify: Justify,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            direction: FlexDirection::Column,
+            width: Dimension::Auto,
+            height: Dimension::Auto,
+            grow: 0.0,
+            padding: 0.0,
+            margin: 0.0,
+            gap: 0.0,
+            align_items: Align::Start,
+            justify: Justify::Start,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ButtonSprites {
+    pub normal: Arc<SmallD>,
+    pub hover: Option<Arc<SmallD>>,
+    pub pressed: Option<Arc<SmallD>>,
+}
+
+#[derive(Clone)]
+pub enum Widget {
+    Panel {
+        color: Pixel,
+        border: Option<Pixel>,
+    },
+    Label {
+        text: String,
+        color: Pixel,
+        scale: f32,
+    },
+    Image {
+        decal: Arc<SmallD>,
+        tint: Pixel,
+    },
+    Button {
+        text: String,
+        //Drawn stretched to the button instead of the plain colours
+        sprites: Option<ButtonSprites>,
+    },
+    List {
+        items: Vec<String>,
+        selected: Option<usize>,
+        row_height: f32,
+    },
+    //Children past its edges are scrolled into view with the mouse wheel or focus.
+    // Decals can't be clipped, so children are only hidden once fully outside it.
+    ScrollView {
+        offset: f32,
+    },
+}
+
+impl Widget {
+    pub fn panel(color: Pixel) -> Self {
+        Widget::Panel {
+            color,
+            border: None,
+        }
+    }
+
+    pub fn label(text: &str) -> Self {
+        Widget::Label {
+            text: text.to_string(),
+            color: Pixel::WHITE,
+            scale: 1.0,
+        }
+    }
+
+    pub fn image(decal: Arc<SmallD>) -> Self {
+        Widget::Image {
+            decal,
+            tint: Pixel::WHITE,
+        }
+    }
+
+    pub fn button(text: &str) -> Self {
+        Widget::Button {
+            text: text.to_string(),
+            sprites: None,
+        }
+    }
+
+    pub fn list(items: Vec<String>) -> Self {
+        Widget::List {
+            items,
+            selected: None,
+            row_height: 10.0,
+        }
+    }
+
+    pub fn scroll_view() -> Self {
+        Widget::ScrollView { offset: 0.0 }
+    }
+
+    fn focusable(&self) -> bool {
+        matches!(self, Widget::Button { .. } | Widget::List { .. })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UiEvent {
+    Clicked(WidgetId),
+    //A list's selection changed, or the selected row was activated again
+    Selected(WidgetId, usize),
+    Focused(WidgetId),
+}
+
+///Focus movement, for feeding in a gamepad. Keyboard arrows, tab, enter and space
+/// already go through this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UiNav {
+    Previous,
+    Next,
+    Activate,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct UiColors {
+    pub button: Pixel,
+    pub button_hover: Pixel,
+    pub button_pressed: Pixel,
+    pub text: Pixel,
+    pub focus: Pixel,
+    pub selection: Pixel,
+}
+
+impl Default for UiColors {
+    fn default() -> Self {
+        Self {
+            button: Pixel::rgb(60, 60, 80),
+            button_hover: Pixel::rgb(85, 85, 115),
+            button_pressed: Pixel::rgb(40, 40, 55),
+            text: Pixel::WHITE,
+            focus: Pixel::rgb(255, 200, 60),
+            selection: Pixel::rgb(70, 110, 170),
+        }
+    }
+}
+
+pub struct UiNode {
+    pub widget: Widget,
+    pub style: Style,
+    pub visible: bool,
+    pub parent: Option<WidgetId>,
+    pub children: Vec<WidgetId>,
+    //Set by layout, in game pixels
+    pub pos: Vf2d,
+    pub size: Vf2d,
+    //How much room the children took, scroll views use it to limit scrolling
+    content: Vf2d,
+}
+
+///A retained widget tree for menus. Build it once, then each frame call update with the
+/// engine to lay it out for the current resolution and handle input, and draw to draw it
+/// with decals onto the draw target.
+pub struct Ui {
+    nodes: Vec<Option<UiNode>>,
+    root: WidgetId,
+    pub colors: UiColors,
+    //None uses the engine's built in font
+    pub font: Option<Font>,
+    focused: Option<WidgetId>,
+    hovered: Option<WidgetId>,
+    pressed: Option<WidgetId>,
+}
+
+impl Default for Ui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ui {
+    pub fn new() -> Self {
+        let root = UiNode {
+            widget: Widget::Panel {
+                color: Pixel::BLANK,
+                border: None,
+            },
+            style: Style::default(),
+            visible: true,
+            parent: None,
+            children: vec![],
+            pos: Vf2d::new(0.0, 0.0),
+            size: Vf2d::new(0.0, 0.0),
+            content: Vf2d::new(0.0, 0.0),
+        };
+        Self {
+            nodes: vec![Some(root)],
+            root: WidgetId(0),
+            colors: UiColors::default(),
+            font: None,
+            focused: None,
+            hovered: None,
+            pressed: None,
+        }
+    }
+
+    ///The invisible node that fills the screen, everything else hangs off it
+    pub fn root(&self) -> WidgetId {
+        self.root
+    }
+
+    pub fn add(&mut self, parent: WidgetId, widget: Widget, style: Style) -> WidgetId {
+        let id = WidgetId(self.nodes.len());
+        self.nodes.push(Some(UiNode {
+            widget,
+            style,
+            visible: true,
+            parent: Some(parent),
+            children: vec![],
+            pos: Vf2d::new(0.0, 0.0),
+            size: Vf2d::new(0.0, 0.0),
+            content: Vf2d::new(0.0, 0.0),
+        }));
+        if let Some(parent) = self.node_mut(parent) {
+            parent.children.push(id);
+        }
+        id
+    }
+
+    ///Removes the widget and everything under it
+    pub fn remove(&mut self, id: WidgetId) {
+        if id == self.root {
+            return;
+        }
+        if let Some(parent) = self.node(id).and_then(|node| node.parent) {
+            if let Some(parent) = self.node_mut(parent) {
+                parent.children.retain(|child| *child != id);
+            }
+        }
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if let Some(node) = self.nodes.get_mut(id.0).and_then(|node| node.take()) {
+                stack.extend(node.children);
+            }
+            for state in [&mut self.focused, &mut self.hovered, &mut self.pressed] {
+                if *state == Some(id) {
+                    *state = None;
+                }
+            }
+        }
+    }
+
+    pub fn node(&self, id: WidgetId) -> Option<&UiNode> {
+        self.nodes.get(id.0).and_then(|node| node.as_ref())
+    }
+
+    pub fn node_mut(&mut self, id: WidgetId) -> Option<&mut UiNode> {
+        self.nodes.get_mut(id.0).and_then(|node| node.as_mut())
+    }
+
+    pub fn widget_mut(&mut self, id: WidgetId) -> Option<&mut Widget> {
+        self.node_mut(id).map(|node| &mut node.widget)
+    }
+
+    pub fn set_visible(&mut self, id: WidgetId, visible: bool) {
+        if let Some(node) = self.node_mut(id) {
+            node.visible = visible;
+        }
+    }
+
+    pub fn focused(&self) -> Option<WidgetId> {
+        self.focused
+    }
+
+    pub fn set_focus(&mut self, id: Option<WidgetId>) {
+        self.focused = id;
+        if let Some(id) = id {
+            self.scroll_into_view(id);
+        }
+    }
+
+    ///Lays the tree out to fill width x height
+    pub fn layout(&mut self, width: f32, height: f32, font: &Font) {
+        let root = self.root;
+        if let Some(node) = self.node_mut(root) {
+            node.pos = Vf2d::new(0.0, 0.0);
+            node.size = Vf2d::new(width, height);
+        }
+        self.layout_children(root, font);
+    }
+
+    fn layout_children(&mut self, id: WidgetId, font: &Font) {
+        let (pos, size, style, children, scroll) = match self.node(id) {
+            Some(node) => (
+                node.pos,
+                node.size,
+                node.style,
+                node.children.clone(),
+                match node.widget {
+                    Widget::ScrollView { offset } => Some(offset),
+                    _ => None,
+                },
+            ),
+            None => return,
+        };
+        let axis = style.direction;
+        let inner_pos = pos + style.padding;
+        let inner = Vf2d::new(
+            (size.x - style.padding * 2.0).max(0.0),
+            (size.y - style.padding * 2.0).max(0.0),
+        );
+        let children: Vec<WidgetId> = children
+            .into_iter()
+            .filter(|child| self.node(*child).map(|n| n.visible).unwrap_or(false))
+            .collect();
+
+        //Each child's size along the direction before any growing, margins included
+        let bases: Vec<(f32, f32, Style)> = children
+            .iter()
+            .map(|child| {
+                let child_style = self.nodes[child.0].as_ref().unwrap().style;
+                let content = self.content_size(*child, font);
+                let main = resolve(
+                    main_dim(&child_style, axis),
+                    main(inner, axis),
+                    main(content, axis),
+                );
+                (main, child_style.grow, child_style)
+            })
+            .collect();
+        let gaps = style.gap * children.len().saturating_sub(1) as f32;
+        let used: f32 = bases
+            .iter()
+            .map(|(m, _, s)| m + s.margin * 2.0)
+            .sum::<f32>()
+            + gaps;
+        let total_grow: f32 = bases.iter().map(|(_, g, _)| g).sum();
+        //Scroll views let their content run on past the end instead of squashing it
+        let free = if scroll.is_some() {
+            0.0
+        } else {
+            main(inner, axis) - used
+        };
+
+        let n = children.len() as f32;
+        let (mut cursor, spacing) = if total_grow > 0.0 || free <= 0.0 {
+            (0.0, 0.0)
+        } else {
+            match style.justify {
+                Justify::Start => (0.0, 0.0),
+                Justify::Center => (free / 2.0, 0.0),
+                Justify::End => (free, 0.0),
+                Justify::SpaceBetween if n > 1.0 => (0.0, free / (n - 1.0)),
+                Justify::SpaceBetween => (0.0, 0.0),
+                Justify::SpaceAround => (free / n / 2.0, free / n),
+            }
+        };
+        cursor -= scroll.unwrap_or(0.0);
+
+        let mut content_main: f32 = 0.0;
+        let mut content_cross: f32 = 0.0;
+        for (child, (base, grow, child_style)) in children.iter().zip(bases) {
+            let grown = if total_grow > 0.0 && free > 0.0 {
+                base + free * grow / total_grow
+            } else {
+                base
+            };
+            let margin = child_style.margin;
+            let content = self.content_size(*child, font);
+            let cross_space = (cross(inner, axis) - margin * 2.0).max(0.0);
+            let cross_size = match (cross_dim(&child_style, axis), style.align_items) {
+                (Dimension::Auto, Align::Stretch) => cross_space,
+                (dim, _) => resolve(dim, cross(inner, axis), cross(content, axis)),
+            };
+            let cross_offset = match style.align_items {
+                Align::Start | Align::Stretch => 0.0,
+                Align::Center => (cross_space - cross_size) / 2.0,
+                Align::End => cross_space - cross_size,
+            };
+            let child_pos = inner_pos + make(cursor + margin, cross_offset + margin, axis);
+            if let Some(node) = self.node_mut(*child) {
+                node.pos = child_pos;
+                node.size = make(grown, cross_size, axis);
+            }
+            cursor += grown + margin * 2.0 + style.gap + spacing;
+            content_main = cursor + scroll.unwrap_or(0.0) - style.gap - spacing;
+            content_cross = content_cross.max(cross_size + margin * 2.0);
+            self.layout_children(*child, font);
+        }
+        if let Some(node) = self.node_mut(id) {
+            node.content = make(content_main.max(0.0), content_cross, axis);
+        }
+    }
+
+    //The size a widget wants when its style leaves it up to the content
+    fn content_size(&self, id: WidgetId, font: &Font) -> Vf2d {
+        let node = match self.node(id) {
+            Some(node) => node,
+            None => return Vf2d::new(0.0, 0.0),
+        };
+        let own = match &node.widget {
+            Widget::Label { text, scale, .. } => font.get_text_size(text) * *scale,
+            Widget::Image { decal, .. } => {
+                Vf2d::new(decal.sprite.width as f32, decal.sprite.height as f32)
+            }
+            Widget::Button { text, sprites, .. } => {
+                let text_size = font.get_text_size(text) + BUTTON_PADDING * 2.0;
+                match sprites {
+                    Some(sprites) => Vf2d::new(
+                        text_size.x.max(sprites.normal.sprite.width as f32),
+                        text_size.y.max(sprites.normal.sprite.height as f32),
+                    ),
+                    None => text_size,
+                }
+            }
+            Widget::List {
+                items, row_height, ..
+            } => {
+                let width = items
+                    .iter()
+                    .map(|item| font.get_text_size(item).x)
+                    .fold(0.0, f32::max);
+                Vf2d::new(width + 4.0, items.len() as f32 * row_height)
+            }
+            Widget::Panel { .. } | Widget::ScrollView { .. } => Vf2d::new(0.0, 0.0),
+        };
+        //Containers wrap their children, resolving percentages against nothing
+        let axis = node.style.direction;
+        let mut along: f32 = 0.0;
+        let mut across: f32 = 0.0;
+        let mut count = 0;
+        for child in &node.children {
+            let child_node = match self.node(*child) {
+                Some(child_node) if child_node.visible => child_node,
+                _ => continue,
+            };
+            let content = self.content_size(*child, font);
+            let margin = child_node.style.margin * 2.0;
+            let w = resolve(child_node.style.width, 0.0, content.x) + margin;
+            let h = resolve(child_node.style.height, 0.0, content.y) + margin;
+            let size = Vf2d::new(w, h);
+            along += main(size, axis);
+            across = across.max(cross(size, axis));
+            count += 1;
+        }
+        if count > 0 {
+            along += node.style.gap * (count - 1) as f32;
+        }
+        let children = make(along, across, axis) + node.style.padding * 2.0;
+        Vf2d::new(own.x.max(children.x), own.y.max(children.y))
+    }
+
+    ///Lays the tree out for the current resolution and handles the mouse and keyboard
+    pub fn update<D: OlcData>(&mut self, engine: &OLCEngine<D>) -> Vec<UiEvent> {
+        {
+            let font = self.font.take();
+            self.layout(
+                engine.pixels_w as f32,
+                engine.pixels_h as f32,
+                font.as_ref().unwrap_or(&engine.default_font),
+            );
+            self.font = font;
+        }
+        let mut events = vec![];
+        let mouse = engine.get_mouse_pos();
+        let left = engine.get_mouse(Mouse::Left);
+
+        let under_mouse = self.hit_test(mouse);
+        self.hovered =
+            under_mouse.filter(|id| self.nodes[id.0].as_ref().unwrap().widget.focusable());
+        if left.pressed {
+            self.pressed = self.hovered;
+            if let Some(id) = self.hovered {
+                if self.focused != Some(id) {
+                    self.focused = Some(id);
+                    events.push(UiEvent::Focused(id));
+                }
+                //Lists pick the row on press, buttons wait for the release
+                if let Some(row) = self.list_row(id, mouse) {
+                    events.extend(self.select(id, row));
+                }
+            }
+        }
+        if left.released {
+            if let (Some(pressed), Some(hovered)) = (self.pressed, self.hovered) {
+                if pressed == hovered
+                    && matches!(
+                        self.nodes[pressed.0].as_ref().unwrap().widget,
+                        Widget::Button { .. }
+                    )
+                {
+                    events.push(UiEvent::Clicked(pressed));
+                }
+            }
+            self.pressed = None;
+        }
+
+        //The wheel scrolls the innermost scroll view under the mouse
+        let wheel = engine.mouse_wheel();
+        if wheel != 0 {
+            let mut id = under_mouse.or_else(|| self.deepest_at(self.root, mouse));
+            while let Some(current) = id {
+                if let Some(node) = self.node_mut(current) {
+                    if let Widget::ScrollView { offset } = &mut node.widget {
+                        let limit =
+                            (node.content.y - node.size.y + node.style.padding * 2.0).max(0.0);
+                        *offset = (*offset - wheel as f32 * SCROLL_STEP).clamp(0.0, limit);
+                        break;
+                    }
+                    id = node.parent;
+                } else {
+                    break;
+                }
+            }
+        }
+
+        let focused_list = self
+            .focused
+            .map(|id| {
+                matches!(
+                    self.nodes[id.0].as_ref().unwrap().widget,
+                    Widget::List { .. }
+                )
+            })
+            .unwrap_or(false);
+        let key = |k: Key| engine.get_key(k).pressed;
+        if focused_list && (key(Key::Up) || key(Key::Down)) {
+            let id = self.focused.unwrap();
+            let (len, selected) = match &self.nodes[id.0].as_ref().unwrap().widget {
+                Widget::List {
+                    items, selected, ..
+                } => (items.len(), *selected),
+                _ => (0, None),
+            };
+            let row = match (selected, key(Key::Up)) {
+                (None, _) => Some(0),
+                (Some(row), true) if row > 0 => Some(row - 1),
+                (Some(row), false) if row + 1 < len => Some(row + 1),
+                _ => None,
+            };
+            match row {
+                Some(row) if row < len => events.extend(self.select(id, row)),
+                //Past either end of the list, focus moves on
+                _ => events.extend(self.navigate(if key(Key::Up) {
+                    UiNav::Previous
+                } else {
+                    UiNav::Next
+                })),
+            }
+        } else if key(Key::Up) || key(Key::Left) {
+            events.extend(self.navigate(UiNav::Previous));
+        } else if key(Key::Down) || key(Key::Right) || key(Key::Tab) {
+            events.extend(self.navigate(UiNav::Next));
+        }
+        if key(Key::Return) || key(Key::Space) {
+            events.extend(self.navigate(UiNav::Activate));
+        }
+        events
+    }
+
+    ///Moves focus through the focusable widgets in tree order, or activates the focused one
+    pub fn navigate(&mut self, nav: UiNav) -> Vec<UiEvent> {
+        let order = self.focus_order();
+        if order.is_empty() {
+            return vec![];
+        }
+        let current = self
+            .focused
+            .and_then(|id| order.iter().position(|o| *o == id));
+        let next = match (nav, current) {
+            (UiNav::Activate, Some(i)) => {
+                let id = order[i];
+                return match &self.nodes[id.0].as_ref().unwrap().widget {
+                    Widget::Button { .. } => vec![UiEvent::Clicked(id)],
+                    Widget::List {
+                        selected: Some(row),
+                        ..
+                    } => vec![UiEvent::Selected(id, *row)],
+                    _ => vec![],
+                };
+            }
+            (UiNav::Activate, None) => return vec![],
+            (UiNav::Next, Some(i)) => (i + 1) % order.len(),
+            (UiNav::Previous, Some(i)) => (i + order.len() - 1) % order.len(),
+            (UiNav::Next, None) => 0,
+            (UiNav::Previous, None) => order.len() - 1,
+        };
+        self.set_focus(Some(order[next]));
+        vec![UiEvent::Focused(order[next])]
+    }
+
+    fn select(&mut self, id: WidgetId, row: usize) -> Option<UiEvent> {
+        if let Some(Widget::List {
+            items, selected, ..
+        }) = self.widget_mut(id)
+        {
+            if row < items.len() {
+                *selected = Some(row);
+                return Some(UiEvent::Selected(id, row));
+            }
+        }
+        None
+    }
+
+    fn list_row(&self, id: WidgetId, mouse: Vf2d) -> Option<usize> {
+        let node = self.node(id)?;
+        match &node.widget {
+            Widget::List { row_height, .. } if *row_height > 0.0 => {
+                Some(((mouse.y - node.pos.y) / row_height).floor().max(0.0) as usize)
+            }
+            _ => None,
+        }
+    }
+
+    fn focus_order(&self) -> Vec<WidgetId> {
+        let mut order = vec![];
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            if let Some(node) = self.node(id) {
+                if !node.visible {
+                    continue;
+                }
+                if node.widget.focusable() {
+                    order.push(id);
+                }
+                stack.extend(node.children.iter().rev());
+            }
+        }
+        order
+    }
+
+    //The last drawn visible widget under the point, skipping ones scrolled out of view
+    fn hit_test(&self, p: Vf2d) -> Option<WidgetId> {
+        self.deepest_at(self.root, p).filter(|id| *id != self.root)
+    }
+
+    fn deepest_at(&self, id: WidgetId, p: Vf2d) -> Option<WidgetId> {
+        let node = self.node(id)?;
+        if !node.visible || !contains(node.pos, node.size, p) {
+            return None;
+        }
+        node.children
+            .iter()
+            .rev()
+            .find_map(|child| self.deepest_at(*child, p))
+            .or(Some(id))
+    }
+
+    fn scroll_into_view(&mut self, id: WidgetId) {
+        let (pos, size) = match self.node(id) {
+            Some(node) => (node.pos, node.size),
+            None => return,
+        };
+        let mut parent = self.node(id).and_then(|node| node.parent);
+        while let Some(current) = parent {
+            let node = match self.node_mut(current) {
+                Some(node) => node,
+                None => return,
+            };
+            if let Widget::ScrollView { offset } = &mut node.widget {
+                let top = node.pos.y + node.style.padding;
+                let bottom = node.pos.y + node.size.y - node.style.padding;
+                if pos.y < top {
+                    *offset -= top - pos.y;
+                } else if pos.y + size.y > bottom {
+                    *offset += pos.y + size.y - bottom;
+                }
+                *offset = offset.max(0.0);
+            }
+            parent = node.parent;
+        }
+    }
+
+    ///Draws the tree with decals on the current draw target.
+    /// A TrueType font gets any glyphs it is missing rasterised first.
+    pub fn draw<D: OlcData>(&mut self, engine: &mut OLCEngine<D>) {
+        if let Some(font) = self.font.as_mut() {
+            for node in self.nodes.iter().flatten() {
+                match &node.widget {
+                    Widget::Label { text, .. } | Widget::Button { text, .. } => {
+                        font.cache_text(text, &mut engine.renderer)
+                    }
+                    Widget::List { items, .. } => {
+                        for item in items {
+                            font.cache_text(item, &mut engine.renderer);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let screen = Vf2d::new(engine.pixels_w as f32, engine.pixels_h as f32);
+        self.draw_node(engine, self.root, (Vf2d::new(0.0, 0.0), screen));
+    }
+
+    fn draw_node<D: OlcData>(&self, engine: &mut OLCEngine<D>, id: WidgetId, clip: (Vf2d, Vf2d)) {
+        let node = match self.node(id) {
+            Some(node) if node.visible => node,
+            _ => return,
+        };
+        //Nothing is drawn once it is scrolled completely out of its scroll view
+        if !overlaps(node.pos, node.size, clip.0, clip.1) {
+            return;
+        }
+        let (pos, size) = (node.pos, node.size);
+        let colors = self.colors;
+        let font = self.font.as_ref();
+        match &node.widget {
+            Widget::Panel { color, border } => {
+                if color.a() > 0 {
+                    engine.fill_rect_decal(pos, size, *color);
+                }
+                if let Some(border) = border {
+                    engine.draw_rect_decal(pos, size, *border);
+                }
+            }
+            Widget::Label { text, color, scale } => {
+                engine.draw_string_decal_with_font(
+                    pos,
+                    text,
+                    *color,
+                    Vf2d::new(*scale, *scale),
+                    font,
+                );
+            }
+            Widget::Image { decal, tint } => {
+                let scale = Vf2d::new(
+                    size.x / decal.sprite.width.max(1) as f32,
+                    size.y / decal.sprite.height.max(1) as f32,
+                );
+                engine.draw_decal_with_scale_and_tint(pos, decal.clone(), scale, *tint);
+            }
+            Widget::Button { text, sprites } => {
+                let hovered = self.hovered == Some(id);
+                let pressed = hovered && self.pressed == Some(id);
+                match sprites {
+                    Some(sprites) => {
+                        let decal = match (pressed, hovered) {
+                            (true, _) => sprites.pressed.as_ref(),
+                            (false, true) => sprites.hover.as_ref(),
+                            _ => None,
+                        }
+                        .unwrap_or(&sprites.normal);
+                        let scale = Vf2d::new(
+                            size.x / decal.sprite.width.max(1) as f32,
+                            size.y / decal.sprite.height.max(1) as f32,
+                        );
+                        engine.draw_decal_with_scale_and_tint(
+                            pos,
+                            decal.clone(),
+                            scale,
+                            Pixel::WHITE,
+                        );
+                    }
+                    None => {
+                        let col = match (pressed, hovered) {
+                            (true, _) => colors.button_pressed,
+                            (false, true) => colors.button_hover,
+                            _ => colors.button,
+                        };
+                        engine.fill_rect_decal(pos, size, col);
+                    }
+                }
+                let text_size = engine.get_text_size_with_font(text, font).to_vf2d();
+                let text_pos = pos + ((size - text_size) / 2.0);
+                engine.draw_string_decal_with_font(
+                    Vf2d::new(text_pos.x.floor(), text_pos.y.floor()),
+                    text,
+                    colors.text,
+                    Vf2d::new(1.0, 1.0),
+                    font,
+                );
+            }
+            Widget::List {
+                items,
+                selected,
+                row_height,
+            } => {
+                for (i, item) in items.iter().enumerate() {
+                    let row_pos = pos + Vf2d::new(0.0, i as f32 * row_height);
+                    let row_size = Vf2d::new(size.x, *row_height);
+                    if !overlaps(row_pos, row_size, clip.0, clip.1) {
+                        continue;
+                    }
+                    if *selected == Some(i) {
+                        engine.fill_rect_decal(row_pos, row_size, colors.selection);
+                    }
+                    engine.draw_string_decal_with_font(
+                        row_pos + Vf2d::new(2.0, 1.0),
+                        item,
+                        colors.text,
+                        Vf2d::new(1.0, 1.0),
+                        font,
+                    );
+                }
+            }
+            Widget::ScrollView { .. } => {}
+        }
+        if self.focused == Some(id) {
+            engine.draw_rect_decal(pos - 1.0, size + 2.0, colors.focus);
+        }
+        let clip = match node.widget {
+            Widget::ScrollView { .. } => intersect(clip, (pos, size)),
+            _ => clip,
+        };
+        for child in &node.children {
+            self.draw_node(engine, *child, clip);
+        }
+    }
+}
+
+fn main(v: Vf2d, axis: FlexDirection) -> f32 {
+    match axis {
+        FlexDirection::Row => v.x,
+        FlexDirection::Column => v.y,
+    }
+}
+
+fn cross(v: Vf2d, axis: FlexDirection) -> f32 {
+    match axis {
+        FlexDirection::Row => v.y,
+        FlexDirection::Column => v.x,
+    }
+}
+
+fn make(main: f32, cross: f32, axis: FlexDirection) -> Vf2d {
+    match axis {
+        FlexDirection::Row => Vf2d::new(main, cross),
+        FlexDirection::Column => Vf2d::new(cross, main),
+    }
+}
+
+fn main_dim(style: &Style, axis: FlexDirection) -> Dimension {
+    match axis {
+        FlexDirection::Row => style.width,
+        FlexDirection::Column => style.height,
+    }
+}
+
+fn cross_dim(style: &Style, axis: FlexDirection) -> Dimension {
+    match axis {
+        FlexDirection::Row => style.height,
+        FlexDirection::Column => style.width,
+    }
+}
+
+fn resolve(dim: Dimension, parent: f32, content: f32) -> f32 {
+    match dim {
+        Dimension::Auto => content,
+        Dimension::Px(px) => px,
+        Dimension::Percent(p) => parent * p / 100.0,
+    }
+}
+
+fn contains(pos: Vf2d, size: Vf2d, p: Vf2d) -> bool {
+    p.x >= pos.x && p.y >= pos.y && p.x < pos.x + size.x && p.y < pos.y + size.y
+}
+
+fn overlaps(pos: Vf2d, size: Vf2d, other_pos: Vf2d, other_size: Vf2d) -> bool {
+    pos.x < other_pos.x + other_size.x
+        && pos.y < other_pos.y + other_size.y
+        && pos.x + size.x > other_pos.x
+        && pos.y + size.y > other_pos.y
+}
+
+fn intersect(a: (Vf2d, Vf2d), b: (Vf2d, Vf2d)) -> (Vf2d, Vf2d) {
+    let min = Vf2d::new(a.0.x.max(b.0.x), a.0.y.max(b.0.y));
+    let max = Vf2d::new(
+        (a.0.x + a.1.x).min(b.0.x + b.1.x),
+        (a.0.y + a.1.y).min(b.0.y + b.1.y),
+    );
+    (
+        min,
+        Vf2d::new((max.x - min.x).max(0.0), (max.y - min.y).max(0.0)),
+    )
+}